@@ -40,20 +40,21 @@ async fn main() {
     let (ws_stream, _) = connect_async(&config.ws_url).await.unwrap();
     let (mut ws_write, mut ws_read) = ws_stream.split();
     let (ws_tx, mut ws_rx) = mpsc::unbounded_channel::<WsMessage>();
-    let send_loop = tokio::spawn(async move {
+    let send_loop = async move {
         while let Some(msg) = ws_rx.recv().await {
-            ws_write.send(msg).await.expect("Send message to channel Error");
+            ws_write.send(msg).await?;
         }
-    });
+        Ok::<_, tokio_tungstenite::tungstenite::Error>(())
+    };
 
     let bot_id = format!("{}-{}", "onebot", process::id());
 
     let client = plugin.server.client();
     let sink = client.sink();
     let bot_id_ = bot_id.clone();
-    let recv_loop = tokio::spawn(async move {
+    let recv_loop = async move {
         while let Some(message) = ws_read.next().await {
-            let message = message.expect("Recv message from ws Error");
+            let message = message?;
             let message = match message.into_text() {
                 Ok(message) => message,
                 Err(err) => {
@@ -78,9 +79,10 @@ async fn main() {
             let Some(message) = message else {
                 continue;
             };
-            sink.send(message).unwrap();
+            sink.send(message)?;
         }
-    });
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+    };
 
     let state = AdapterState { ws_tx };
 
@@ -90,11 +92,17 @@ async fn main() {
             .with_state(state)
     });
 
-    tokio::select! {
-        _ = send_loop => {}
-        _ = recv_loop => {}
-        _ = plugin.run().join_all() => {}
-        _ = tokio::signal::ctrl_c() => {}
+    let exit = plugin
+        .task("send", send_loop)
+        .task("recv", recv_loop)
+        .on_shutdown(|exit| {
+            log::info!("OneBot adapter stopped: {exit}");
+            async {}
+        })
+        .run()
+        .await;
+    if exit.is_error() {
+        process::exit(1);
     }
 }
 
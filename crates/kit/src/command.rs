@@ -0,0 +1,1006 @@
+//! Declarative command parsing for chat messages.
+//!
+//! The [`command!`](crate::command!) macro declares a command type together
+//! with its usage text, and the [`Command`] extractor parses incoming
+//! [`Message`]s into it:
+//!
+//! ```
+//! use sithra_kit::{
+//!     command,
+//!     command::{Command, Mention, Rest},
+//! };
+//!
+//! command! {
+//!     /// Look up the weather.
+//!     pub struct Weather("/weather", "/w") {
+//!         /// City name.
+//!         city: String,
+//!         /// Number of days to forecast.
+//!         days: Option<u32>,
+//!     }
+//! }
+//!
+//! command! {
+//!     /// Manage todos.
+//!     pub enum Todo("/todo") {
+//!         /// Add a todo for someone.
+//!         Add("add") { who: Mention, text: Rest },
+//!         /// List all todos.
+//!         List("list", "ls") {},
+//!     }
+//! }
+//!
+//! async fn weather(Command(weather): Command<Weather>) {
+//!     let _ = (weather.city, weather.days);
+//! }
+//! ```
+//!
+//! Messages that do not start with the command name are rejected silently,
+//! while malformed arguments are answered with an error and the usage text.
+
+use std::{
+    fmt::{self, Write as _},
+    ops::{Deref, DerefMut},
+};
+
+use sithra_server::{
+    extract::FromRequest,
+    multi::Flow,
+    response::{IntoResponse, Response},
+    sync::Arc,
+    transport::datapack::RequestDataPack,
+};
+use sithra_types::{
+    message::{Message, Segment, SendMessage, common::CommonSegment},
+    smallvec::SmallVec,
+};
+use thiserror::Error;
+
+/// A single token of a command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    /// A word or a quoted string.
+    Text(String),
+    /// A mention of a user, from [`CommonSegment::At`].
+    At(String),
+    /// An image, from [`CommonSegment::Image`].
+    Image(String),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    token:  Token,
+    raw:    String,
+    spaced: bool,
+}
+
+/// The tokenized arguments of a command.
+///
+/// Text is split on whitespace, honouring single and double quotes, while
+/// mentions and images become tokens of their own.
+#[derive(Debug, Clone)]
+pub struct Args {
+    entries: Vec<Entry>,
+    pos:     usize,
+}
+
+impl Args {
+    #[must_use]
+    pub fn new(content: &[CommonSegment]) -> Self {
+        let mut entries = Vec::new();
+        let mut text = String::new();
+        let mut spaced = false;
+        for segment in content {
+            match segment {
+                CommonSegment::Text(t) => text.push_str(t),
+                CommonSegment::At(id) => {
+                    spaced = tokenize(&std::mem::take(&mut text), spaced, &mut entries);
+                    entries.push(Entry {
+                        token: Token::At(id.clone()),
+                        raw: format!("@{id}"),
+                        spaced,
+                    });
+                    spaced = false;
+                }
+                CommonSegment::Image(url) => {
+                    spaced = tokenize(&std::mem::take(&mut text), spaced, &mut entries);
+                    entries.push(Entry {
+                        token: Token::Image(url.clone()),
+                        raw: String::new(),
+                        spaced,
+                    });
+                    spaced = false;
+                }
+                CommonSegment::Unknown(_) => {}
+            }
+        }
+        tokenize(&text, spaced, &mut entries);
+        Self { entries, pos: 0 }
+    }
+
+    #[must_use]
+    pub fn from_message(message: &Message<CommonSegment>) -> Self {
+        Self::new(&message.content)
+    }
+
+    /// Returns the next token without consuming it.
+    #[must_use]
+    pub fn peek(&self) -> Option<&Token> {
+        self.entries.get(self.pos).map(|entry| &entry.token)
+    }
+
+    /// Returns the next token if it is text, without consuming it.
+    #[must_use]
+    pub fn peek_text(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Consumes the next token.
+    pub fn next_token(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.pos >= self.entries.len()
+    }
+
+    /// Skips the next token if it mentions `id`.
+    pub fn skip_mention(&mut self, id: &str) -> bool {
+        let is_mention = matches!(self.peek(), Some(Token::At(target)) if target == id);
+        if is_mention {
+            self.pos += 1;
+        }
+        is_mention
+    }
+
+    /// Parses the next argument.
+    ///
+    /// # Errors
+    /// Returns an error if the argument is missing or invalid.
+    pub fn arg<T: FromArg>(&mut self, name: &'static str) -> Result<T, CommandError> {
+        T::from_arg(self, name)
+    }
+
+    /// Consumes the remaining text tokens, keeping their quotes as typed.
+    /// Tokens that were separated by whitespace are joined with a single
+    /// space.
+    pub fn rest(&mut self) -> String {
+        let mut rest = String::new();
+        while let Some(entry) = self.entries.get(self.pos) {
+            if !matches!(entry.token, Token::Text(_)) {
+                break;
+            }
+            if entry.spaced && !rest.is_empty() {
+                rest.push(' ');
+            }
+            rest.push_str(&entry.raw);
+            self.pos += 1;
+        }
+        rest
+    }
+
+    /// Checks that every token has been consumed.
+    ///
+    /// # Errors
+    /// Returns [`CommandError::Unexpected`] if tokens are left over.
+    pub fn finish(&self) -> Result<(), CommandError> {
+        match self.entries.get(self.pos) {
+            None => Ok(()),
+            Some(entry) => Err(CommandError::Unexpected(describe(entry))),
+        }
+    }
+}
+
+fn describe(entry: &Entry) -> String {
+    match &entry.token {
+        Token::Text(text) => text.clone(),
+        Token::At(id) => format!("@{id}"),
+        Token::Image(_) => "[image]".to_owned(),
+    }
+}
+
+/// Splits `text` into entries, returning whether it ended with whitespace.
+fn tokenize(text: &str, mut spaced: bool, entries: &mut Vec<Entry>) -> bool {
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            spaced = true;
+            chars.next();
+            continue;
+        }
+        let mut value = String::new();
+        let mut raw = String::new();
+        if c == '"' || c == '\'' {
+            raw.push(c);
+            chars.next();
+            while let Some(next) = chars.next() {
+                raw.push(next);
+                if next == c {
+                    break;
+                }
+                if next == '\\' {
+                    if let Some(escaped) = chars.next() {
+                        raw.push(escaped);
+                        value.push(escaped);
+                    }
+                    continue;
+                }
+                value.push(next);
+            }
+        } else {
+            while let Some(&next) = chars.peek() {
+                if next.is_whitespace() {
+                    break;
+                }
+                raw.push(next);
+                value.push(next);
+                chars.next();
+            }
+        }
+        entries.push(Entry {
+            token: Token::Text(value),
+            raw,
+            spaced,
+        });
+        spaced = false;
+    }
+    spaced
+}
+
+/// Errors produced while parsing a command.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CommandError {
+    /// The message is not this command.
+    #[error("Not a matching command")]
+    NotMatched,
+    #[error("Missing argument `{0}`")]
+    Missing(&'static str),
+    #[error("Invalid value {value:?} for argument `{name}`, expected {expected}")]
+    Invalid {
+        name:     &'static str,
+        value:    String,
+        expected: &'static str,
+    },
+    #[error("Unexpected argument {0:?}")]
+    Unexpected(String),
+    #[error("Missing subcommand")]
+    MissingSubcommand,
+    #[error("Unknown subcommand {0:?}")]
+    UnknownSubcommand(String),
+}
+
+/// Describes how an argument is shown in the usage text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hint {
+    pub kind:     &'static str,
+    pub optional: bool,
+    pub variadic: bool,
+}
+
+impl Hint {
+    #[must_use]
+    pub const fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            optional: false,
+            variadic: false,
+        }
+    }
+}
+
+/// A type that can be parsed from command arguments.
+pub trait FromArg: Sized {
+    /// Parses the argument called `name` from `args`.
+    ///
+    /// # Errors
+    /// Returns an error if the argument is missing or invalid.
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError>;
+
+    fn hint() -> Hint;
+}
+
+fn next_text(args: &mut Args, name: &'static str) -> Result<String, CommandError> {
+    match args.peek() {
+        None => Err(CommandError::Missing(name)),
+        Some(Token::Text(text)) => {
+            let text = text.clone();
+            args.pos += 1;
+            Ok(text)
+        }
+        Some(_) => Err(CommandError::Invalid {
+            name,
+            value: describe(&args.entries[args.pos]),
+            expected: "text",
+        }),
+    }
+}
+
+impl FromArg for String {
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+        next_text(args, name)
+    }
+
+    fn hint() -> Hint {
+        Hint::new("text")
+    }
+}
+
+macro_rules! from_arg_for_number {
+    ($expected:literal => $($ty:ty),*) => {
+        $(
+            impl FromArg for $ty {
+                fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+                    let pos = args.pos;
+                    let text = next_text(args, name)?;
+                    if let Ok(value) = text.parse() {
+                        return Ok(value);
+                    }
+                    args.pos = pos;
+                    Err(CommandError::Invalid {
+                        name,
+                        value: text,
+                        expected: $expected,
+                    })
+                }
+
+                fn hint() -> Hint {
+                    Hint::new($expected)
+                }
+            }
+        )*
+    };
+}
+
+from_arg_for_number!("integer" => i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+from_arg_for_number!("number" => f32, f64);
+
+impl FromArg for bool {
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+        let pos = args.pos;
+        let text = next_text(args, name)?;
+        match text.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" => Ok(false),
+            _ => {
+                args.pos = pos;
+                Err(CommandError::Invalid {
+                    name,
+                    value: text,
+                    expected: "yes or no",
+                })
+            }
+        }
+    }
+
+    fn hint() -> Hint {
+        Hint::new("yes/no")
+    }
+}
+
+/// A mentioned user, parsed from [`CommonSegment::At`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mention(pub String);
+
+impl FromArg for Mention {
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+        match args.peek() {
+            None => Err(CommandError::Missing(name)),
+            Some(Token::At(id)) => {
+                let id = id.clone();
+                args.pos += 1;
+                Ok(Self(id))
+            }
+            Some(_) => Err(CommandError::Invalid {
+                name,
+                value: describe(&args.entries[args.pos]),
+                expected: "a mention",
+            }),
+        }
+    }
+
+    fn hint() -> Hint {
+        Hint::new("@mention")
+    }
+}
+
+/// An image, parsed from [`CommonSegment::Image`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image(pub String);
+
+impl FromArg for Image {
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+        match args.peek() {
+            None => Err(CommandError::Missing(name)),
+            Some(Token::Image(url)) => {
+                let url = url.clone();
+                args.pos += 1;
+                Ok(Self(url))
+            }
+            Some(_) => Err(CommandError::Invalid {
+                name,
+                value: describe(&args.entries[args.pos]),
+                expected: "an image",
+            }),
+        }
+    }
+
+    fn hint() -> Hint {
+        Hint::new("image")
+    }
+}
+
+/// The remaining text of the command, as returned by [`Args::rest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rest(pub String);
+
+impl FromArg for Rest {
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+        let rest = args.rest();
+        if rest.is_empty() {
+            return Err(CommandError::Missing(name));
+        }
+        Ok(Self(rest))
+    }
+
+    fn hint() -> Hint {
+        Hint {
+            variadic: true,
+            ..Hint::new("text")
+        }
+    }
+}
+
+impl<T: FromArg> FromArg for Option<T> {
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+        let pos = args.pos;
+        T::from_arg(args, name).map_or_else(
+            |_| {
+                args.pos = pos;
+                Ok(None)
+            },
+            |value| Ok(Some(value)),
+        )
+    }
+
+    fn hint() -> Hint {
+        Hint {
+            optional: true,
+            ..T::hint()
+        }
+    }
+}
+
+impl<T: FromArg> FromArg for Vec<T> {
+    fn from_arg(args: &mut Args, name: &'static str) -> Result<Self, CommandError> {
+        let mut values = Self::new();
+        while !args.is_empty() {
+            let pos = args.pos;
+            let Ok(value) = T::from_arg(args, name) else {
+                args.pos = pos;
+                break;
+            };
+            // An argument that consumes nothing, like `Option<T>`, would repeat forever.
+            if args.pos == pos {
+                break;
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    fn hint() -> Hint {
+        Hint {
+            optional: true,
+            variadic: true,
+            ..T::hint()
+        }
+    }
+}
+
+/// The usage description of an argument.
+#[derive(Debug, Clone)]
+pub struct ArgSpec {
+    pub name:  &'static str,
+    pub about: String,
+    pub hint:  Hint,
+}
+
+impl fmt::Display for ArgSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dots = if self.hint.variadic { "..." } else { "" };
+        if self.hint.optional {
+            write!(f, "[{}{dots}]", self.name)
+        } else {
+            write!(f, "<{}{dots}>", self.name)
+        }
+    }
+}
+
+/// The usage description of a command, used to render help text.
+#[derive(Debug, Clone)]
+pub struct Spec {
+    pub names:       &'static [&'static str],
+    pub about:       String,
+    pub args:        Vec<ArgSpec>,
+    pub subcommands: Vec<Self>,
+}
+
+impl Spec {
+    #[must_use]
+    pub const fn new(names: &'static [&'static str]) -> Self {
+        Self {
+            names,
+            about: String::new(),
+            args: Vec::new(),
+            subcommands: Vec::new(),
+        }
+    }
+
+    /// Sets the description, trimming the leading space of doc comments.
+    #[must_use]
+    pub fn about(mut self, about: &[&str]) -> Self {
+        self.about = join_doc(about);
+        self
+    }
+
+    #[must_use]
+    pub fn arg<T: FromArg>(mut self, name: &'static str, about: &[&str]) -> Self {
+        self.args.push(ArgSpec {
+            name,
+            about: join_doc(about),
+            hint: T::hint(),
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn subcommand(mut self, subcommand: Self) -> Self {
+        self.subcommands.push(subcommand);
+        self
+    }
+
+    fn name(&self) -> &'static str {
+        self.names.first().copied().unwrap_or_default()
+    }
+
+    fn synopsis(&self, parent: &str) -> String {
+        let mut synopsis = format!("{parent}{}", self.name());
+        if !self.subcommands.is_empty() {
+            let names: Vec<_> = self.subcommands.iter().map(Self::name).collect();
+            let _ = write!(synopsis, " <{}>", names.join("|"));
+        }
+        for arg in &self.args {
+            let _ = write!(synopsis, " {arg}");
+        }
+        synopsis
+    }
+
+    /// Renders the usage text.
+    #[must_use]
+    pub fn usage(&self) -> String {
+        let mut usage = format!("Usage: {}", self.synopsis(""));
+        if self.names.len() > 1 {
+            let _ = write!(usage, "\nAliases: {}", self.names[1..].join(", "));
+        }
+        if !self.about.is_empty() {
+            let _ = write!(usage, "\n{}", self.about);
+        }
+        for arg in &self.args {
+            let _ = write!(usage, "\n  {} ({})", arg.name, arg.hint.kind);
+            if !arg.about.is_empty() {
+                let _ = write!(usage, ": {}", arg.about);
+            }
+        }
+        let parent = format!("{} ", self.name());
+        for subcommand in &self.subcommands {
+            let _ = write!(usage, "\n  {}", subcommand.synopsis(&parent));
+            if !subcommand.about.is_empty() {
+                let _ = write!(usage, ": {}", subcommand.about);
+            }
+        }
+        usage
+    }
+}
+
+fn join_doc(lines: &[&str]) -> String {
+    lines
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A command parsed from a message.
+///
+/// Usually implemented with the [`command!`](crate::command!) macro.
+pub trait ParseCommand: Sized {
+    /// The words that invoke this command, including any prefix.
+    fn names() -> &'static [&'static str];
+
+    fn spec() -> Spec;
+
+    /// Parses the arguments following the command name.
+    ///
+    /// # Errors
+    /// Returns an error if the arguments do not match the command.
+    fn parse_args(args: &mut Args) -> Result<Self, CommandError>;
+
+    /// Parses a whole command line, including the command name.
+    ///
+    /// # Errors
+    /// Returns [`CommandError::NotMatched`] if `args` does not start with one
+    /// of [`ParseCommand::names`], or another error if the arguments are
+    /// invalid.
+    fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let matched = args.peek_text().is_some_and(|name| Self::names().contains(&name));
+        if !matched {
+            return Err(CommandError::NotMatched);
+        }
+        args.next_token();
+        let command = Self::parse_args(args)?;
+        args.finish()?;
+        Ok(command)
+    }
+}
+
+/// Extracts a [`ParseCommand`] from a `Message<CommonSegment>` payload.
+///
+/// A leading mention of the bot itself is ignored. Messages that are not
+/// this command are rejected without a response, passing the request on to
+/// the next handler of a [`chain`](sithra_server::chain).
+#[derive(Debug, Clone)]
+pub struct Command<T>(pub T);
+
+impl<T> Deref for Command<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Command<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T, S> FromRequest<S> for Command<T>
+where
+    T: ParseCommand + Send,
+    S: Send + Sync,
+{
+    type Rejection = CommandRejection;
+
+    async fn from_request(req: Arc<RequestDataPack>, _state: &S) -> Result<Self, Self::Rejection> {
+        let Ok(message) = rmpv::ext::from_value::<Message<CommonSegment>>(req.payload.clone())
+        else {
+            return Err(CommandRejection {
+                error: CommandError::NotMatched,
+                usage: String::new(),
+            });
+        };
+        let mut args = Args::from_message(&message);
+        if let Some(self_id) = req.channel.as_ref().and_then(|c| c.self_id.as_deref()) {
+            args.skip_mention(self_id);
+        }
+        T::parse(&mut args).map(Self).map_err(|error| CommandRejection {
+            usage: if error == CommandError::NotMatched {
+                String::new()
+            } else {
+                T::spec().usage()
+            },
+            error,
+        })
+    }
+}
+
+/// The rejection of the [`Command`] extractor.
+///
+/// Replies with the error and the usage text, unless the message was not
+/// the command at all: then it responds with [`Flow::Next`].
+#[derive(Debug, Clone)]
+pub struct CommandRejection {
+    pub error: CommandError,
+    pub usage: String,
+}
+
+impl IntoResponse for CommandRejection {
+    fn into_response(self) -> Response {
+        if self.error == CommandError::NotMatched {
+            return Flow::<()>::Next.into_response();
+        }
+        let text = format!("{}\n{}", self.error, self.usage);
+        SendMessage {
+            content: SmallVec::from_iter([Segment::text(&text)]),
+        }
+        .into_response()
+    }
+}
+
+/// Declares a command type and implements [`ParseCommand`] for it.
+///
+/// Structs take their arguments in field order. Enums take a subcommand
+/// name first, then the arguments of that variant. Doc comments become the
+/// usage text. See the [module documentation](crate::command) for examples.
+#[macro_export]
+macro_rules! command {
+    (
+        $(#[doc = $doc:literal])*
+        $vis:vis struct $name:ident($($trigger:literal),+ $(,)?) {
+            $(
+                $(#[doc = $fdoc:literal])*
+                $fvis:vis $field:ident: $ty:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[doc = $doc])*
+        #[derive(Debug)]
+        $vis struct $name {
+            $(
+                $(#[doc = $fdoc])*
+                $fvis $field: $ty,
+            )*
+        }
+
+        impl $crate::command::ParseCommand for $name {
+            fn names() -> &'static [&'static str] {
+                &[$($trigger),+]
+            }
+
+            fn spec() -> $crate::command::Spec {
+                $crate::command::Spec::new(<Self as $crate::command::ParseCommand>::names())
+                    .about(&[$($doc),*])
+                    $(.arg::<$ty>(stringify!($field), &[$($fdoc),*]))*
+            }
+
+            #[allow(unused_variables)]
+            fn parse_args(
+                args: &mut $crate::command::Args,
+            ) -> ::std::result::Result<Self, $crate::command::CommandError> {
+                ::std::result::Result::Ok(Self {
+                    $($field: args.arg::<$ty>(stringify!($field))?,)*
+                })
+            }
+        }
+    };
+    (
+        $(#[doc = $doc:literal])*
+        $vis:vis enum $name:ident($($trigger:literal),+ $(,)?) {
+            $(
+                $(#[doc = $vdoc:literal])*
+                $variant:ident($($vtrigger:literal),+ $(,)?) {
+                    $(
+                        $(#[doc = $fdoc:literal])*
+                        $field:ident: $ty:ty
+                    ),* $(,)?
+                }
+            ),* $(,)?
+        }
+    ) => {
+        $(#[doc = $doc])*
+        #[derive(Debug)]
+        $vis enum $name {
+            $(
+                $(#[doc = $vdoc])*
+                $variant {
+                    $(
+                        $(#[doc = $fdoc])*
+                        $field: $ty,
+                    )*
+                },
+            )*
+        }
+
+        impl $crate::command::ParseCommand for $name {
+            fn names() -> &'static [&'static str] {
+                &[$($trigger),+]
+            }
+
+            fn spec() -> $crate::command::Spec {
+                $crate::command::Spec::new(<Self as $crate::command::ParseCommand>::names())
+                    .about(&[$($doc),*])
+                    $(.subcommand(
+                        $crate::command::Spec::new(&[$($vtrigger),+])
+                            .about(&[$($vdoc),*])
+                            $(.arg::<$ty>(stringify!($field), &[$($fdoc),*]))*
+                    ))*
+            }
+
+            fn parse_args(
+                args: &mut $crate::command::Args,
+            ) -> ::std::result::Result<Self, $crate::command::CommandError> {
+                let ::std::option::Option::Some(subcommand) = args.peek_text() else {
+                    return ::std::result::Result::Err(
+                        $crate::command::CommandError::MissingSubcommand,
+                    );
+                };
+                let subcommand = subcommand.to_owned();
+                $(
+                    if [$($vtrigger),+].contains(&subcommand.as_str()) {
+                        args.next_token();
+                        return ::std::result::Result::Ok(Self::$variant {
+                            $($field: args.arg::<$ty>(stringify!($field))?,)*
+                        });
+                    }
+                )*
+                ::std::result::Result::Err($crate::command::CommandError::UnknownSubcommand(
+                    subcommand,
+                ))
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use sithra_server::{
+        chain, extract::payload::Payload, on, request::Request, routing::router::Router,
+        transport::datapack::RequestDataPack,
+    };
+    use sithra_types::{
+        message::{Message, SendMessage, common::CommonSegment},
+        smallvec::SmallVec,
+    };
+    use tower::Service;
+
+    use super::{Args, Command, CommandError, Image, Mention, ParseCommand, Rest, Token};
+
+    command! {
+        /// Look up the weather.
+        pub struct Weather("/weather", "/w") {
+            /// City name.
+            city: String,
+            days: Option<u32>,
+        }
+    }
+
+    command! {
+        /// Manage todos.
+        pub enum Todo("todo") {
+            /// Add a todo for someone.
+            Add("add") { who: Mention, text: Rest },
+            /// Share a picture.
+            Share("share") { images: Vec<Image> },
+            List("list", "ls") {},
+        }
+    }
+
+    fn text(text: &str) -> Args {
+        Args::new(&[CommonSegment::text(&text)])
+    }
+
+    #[test]
+    fn tokenize() {
+        let mut args = Args::new(&[
+            CommonSegment::text(&"say \"hello world\" 'it\\'s'"),
+            CommonSegment::at(&"42"),
+            CommonSegment::text(&" done"),
+        ]);
+        assert_eq!(args.next_token(), Some(Token::Text("say".to_owned())));
+        assert_eq!(
+            args.next_token(),
+            Some(Token::Text("hello world".to_owned()))
+        );
+        assert_eq!(args.next_token(), Some(Token::Text("it's".to_owned())));
+        assert_eq!(args.next_token(), Some(Token::At("42".to_owned())));
+        assert_eq!(args.rest(), "done");
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn parse_struct() {
+        let weather = Weather::parse(&mut text("/w Beijing 3")).unwrap();
+        assert_eq!(weather.city, "Beijing");
+        assert_eq!(weather.days, Some(3));
+
+        let weather = Weather::parse(&mut text("/weather \"New York\"")).unwrap();
+        assert_eq!(weather.city, "New York");
+        assert_eq!(weather.days, None);
+
+        assert_eq!(
+            Weather::parse(&mut text("echo Beijing")).unwrap_err(),
+            CommandError::NotMatched
+        );
+        assert_eq!(
+            Weather::parse(&mut text("/weather")).unwrap_err(),
+            CommandError::Missing("city")
+        );
+        assert_eq!(
+            Weather::parse(&mut text("/weather Beijing soon")).unwrap_err(),
+            CommandError::Unexpected("soon".to_owned())
+        );
+    }
+
+    #[test]
+    fn parse_enum() {
+        let mut args = Args::new(&[
+            CommonSegment::text(&"todo add "),
+            CommonSegment::at(&"42"),
+            CommonSegment::text(&" buy  milk"),
+        ]);
+        let Todo::Add { who, text: item } = Todo::parse(&mut args).unwrap() else {
+            panic!("expected `add`");
+        };
+        assert_eq!(who, Mention("42".to_owned()));
+        assert_eq!(item, Rest("buy milk".to_owned()));
+
+        let mut args = Args::new(&[
+            CommonSegment::text(&"todo share"),
+            CommonSegment::image(&"a.png"),
+            CommonSegment::image(&"b.png"),
+        ]);
+        let Todo::Share { images } = Todo::parse(&mut args).unwrap() else {
+            panic!("expected `share`");
+        };
+        assert_eq!(images.len(), 2);
+
+        let mut args = text("1 two");
+        assert_eq!(args.arg::<Vec<Option<u32>>>("numbers"), Ok(vec![Some(1)]));
+        assert_eq!(args.rest(), "two");
+
+        assert!(matches!(
+            Todo::parse(&mut text("todo ls")),
+            Ok(Todo::List {})
+        ));
+        assert_eq!(
+            Todo::parse(&mut text("todo remove 1")).unwrap_err(),
+            CommandError::UnknownSubcommand("remove".to_owned())
+        );
+    }
+
+    #[test]
+    fn usage() {
+        assert_eq!(
+            Weather::spec().usage(),
+            "Usage: /weather <city> [days]\nAliases: /w\nLook up the weather.\n  city (text): \
+             City name.\n  days (integer)"
+        );
+        assert_eq!(
+            Todo::spec().usage(),
+            "Usage: todo <add|share|list>\nManage todos.\n  todo add <who> <text...>: Add a todo \
+             for someone.\n  todo share [images...]: Share a picture.\n  todo list"
+        );
+    }
+
+    #[tokio::test]
+    async fn chain_commands() {
+        let mut router: Router = Router::new().route(
+            "/message",
+            chain([
+                (
+                    10,
+                    on(async |Command(weather): Command<Weather>| Payload(weather.city)),
+                ),
+                (0, on(async |Command(_): Command<Todo>| Payload("todo"))),
+            ]),
+        );
+        let mut call = async |text: &str| {
+            let message = Message {
+                id:      "1".to_owned(),
+                content: SmallVec::<[_; 1]>::from_iter([CommonSegment::text(&text)]),
+            };
+            let data = RequestDataPack::default().path("/message").payload(message);
+            router.call(Request::new(data)).await.unwrap()
+        };
+
+        let response = call("todo ls").await;
+        assert!(!response.is_next());
+        assert_eq!(response.data[0].payload::<String>().unwrap(), "todo");
+        let response = call("/w Beijing").await;
+        assert_eq!(response.data[0].payload::<String>().unwrap(), "Beijing");
+        let response = call("/weather").await;
+        assert!(response.data[0].payload::<SendMessage>().is_ok());
+        let response = call("hello").await;
+        assert!(response.is_next());
+        assert!(response.data.is_empty());
+    }
+}
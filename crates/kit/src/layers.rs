@@ -13,6 +13,16 @@ use sithra_server::{
 };
 use tower::{Layer, Service};
 
+#[cfg(feature = "filter")]
+mod filter;
+mod ordered;
+mod rate_limit;
+
+#[cfg(feature = "filter")]
+pub use filter::{Filter, FilterLayer, Rule};
+pub use ordered::{ChannelKey, Ordered, OrderedLayer};
+pub use rate_limit::{Cooldown, KeyBy, OnLimit, RateLimit, RateLimitLayer};
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BotId(pub String);
 
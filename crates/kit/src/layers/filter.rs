@@ -0,0 +1,415 @@
+//! Filtering requests by where they come from and what they say.
+//!
+//! A [`Rule`] is a predicate on requests, and [`Filter`] is a [`Layer`] that
+//! only lets requests matching its rule through. Rules deserialize from the
+//! plugin's configuration, so operators can restrict a plugin without
+//! recompiling it:
+//!
+//! ```toml
+//! [echo.config.filter]
+//! all = [
+//!     { channel_type = "group" },
+//!     { not = { user = ["10001", "10002"] } },
+//!     { any = ["mentioned", { text = "^/echo\\b" }] },
+//! ]
+//! ```
+//!
+//! Or in code:
+//!
+//! ```
+//! # use sithra_kit::{
+//! #     layers::{Filter, Rule},
+//! #     server::routing::router::Router,
+//! #     transport::channel::ChannelType,
+//! # };
+//! let rule = Rule::ChannelType(ChannelType::Group) & !Rule::user(["10001"]);
+//! let router: Router = Router::new()
+//!     // .route(...)
+//!     .layer(Filter::new(rule));
+//! ```
+
+use std::{
+    cell::OnceCell,
+    convert::Infallible,
+    ops,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de};
+use sithra_server::{
+    multi::Flow,
+    request::Request,
+    response::{IntoResponse, Response},
+    routing::route::{Route, RouteFuture},
+    transport::{channel::ChannelType, datapack::RequestDataPack},
+};
+use sithra_types::message::Message;
+use tower::{Layer, Service};
+
+/// A predicate on requests.
+///
+/// Lists match if any of their entries does. Rules about the channel never
+/// match requests without one, and rules about the text never match requests
+/// that are not messages.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// The chat is one of these: the group, or the user in a private chat.
+    Channel(Vec<String>),
+    /// The sender is one of these users.
+    User(Vec<String>),
+    /// The receiving bot is one of these.
+    Bot(Vec<String>),
+    /// The chat is of this type.
+    ChannelType(ChannelType),
+    /// The plain text of the message matches this pattern.
+    Text(#[serde(deserialize_with = "deserialize_regex")] Regex),
+    /// The message mentions the receiving bot.
+    Mentioned,
+    /// All of these rules match.
+    All(Vec<Self>),
+    /// Any of these rules matches.
+    Any(Vec<Self>),
+    /// This rule does not match.
+    Not(Box<Self>),
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(de::Error::custom)
+}
+
+impl Rule {
+    pub fn channel<T: ToString>(ids: impl IntoIterator<Item = T>) -> Self {
+        Self::Channel(ids.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    pub fn user<T: ToString>(ids: impl IntoIterator<Item = T>) -> Self {
+        Self::User(ids.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    pub fn bot<T: ToString>(ids: impl IntoIterator<Item = T>) -> Self {
+        Self::Bot(ids.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    /// # Errors
+    /// Returns an error if `pattern` is not a valid regular expression.
+    pub fn text(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(Self::Text)
+    }
+
+    /// Matches if both rules do.
+    #[must_use]
+    pub fn and(self, other: Self) -> Self {
+        match self {
+            Self::All(mut rules) => {
+                rules.push(other);
+                Self::All(rules)
+            }
+            rule => Self::All(vec![rule, other]),
+        }
+    }
+
+    /// Matches if either rule does.
+    #[must_use]
+    pub fn or(self, other: Self) -> Self {
+        match self {
+            Self::Any(mut rules) => {
+                rules.push(other);
+                Self::Any(rules)
+            }
+            rule => Self::Any(vec![rule, other]),
+        }
+    }
+
+    /// Returns `true` if `req` matches the rule.
+    #[must_use]
+    pub fn matches(&self, req: &RequestDataPack) -> bool {
+        self.matches_in(&Subject {
+            req,
+            message: OnceCell::new(),
+        })
+    }
+
+    fn matches_in(&self, subject: &Subject<'_>) -> bool {
+        let req = subject.req;
+        let channel = req.channel.as_ref();
+        match self {
+            Self::Channel(ids) => channel.is_some_and(|channel| {
+                let id = channel.parent_id.as_ref().unwrap_or(&channel.id);
+                ids.contains(id)
+            }),
+            Self::User(ids) => channel.is_some_and(|channel| ids.contains(&channel.id)),
+            Self::Bot(ids) => subject.self_id().is_some_and(|id| ids.iter().any(|bot| bot == id)),
+            Self::ChannelType(ty) => channel.is_some_and(|channel| channel.ty == *ty),
+            Self::Text(pattern) => subject.text().is_some_and(|text| pattern.is_match(&text)),
+            Self::Mentioned => subject.mentioned(),
+            Self::All(rules) => rules.iter().all(|rule| rule.matches_in(subject)),
+            Self::Any(rules) => rules.iter().any(|rule| rule.matches_in(subject)),
+            Self::Not(rule) => !rule.matches_in(subject),
+        }
+    }
+}
+
+impl ops::BitAnd for Rule {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        self.and(rhs)
+    }
+}
+
+impl ops::BitOr for Rule {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.or(rhs)
+    }
+}
+
+impl ops::Not for Rule {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        match self {
+            Self::Not(rule) => *rule,
+            rule => Self::Not(Box::new(rule)),
+        }
+    }
+}
+
+/// A request being matched, with its message parsed at most once.
+struct Subject<'a> {
+    req:     &'a RequestDataPack,
+    message: OnceCell<Option<Message>>,
+}
+
+impl Subject<'_> {
+    fn self_id(&self) -> Option<&str> {
+        let channel = self.req.channel.as_ref();
+        channel
+            .and_then(|channel| channel.self_id.as_deref())
+            .or(self.req.bot_id.as_deref())
+    }
+
+    fn message(&self) -> Option<&Message> {
+        self.message
+            .get_or_init(|| rmpv::ext::from_value(self.req.payload.clone()).ok())
+            .as_ref()
+    }
+
+    fn text(&self) -> Option<String> {
+        let message = self.message()?;
+        let text = message.content.iter().filter(|segment| segment.ty == "text");
+        Some(text.filter_map(|segment| segment.data.as_str()).collect())
+    }
+
+    fn mentioned(&self) -> bool {
+        let (Some(message), Some(self_id)) = (self.message(), self.self_id()) else {
+            return false;
+        };
+        message
+            .content
+            .iter()
+            .any(|segment| segment.ty == "at" && segment.data.as_str() == Some(self_id))
+    }
+}
+
+/// Only lets requests matching a [`Rule`] through.
+///
+/// Other requests are passed on to the next handler of a
+/// [`chain`](sithra_server::chain), and otherwise ignored.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    rule: Arc<Rule>,
+}
+
+impl Filter {
+    #[must_use]
+    pub fn new(rule: Rule) -> Self {
+        Self {
+            rule: Arc::new(rule),
+        }
+    }
+
+    #[must_use]
+    pub fn rule(&self) -> &Rule {
+        &self.rule
+    }
+}
+
+impl From<Rule> for Filter {
+    fn from(rule: Rule) -> Self {
+        Self::new(rule)
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Rule::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl Layer<Route> for Filter {
+    type Service = FilterLayer;
+
+    fn layer(&self, inner: Route) -> Self::Service {
+        FilterLayer {
+            rule: self.rule.clone(),
+            svc:  inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FilterLayer {
+    rule: Arc<Rule>,
+    svc:  Route,
+}
+
+impl Service<Request> for FilterLayer {
+    type Error = Infallible;
+    type Future = RouteFuture;
+    type Response = Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.svc.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        if self.rule.matches(&req.data) {
+            self.svc.call(req)
+        } else {
+            RouteFuture::ready(Flow::<()>::Next.into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use sithra_server::{
+        chain, on,
+        request::Request,
+        response::Response,
+        routing::router::Router,
+        transport::{
+            channel::{Channel, ChannelType},
+            datapack::RequestDataPack,
+        },
+    };
+    use sithra_types::{
+        message::{Message, Segment},
+        smallvec::SmallVec,
+    };
+    use tower::Service;
+
+    use super::{Filter, Rule};
+
+    fn message(
+        ty: ChannelType,
+        user: &str,
+        content: impl IntoIterator<Item = Segment>,
+    ) -> RequestDataPack {
+        let channel = Channel {
+            id: user.to_owned(),
+            ty,
+            name: user.to_owned(),
+            parent_id: (ty == ChannelType::Group).then(|| "group".to_owned()),
+            self_id: Some("bot".to_owned()),
+        };
+        let message = Message {
+            id:      "1".to_owned(),
+            content: content.into_iter().collect::<SmallVec<_>>(),
+        };
+        RequestDataPack::default().path("/message").channel(channel).payload(message)
+    }
+
+    #[test]
+    fn config() {
+        #[derive(Deserialize)]
+        struct Config {
+            filter: Filter,
+        }
+
+        let config: Config = toml::from_str(
+            r#"
+            [filter]
+            all = [
+                { channel_type = "group" },
+                { not = { user = ["blocked"] } },
+                { any = ["mentioned", { text = "^/echo\\b" }] },
+            ]
+            "#,
+        )
+        .unwrap();
+        let rule = config.filter.rule();
+
+        let group = ChannelType::Group;
+        assert!(rule.matches(&message(group, "user", [Segment::text(&"/echo hi")])));
+        assert!(rule.matches(&message(
+            group,
+            "user",
+            [Segment::at(&"bot"), Segment::text(&"hi")]
+        )));
+        assert!(!rule.matches(&message(
+            group,
+            "user",
+            [Segment::at(&"other"), Segment::text(&"hi")]
+        )));
+        assert!(!rule.matches(&message(group, "blocked", [Segment::text(&"/echo hi")])));
+        assert!(!rule.matches(&message(
+            ChannelType::Private,
+            "user",
+            [Segment::text(&"/echo")]
+        )));
+        assert!(!rule.matches(&RequestDataPack::default().path("/message")));
+    }
+
+    #[test]
+    fn compose() {
+        let rule = Rule::channel(["group"]) & !Rule::user(["blocked"]) | Rule::bot(["admin"]);
+        let group = ChannelType::Group;
+        assert!(rule.matches(&message(group, "user", [])));
+        assert!(!rule.matches(&message(group, "blocked", [])));
+        assert!(!rule.matches(&message(ChannelType::Private, "user", [])));
+        assert!(rule.matches(&RequestDataPack::default().bot_id("admin")));
+        assert!(matches!(!!Rule::Mentioned, Rule::Mentioned));
+    }
+
+    #[tokio::test]
+    async fn layer() {
+        let mut router: Router = Router::new().route(
+            "/message",
+            chain([
+                (
+                    1,
+                    on(async || Response::new(RequestDataPack::default().path("/filtered")))
+                        .layer(Filter::new(Rule::user(["admin"]))),
+                ),
+                (
+                    0,
+                    on(async || Response::new(RequestDataPack::default().path("/fallback"))),
+                ),
+            ]),
+        );
+
+        let mut paths = async |req: RequestDataPack| {
+            let response = router.call(Request::new(req)).await.unwrap();
+            response.data.iter().filter_map(|data| data.path.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            paths(message(ChannelType::Private, "admin", [])).await,
+            ["/filtered"]
+        );
+        assert_eq!(
+            paths(message(ChannelType::Private, "user", [])).await,
+            ["/fallback"]
+        );
+    }
+}
@@ -0,0 +1,350 @@
+//! Per-key ordered execution.
+//!
+//! [`Ordered`] serialises requests that share a key (by default the
+//! [`Channel`] they were sent in) while requests with different keys run in
+//! parallel. Messages in one chat are therefore handled one after another,
+//! even if a handler waits for a follow-up message.
+//!
+//! The server handles one request at a time by default, so a slow chat only
+//! stops holding up the others once
+//! [`Plugin::concurrency`](crate::plugin::Plugin::concurrency) (or
+//! [`Server::concurrency`](sithra_server::server::Server::concurrency)) allows
+//! more than one:
+//!
+//! ```no_run
+//! # use sithra_kit::{layers::Ordered, plugin::Plugin};
+//! # async fn example() {
+//! let (plugin, ()) = Plugin::new().await.unwrap();
+//! plugin
+//!     .map(|router| {
+//!         router
+//!             // .route(...)
+//!             .layer(Ordered::by_channel().max_in_flight(64))
+//!     })
+//!     .concurrency(64)
+//!     .run()
+//!     .await;
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    hash::Hash,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::future::BoxFuture;
+use parking_lot::Mutex;
+use sithra_server::{
+    request::Request,
+    response::Response,
+    routing::route::Route,
+    transport::channel::{Channel, ChannelType},
+};
+use tokio::sync::{Semaphore, oneshot};
+use tower::{Layer, Service, ServiceExt};
+
+/// Key used by [`Ordered::by_channel`].
+///
+/// Two channels are the same if they belong to the same bot and share their
+/// type, ID and parent ID; the display name is ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChannelKey {
+    pub self_id:   Option<String>,
+    pub ty:        ChannelType,
+    pub id:        String,
+    pub parent_id: Option<String>,
+}
+
+impl From<&Channel> for ChannelKey {
+    fn from(channel: &Channel) -> Self {
+        Self {
+            self_id:   channel.self_id.clone(),
+            ty:        channel.ty,
+            id:        channel.id.clone(),
+            parent_id: channel.parent_id.clone(),
+        }
+    }
+}
+
+type KeyFn<K> = dyn Fn(&Request) -> Option<K> + Send + Sync;
+
+/// A layer that processes requests with the same key in order.
+///
+/// Requests for which the key function returns `None` are not ordered, but
+/// still count towards [`Ordered::max_in_flight`].
+///
+/// Clones of an `Ordered` share their queues, so the order holds across all
+/// routes the layer is applied to.
+pub struct Ordered<K = ChannelKey> {
+    key:       Arc<KeyFn<K>>,
+    semaphore: Option<Arc<Semaphore>>,
+    queues:    Arc<Mutex<Queues<K>>>,
+}
+
+impl<K> Clone for Ordered<K> {
+    fn clone(&self) -> Self {
+        Self {
+            key:       self.key.clone(),
+            semaphore: self.semaphore.clone(),
+            queues:    self.queues.clone(),
+        }
+    }
+}
+
+impl Ordered {
+    /// Orders requests by the channel they were sent in.
+    #[must_use]
+    pub fn by_channel() -> Self {
+        Self::by_key(|req| req.data.channel.as_ref().map(ChannelKey::from))
+    }
+}
+
+impl<K> Ordered<K>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    /// Orders requests by a custom key.
+    pub fn by_key<F>(key: F) -> Self
+    where
+        F: Fn(&Request) -> Option<K> + Send + Sync + 'static,
+    {
+        Self {
+            key:       Arc::new(key),
+            semaphore: None,
+            queues:    Arc::new(Mutex::new(Queues::default())),
+        }
+    }
+
+    /// Limits the number of requests being processed at the same time, across
+    /// all keys.
+    ///
+    /// Requests waiting for their turn within a key do not count towards the
+    /// limit.
+    #[must_use]
+    pub fn max_in_flight(mut self, limit: usize) -> Self {
+        self.semaphore = Some(Arc::new(Semaphore::new(limit)));
+        self
+    }
+}
+
+impl<K> Layer<Route> for Ordered<K>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    type Service = OrderedLayer<K>;
+
+    fn layer(&self, inner: Route) -> Self::Service {
+        OrderedLayer {
+            key:       self.key.clone(),
+            semaphore: self.semaphore.clone(),
+            queues:    self.queues.clone(),
+            svc:       inner,
+        }
+    }
+}
+
+struct Queues<K> {
+    next:  u64,
+    /// The last request queued for each key, and a receiver that completes
+    /// once it has finished.
+    tails: HashMap<K, (u64, oneshot::Receiver<()>)>,
+}
+
+impl<K> Default for Queues<K> {
+    fn default() -> Self {
+        Self {
+            next:  0,
+            tails: HashMap::new(),
+        }
+    }
+}
+
+pub struct OrderedLayer<K = ChannelKey> {
+    key:       Arc<KeyFn<K>>,
+    semaphore: Option<Arc<Semaphore>>,
+    queues:    Arc<Mutex<Queues<K>>>,
+    svc:       Route,
+}
+
+impl<K> Clone for OrderedLayer<K> {
+    fn clone(&self) -> Self {
+        Self {
+            key:       self.key.clone(),
+            semaphore: self.semaphore.clone(),
+            queues:    self.queues.clone(),
+            svc:       self.svc.clone(),
+        }
+    }
+}
+
+/// Releases the next request of a key when dropped, and forgets the key if
+/// no request is waiting behind this one.
+struct Turn<K: Hash + Eq> {
+    queues: Arc<Mutex<Queues<K>>>,
+    key:    Option<K>,
+    seq:    u64,
+    done:   Option<oneshot::Sender<()>>,
+}
+
+impl<K: Hash + Eq> Drop for Turn<K> {
+    fn drop(&mut self) {
+        drop(self.done.take());
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut queues = self.queues.lock();
+        if queues.tails.get(&key).is_some_and(|(seq, _)| *seq == self.seq) {
+            queues.tails.remove(&key);
+        }
+    }
+}
+
+impl<K> Service<Request> for OrderedLayer<K>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+    type Response = Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.svc.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // The queue position is taken here rather than in the future, so
+        // requests with the same key run in the order they were called.
+        let key = (self.key)(&req);
+        let (done, finished) = oneshot::channel();
+        let mut queues = self.queues.lock();
+        let seq = queues.next;
+        queues.next += 1;
+        let previous = key
+            .clone()
+            .and_then(|key| queues.tails.insert(key, (seq, finished)))
+            .map(|(_, previous)| previous);
+        drop(queues);
+        let turn = Turn {
+            queues: self.queues.clone(),
+            key,
+            seq,
+            done: Some(done),
+        };
+        let svc = self.svc.clone();
+        let semaphore = self.semaphore.clone();
+        Box::pin(async move {
+            let _turn = turn;
+            if let Some(previous) = previous {
+                // An error only means the previous request was cancelled.
+                let _ = previous.await;
+            }
+            let _permit = match semaphore {
+                Some(semaphore) => semaphore.acquire_owned().await.ok(),
+                None => None,
+            };
+            svc.oneshot(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+    use sithra_server::{
+        extract::{payload::Payload, state::State},
+        on,
+        request::Request,
+        routing::router::Router,
+        server::Server,
+        testing::TestHost,
+        transport::{channel::Channel, datapack::RequestDataPack},
+    };
+    use tokio::sync::Notify;
+    use tower::{Service, ServiceExt};
+
+    use super::Ordered;
+
+    #[derive(Clone, Default)]
+    struct AppState {
+        log:  Arc<Mutex<Vec<u32>>>,
+        gate: Arc<Notify>,
+    }
+
+    fn request(group: &str, n: u32) -> Request {
+        Request::new(pack(group, n))
+    }
+
+    fn pack(group: &str, n: u32) -> RequestDataPack {
+        let channel = Channel::Group(group.to_owned(), group.to_owned());
+        RequestDataPack::default().path("/msg").channel(channel).payload(n)
+    }
+
+    #[tokio::test]
+    async fn ordered() {
+        let state = AppState::default();
+        let ordered = Ordered::by_channel().max_in_flight(2);
+        let mut router: Router = Router::new()
+            .route(
+                "/msg",
+                on(
+                    async |Payload(n): Payload<u32>, State(state): State<AppState>| {
+                        if n == 1 {
+                            state.gate.notified().await;
+                        }
+                        state.log.lock().push(n);
+                    },
+                ),
+            )
+            .layer(ordered.clone())
+            .with_state(state.clone());
+
+        let mut tasks = Vec::new();
+        for (group, n) in [("a", 1), ("a", 2), ("b", 3)] {
+            let future = router.ready().await.unwrap().call(request(group, n));
+            tasks.push(tokio::spawn(future));
+        }
+        tasks.pop().unwrap().await.unwrap().unwrap();
+        assert_eq!(*state.log.lock(), [3]);
+
+        state.gate.notify_one();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(*state.log.lock(), [3, 1, 2]);
+        assert!(ordered.queues.lock().tails.is_empty());
+    }
+
+    #[tokio::test]
+    async fn server() {
+        let state = AppState::default();
+        let router = Router::new()
+            .route(
+                "/msg",
+                on(
+                    async |Payload(n): Payload<u32>, State(state): State<AppState>| {
+                        if n == 1 {
+                            state.gate.notified().await;
+                        }
+                        Payload(n)
+                    },
+                ),
+            )
+            .layer(Ordered::by_channel())
+            .with_state(state.clone());
+        let mut host = TestHost::with_server(Server::new().concurrency(4), router);
+
+        for (group, n) in [("a", 1), ("a", 2), ("b", 3)] {
+            host.send(pack(group, n)).unwrap();
+        }
+        assert_eq!(host.next().await.unwrap().payload::<u32>(), Ok(3));
+        state.gate.notify_one();
+        for n in [1, 2] {
+            assert_eq!(host.next().await.unwrap().payload::<u32>(), Ok(n));
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Rate limiting and cooldowns.
+//!
+//! [`RateLimit`] allows `limit` requests per `period` for each key, with an
+//! optional burst, and decides what happens to requests over the limit with
+//! [`OnLimit`]. It is a [`Layer`] for routes, and a handler can also check
+//! its own cooldown with the [`Cooldown`] extractor by putting the limiter in
+//! its state.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use sithra_kit::{
+//! #     layers::{KeyBy, OnLimit, RateLimit},
+//! #     server::routing::router::Router,
+//! # };
+//! let limit = RateLimit::new(KeyBy::User, 3, Duration::from_mins(1))
+//!     .on_limit(OnLimit::Reply(
+//!         "Slow down, try again in {remaining}s.".to_owned(),
+//!     ));
+//! let router: Router = Router::new()
+//!     // .route(...)
+//!     .layer(limit);
+//! ```
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use sithra_server::{
+    extract::{FromRequest, from_ref::FromRef},
+    request::Request,
+    response::{IntoResponse, Response},
+    routing::route::{Route, RouteFuture},
+    transport::{channel::ChannelType, datapack::RequestDataPack},
+};
+use sithra_types::{
+    message::{Segment, SendMessage},
+    smallvec::SmallVec,
+};
+use tower::{Layer, Service};
+
+/// What requests are counted together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyBy {
+    /// The chat a request comes from: the group, or the user in a private
+    /// chat.
+    Channel,
+    /// The user who sent the request, across all chats.
+    User,
+    /// The bot that received the request.
+    Bot,
+    /// The path of the request.
+    Path,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Channel(Option<String>, ChannelType, String),
+    User(Option<String>, String),
+    Bot(String),
+    Path(String),
+}
+
+impl KeyBy {
+    fn key(self, req: &RequestDataPack) -> Option<Key> {
+        match self {
+            Self::Channel => req.channel.as_ref().map(|channel| {
+                let id = channel.parent_id.as_ref().unwrap_or(&channel.id);
+                Key::Channel(channel.self_id.clone(), channel.ty, id.clone())
+            }),
+            Self::User => req
+                .channel
+                .as_ref()
+                .map(|channel| Key::User(channel.self_id.clone(), channel.id.clone())),
+            Self::Bot => req.bot_id.clone().map(Key::Bot),
+            Self::Path => Some(Key::Path(req.path.clone())),
+        }
+    }
+}
+
+/// What to do with a request over the limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OnLimit {
+    /// Ignore the request.
+    #[default]
+    Drop,
+    /// Reply with a message. `{remaining}` is replaced with the number of
+    /// seconds until the next request is allowed.
+    Reply(String),
+    /// Respond with an error.
+    Error,
+}
+
+/// A rate limiter, shared between its clones.
+///
+/// Keys are limited with the generic cell rate algorithm, which behaves like
+/// a token bucket holding `burst` tokens and refilled with one token every
+/// `period / limit`. Requests without a key, e.g. without a channel when
+/// keyed by [`KeyBy::User`], are never limited.
+#[derive(Clone)]
+pub struct RateLimit {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    key_by:    KeyBy,
+    interval:  Duration,
+    tolerance: Duration,
+    on_limit:  OnLimit,
+    state:     Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    /// The theoretical arrival time of the next request for each key.
+    tat:        HashMap<Key, Instant>,
+    next_prune: usize,
+}
+
+impl RateLimit {
+    /// Allows `limit` requests per `period` for each key.
+    ///
+    /// # Panics
+    /// Panics if `limit` is zero.
+    #[must_use]
+    pub fn new(key_by: KeyBy, limit: u32, period: Duration) -> Self {
+        assert!(limit > 0, "rate limit must allow at least one request");
+        let interval = period / limit;
+        Self {
+            inner: Arc::new(Inner {
+                key_by,
+                interval,
+                tolerance: interval * (limit - 1),
+                on_limit: OnLimit::default(),
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    fn map(self, f: impl FnOnce(&mut Inner)) -> Self {
+        let mut inner = Arc::try_unwrap(self.inner).unwrap_or_else(|inner| Inner {
+            key_by:    inner.key_by,
+            interval:  inner.interval,
+            tolerance: inner.tolerance,
+            on_limit:  inner.on_limit.clone(),
+            state:     Mutex::default(),
+        });
+        f(&mut inner);
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Sets how many requests may be made at once before the limit applies.
+    ///
+    /// Defaults to `limit`.
+    ///
+    /// # Panics
+    /// Panics if `burst` is zero.
+    #[must_use]
+    pub fn burst(self, burst: u32) -> Self {
+        assert!(burst > 0, "burst must allow at least one request");
+        self.map(|inner| inner.tolerance = inner.interval * (burst - 1))
+    }
+
+    /// Sets what to do with requests over the limit.
+    #[must_use]
+    pub fn on_limit(self, on_limit: OnLimit) -> Self {
+        self.map(|inner| inner.on_limit = on_limit)
+    }
+
+    fn buckets(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        self.inner.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a request for `key` at `now`, or returns how long to wait.
+    fn acquire_at(&self, key: Key, now: Instant) -> Result<(), Duration> {
+        let Inner {
+            interval,
+            tolerance,
+            ..
+        } = *self.inner;
+        let mut buckets = self.buckets();
+        if buckets.tat.len() >= buckets.next_prune {
+            // A key whose next arrival time has passed is as good as new.
+            buckets.tat.retain(|_, tat| *tat > now);
+            buckets.next_prune = (buckets.tat.len() * 2).max(64);
+        }
+        let tat = buckets.tat.get(&key).map_or(now, |tat| (*tat).max(now));
+        let allowed_at = tat.checked_sub(tolerance).unwrap_or(now);
+        if allowed_at > now {
+            return Err(allowed_at - now);
+        }
+        buckets.tat.insert(key, tat + interval);
+        drop(buckets);
+        Ok(())
+    }
+
+    /// Returns how long `key` has to wait at `now`.
+    fn remaining_at(&self, key: &Key, now: Instant) -> Option<Duration> {
+        let tat = *self.buckets().tat.get(key)?;
+        tat.checked_sub(self.inner.tolerance)
+            .filter(|allowed_at| *allowed_at > now)
+            .map(|allowed_at| allowed_at - now)
+    }
+
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn rejection(&self, remaining: Duration) -> Response {
+        let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        match &self.inner.on_limit {
+            OnLimit::Drop => Response::none(),
+            OnLimit::Reply(text) => {
+                let text = text.replace("{remaining}", &secs.to_string());
+                SendMessage {
+                    content: SmallVec::from_iter([Segment::text(&text)]),
+                }
+                .into_response()
+            }
+            OnLimit::Error => Response::error(&format!("Rate limited, retry in {secs}s")),
+        }
+    }
+}
+
+impl Layer<Route> for RateLimit {
+    type Service = RateLimitLayer;
+
+    fn layer(&self, inner: Route) -> Self::Service {
+        RateLimitLayer {
+            limit: self.clone(),
+            svc:   inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limit: RateLimit,
+    svc:   Route,
+}
+
+impl Service<Request> for RateLimitLayer {
+    type Error = Infallible;
+    type Future = RouteFuture;
+    type Response = Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.svc.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let Some(key) = self.limit.inner.key_by.key(&req.data) else {
+            return self.svc.call(req);
+        };
+        match self.limit.acquire_at(key, Instant::now()) {
+            Ok(()) => self.svc.call(req),
+            Err(remaining) => RouteFuture::ready(self.limit.rejection(remaining)),
+        }
+    }
+}
+
+/// Extracts the cooldown of the current request from a [`RateLimit`] in the
+/// state.
+///
+/// If the same limiter is also used as a layer, the request has already been
+/// counted when the handler runs.
+pub struct Cooldown {
+    limit: RateLimit,
+    key:   Option<Key>,
+}
+
+impl Cooldown {
+    /// Returns how long until the next request is allowed, or `None` if it is
+    /// allowed now.
+    #[must_use]
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining_at(Instant::now())
+    }
+
+    fn remaining_at(&self, now: Instant) -> Option<Duration> {
+        self.limit.remaining_at(self.key.as_ref()?, now)
+    }
+
+    /// Counts the current request.
+    ///
+    /// # Errors
+    /// Returns how long to wait if the request is over the limit.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        match &self.key {
+            Some(key) => self.limit.acquire_at(key.clone(), Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Builds the response configured with [`RateLimit::on_limit`] for
+    /// `remaining`.
+    #[must_use]
+    pub fn rejection(&self, remaining: Duration) -> Response {
+        self.limit.rejection(remaining)
+    }
+}
+
+impl<S> FromRequest<S> for Cooldown
+where
+    RateLimit: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(
+        req: sithra_server::sync::Arc<RequestDataPack>,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let limit = RateLimit::from_ref(state);
+        let key = limit.inner.key_by.key(&req);
+        Ok(Self { limit, key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use sithra_server::{
+        extract::payload::Payload,
+        on,
+        request::Request,
+        routing::router::Router,
+        transport::{channel::Channel, datapack::RequestDataPack},
+    };
+    use sithra_types::message::SendMessage;
+    use tower::{Service, ServiceExt};
+
+    use super::{Cooldown, Key, KeyBy, OnLimit, RateLimit};
+
+    #[test]
+    fn gcra() {
+        let limit = RateLimit::new(KeyBy::Path, 2, Duration::from_secs(10));
+        let key = Key::Path("/a".to_owned());
+        let now = Instant::now();
+        assert_eq!(limit.acquire_at(key.clone(), now), Ok(()));
+        assert_eq!(limit.acquire_at(key.clone(), now), Ok(()));
+        assert_eq!(
+            limit.acquire_at(key.clone(), now),
+            Err(Duration::from_secs(5))
+        );
+        assert_eq!(
+            limit.remaining_at(&key, now + Duration::from_secs(1)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            limit.acquire_at(key.clone(), now + Duration::from_secs(5)),
+            Ok(())
+        );
+        assert_eq!(limit.acquire_at(Key::Path("/b".to_owned()), now), Ok(()));
+
+        let limit = RateLimit::new(KeyBy::Path, 2, Duration::from_secs(10)).burst(1);
+        assert_eq!(limit.acquire_at(key.clone(), now), Ok(()));
+        assert_eq!(
+            limit.acquire_at(key, now + Duration::from_secs(1)),
+            Err(Duration::from_secs(4))
+        );
+    }
+
+    #[tokio::test]
+    async fn layer() {
+        let limit = RateLimit::new(KeyBy::User, 1, Duration::from_mins(1))
+            .on_limit(OnLimit::Reply("wait {remaining}s".to_owned()));
+        let mut router: Router = Router::new()
+            .route(
+                "/msg",
+                on(async |cooldown: Cooldown| {
+                    assert!(cooldown.remaining().is_some());
+                    Payload("ok")
+                }),
+            )
+            .layer(limit.clone())
+            .with_state(limit);
+
+        let request = |user: &str| {
+            let channel = Channel::Private(user.to_owned(), user.to_owned());
+            Request::new(RequestDataPack::default().path("/msg").channel(channel))
+        };
+        for (user, limited) in [("a", false), ("b", false), ("a", true)] {
+            let response = router.ready().await.unwrap().call(request(user)).await.unwrap();
+            let data = response.data.into_iter().next().unwrap();
+            if limited {
+                let message = data.payload::<SendMessage>().unwrap();
+                assert_eq!(message.content[0].data, "wait 60s".into());
+            } else {
+                assert_eq!(data.payload::<String>().unwrap(), "ok");
+            }
+        }
+    }
+}
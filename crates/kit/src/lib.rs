@@ -13,3 +13,18 @@ pub mod initialize;
 
 #[cfg(feature = "plugin")]
 pub mod plugin;
+
+#[cfg(feature = "command")]
+pub mod command;
+
+#[cfg(feature = "trace")]
+pub mod trace;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "schedule")]
+pub mod schedule;
+
+#[cfg(feature = "storage")]
+pub mod storage;
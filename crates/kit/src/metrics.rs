@@ -0,0 +1,29 @@
+//! Reports the metrics of a plugin to the host.
+//!
+//! The host aggregates the reports of all plugins and exposes them; see
+//! [`sithra_server::metrics`] for what is recorded.
+
+use std::time::Duration;
+
+use sithra_server::{metrics::registry, server::ClientSink, transport::datapack::RequestDataPack};
+use sithra_types::metrics::MetricsReport;
+use tokio::task::JoinHandle;
+
+/// How often [`Plugin`](crate::plugin::Plugin) reports its metrics.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Sends a snapshot of the metrics registry to the host every `interval`,
+/// until the connection is closed.
+#[must_use]
+pub fn spawn_reporter(client_sink: ClientSink, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let report = MetricsReport::new(registry().snapshot());
+            if client_sink.send(RequestDataPack::from(report)).is_err() {
+                break;
+            }
+        }
+    })
+}
@@ -1,23 +1,197 @@
-use std::marker::PhantomData;
+//! The entry point of a plugin.
+//!
+//! [`Plugin::new`] waits for the host to send the configuration, and
+//! [`Plugin::run`] registers the paths of the router with the host and serves
+//! it until the plugin stops: on Ctrl-C or `SIGTERM`, when the host
+//! disconnects, when a background task stops, or when a [`ShutdownHandle`] is
+//! triggered. It returns an [`Exit`] telling which, after running the
+//! shutdown hooks.
+//!
+//! When the plugin's table in `config.toml` changes, the host sends a
+//! [`ConfigUpdated`](sithra_types::initialize::ConfigUpdated) request, which
+//! the router can handle to apply the new configuration in place.
+//!
+//! ```no_run
+//! # use sithra_kit::plugin::Plugin;
+//! # async fn example() {
+//! let (plugin, ()) = Plugin::new().await.unwrap();
+//! let exit = plugin
+//!     .map(|router| router /* .route(...) */)
+//!     .on_ready(async || log::info!("Ready"))
+//!     .task("poll", async {
+//!         // loop { ... }
+//!     })
+//!     .on_shutdown(|exit| {
+//!         let exit = exit.to_string();
+//!         async move { log::info!("Stopping: {exit}") }
+//!     })
+//!     .run()
+//!     .await;
+//! if exit.is_error() {
+//!     std::process::exit(1);
+//! }
+//! # }
+//! ```
 
-use futures_util::StreamExt;
+use std::{collections::HashMap, fmt, io::ErrorKind, marker::PhantomData, sync::Arc};
+
+use futures_util::{FutureExt, StreamExt, future::BoxFuture};
 use serde::Deserialize;
 use sithra_server::{
     routing::router::Router,
     server::{Server, ServerError},
-    transport::{peer::Peer, util::FramedPeer},
+    transport::{datapack::DataPackCodecError, peer::Peer, util::FramedPeer},
+};
+use sithra_types::{
+    host::{Ready, Register},
+    initialize::Initialize,
 };
-use sithra_types::initialize::Initialize;
 use thiserror::Error;
-use tokio::task::JoinSet;
+use tokio::{sync::Notify, task::JoinSet};
 
 use crate::logger::init_log;
+#[cfg(feature = "schedule")]
+use crate::schedule::Scheduler;
 
 pub struct Plugin<Config = rmpv::Value> {
-    peer:       Peer,
-    pub server: Server,
-    router:     Router,
-    _marker:    PhantomData<Config>,
+    peer:          Peer,
+    pub server:    Server,
+    /// Jobs that run while the plugin does. Clone it into the router state to
+    /// add or remove jobs from handlers.
+    #[cfg(feature = "schedule")]
+    pub scheduler: Scheduler,
+    router:        Router,
+    lifecycle:     Lifecycle,
+    _marker:       PhantomData<Config>,
+}
+
+/// An error of a background task.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+type Hook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+type ShutdownHook = Box<dyn FnOnce(&Exit) -> BoxFuture<'static, ()> + Send>;
+type Task = BoxFuture<'static, Result<(), BoxError>>;
+
+/// The hooks and background tasks of a plugin.
+#[derive(Default)]
+struct Lifecycle {
+    on_start:    Vec<Hook>,
+    on_ready:    Vec<Hook>,
+    on_shutdown: Vec<ShutdownHook>,
+    tasks:       Vec<(String, Task)>,
+    shutdown:    Arc<Notify>,
+    /// Paths handled outside the router, e.g. by subscriptions.
+    listen:      Vec<String>,
+}
+
+/// Why a plugin stopped.
+#[derive(Debug)]
+pub enum Exit {
+    /// The process received Ctrl-C or `SIGTERM`.
+    Signal,
+    /// A [`ShutdownHandle`] was triggered.
+    Requested,
+    /// The host closed the connection.
+    Disconnected,
+    /// The connection to the host failed.
+    Server(ServerError),
+    /// A background task stopped. `error` is `None` if it returned normally.
+    Task {
+        name:  String,
+        error: Option<BoxError>,
+    },
+}
+
+impl Exit {
+    /// Returns `true` if the plugin stopped because something failed.
+    #[must_use]
+    pub const fn is_error(&self) -> bool {
+        matches!(self, Self::Server(_) | Self::Task { error: Some(_), .. })
+    }
+}
+
+impl Exit {
+    /// The exit for a server task that stopped. Failing to write to a closed
+    /// connection counts as the host disconnecting.
+    fn from_server(result: Result<(), ServerError>) -> Self {
+        match result {
+            Ok(()) => Self::Disconnected,
+            Err(ServerError::Codec(DataPackCodecError::IO(err)))
+                if matches!(
+                    err.kind(),
+                    ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof
+                ) =>
+            {
+                Self::Disconnected
+            }
+            Err(err) => Self::Server(err),
+        }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Signal => f.write_str("Received shutdown signal"),
+            Self::Requested => f.write_str("Shutdown requested"),
+            Self::Disconnected => f.write_str("Host disconnected"),
+            Self::Server(err) => write!(f, "Server failed: {err}"),
+            Self::Task { name, error: None } => write!(f, "Task {name} stopped"),
+            Self::Task {
+                name,
+                error: Some(err),
+            } => write!(f, "Task {name} failed: {err}"),
+        }
+    }
+}
+
+/// The output of a background task: `()`, or a `Result` whose error stops
+/// the plugin with [`Exit::Task`].
+pub trait TaskResult: Send + 'static {
+    /// # Errors
+    /// Returns the error of the task, if any.
+    fn into_result(self) -> Result<(), BoxError>;
+}
+
+impl TaskResult for () {
+    fn into_result(self) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+impl<E> TaskResult for Result<(), E>
+where
+    E: Into<BoxError> + Send + 'static,
+{
+    fn into_result(self) -> Result<(), BoxError> {
+        self.map_err(Into::into)
+    }
+}
+
+/// Stops a running [`Plugin`] with [`Exit::Requested`].
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.notify.notify_one();
+    }
+}
+
+impl<Config> Plugin<Config> {
+    fn from_peer(peer: Peer) -> Self {
+        Self {
+            peer,
+            server: Server::new(),
+            #[cfg(feature = "schedule")]
+            scheduler: Scheduler::new(),
+            router: Router::new(),
+            lifecycle: Lifecycle::default(),
+            _marker: PhantomData,
+        }
+    }
 }
 
 impl<Config> Plugin<Config>
@@ -30,10 +204,7 @@ where
     /// - [`PluginInitError::ConnectionClosed`] if the connection was closed
     ///   before the config was received.
     pub async fn new() -> Result<(Self, Config), PluginInitError> {
-        let peer = Peer::new();
-        let server = Server::new();
-        let router = Router::new();
-        let mut framed = crate::transport::util::framed(peer);
+        let mut framed = crate::transport::util::framed(Peer::new());
 
         let config = loop {
             let Some(msg) = <FramedPeer as StreamExt>::next(&mut framed).await else {
@@ -48,18 +219,12 @@ where
             }
         }?;
 
-        init_log(server.client().sink());
+        let plugin = Self::from_peer(framed.into_inner());
+        init_log(plugin.server.client().sink());
+        #[cfg(feature = "trace")]
+        crate::trace::init_trace(plugin.server.client().sink());
 
-        let peer = framed.into_inner();
-        Ok((
-            Self {
-                peer,
-                server,
-                router,
-                _marker: PhantomData,
-            },
-            config.config,
-        ))
+        Ok((plugin, config.config))
     }
 
     #[must_use]
@@ -70,12 +235,18 @@ where
         let Self {
             peer,
             server,
+            #[cfg(feature = "schedule")]
+            scheduler,
             router,
+            lifecycle,
             _marker,
         } = self;
         Self {
             peer,
             server,
+            #[cfg(feature = "schedule")]
+            scheduler,
+            lifecycle,
             router: f(router.with_state(())),
             _marker: PhantomData,
         }
@@ -89,28 +260,195 @@ where
         let Self {
             peer,
             server,
+            #[cfg(feature = "schedule")]
+            scheduler,
             router,
+            lifecycle,
             _marker,
         } = self;
         Self {
             peer,
             server,
+            #[cfg(feature = "schedule")]
+            scheduler,
+            lifecycle,
             router: f(router).await,
             _marker: PhantomData,
         }
     }
 
+    /// Runs `hook` before the plugin starts serving requests.
     #[must_use]
-    pub fn run(self) -> JoinSet<Result<(), ServerError>> {
+    pub fn on_start<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.lifecycle.on_start.push(Box::new(move || hook().boxed()));
+        self
+    }
+
+    /// Runs `hook` once the plugin serves requests and its background tasks
+    /// are running. The plugin reports ready to the host after these hooks.
+    #[must_use]
+    pub fn on_ready<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.lifecycle.on_ready.push(Box::new(move || hook().boxed()));
+        self
+    }
+
+    /// Runs `hook` when the plugin stops, after its background tasks and
+    /// jobs are cancelled but while it can still send requests.
+    #[must_use]
+    pub fn on_shutdown<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(&Exit) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.lifecycle.on_shutdown.push(Box::new(move |exit| hook(exit).boxed()));
+        self
+    }
+
+    /// Runs `task` in the background while the plugin runs. The plugin stops
+    /// with [`Exit::Task`] when it returns, fails or panics.
+    #[must_use]
+    pub fn task<F>(mut self, name: impl Into<String>, task: F) -> Self
+    where
+        F: Future + Send + 'static,
+        F::Output: TaskResult,
+    {
+        let task = task.map(TaskResult::into_result).boxed();
+        self.lifecycle.tasks.push((name.into(), task));
+        self
+    }
+
+    /// Handles up to `limit` requests at once, e.g. so that handlers waiting
+    /// for a follow-up message do not block the others. See
+    /// [`Server::concurrency`].
+    #[must_use]
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        self.server = self.server.concurrency(limit);
+        self
+    }
+
+    /// Receives requests for `path` even though the router has no route for
+    /// it, e.g. to handle them with [`Client::subscribe`].
+    ///
+    /// [`Plugin::run`] registers the paths of the router with the host, which
+    /// only forwards matching requests to the plugin.
+    ///
+    /// [`Client::subscribe`]: sithra_server::server::Client::subscribe
+    #[must_use]
+    pub fn listen(mut self, path: impl Into<String>) -> Self {
+        self.lifecycle.listen.push(path.into());
+        self
+    }
+
+    /// Returns a handle that stops the plugin once it runs.
+    #[must_use]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            notify: self.lifecycle.shutdown.clone(),
+        }
+    }
+
+    /// Serves the router until the plugin stops, and returns why.
+    ///
+    /// # Panics
+    /// Panics if a task of the server panics.
+    pub async fn run(self) -> Exit {
         let Self {
             peer,
             server,
+            #[cfg(feature = "schedule")]
+            scheduler,
             router,
+            lifecycle,
             _marker,
         } = self;
+        let Lifecycle {
+            on_start,
+            on_ready,
+            on_shutdown,
+            tasks,
+            shutdown,
+            listen,
+        } = lifecycle;
+        for hook in on_start {
+            hook().await;
+        }
+
         let (write, read) = peer.split();
+        #[cfg(feature = "trace")]
+        let router = router.layer(sithra_server::trace::Trace);
+        #[cfg(feature = "metrics")]
+        let reporter =
+            crate::metrics::spawn_reporter(server.client().sink(), crate::metrics::REPORT_INTERVAL);
+        #[cfg(feature = "metrics")]
+        let router = router.layer(sithra_server::metrics::Metrics);
+        let client = server.client();
+        let paths = router.paths().map(str::to_owned).chain(listen);
+        client.send(Register::new(paths)).ok();
+        let mut server = server.service(router).serve(write, read);
+        #[cfg(feature = "schedule")]
+        let scheduler = tokio::spawn(scheduler.run());
+        let mut background = JoinSet::new();
+        let mut names = HashMap::new();
+        for (name, task) in tasks {
+            names.insert(background.spawn(task).id(), name);
+        }
+        for hook in on_ready {
+            hook().await;
+        }
+        client.send(Ready).ok();
 
-        server.service(router).serve(write, read)
+        let exit = tokio::select! {
+            () = signal() => Exit::Signal,
+            () = shutdown.notified() => Exit::Requested,
+            Some(result) = server.join_next() => match result {
+                Ok(result) => Exit::from_server(result),
+                Err(err) => std::panic::resume_unwind(err.into_panic()),
+            },
+            Some(result) = background.join_next_with_id() => {
+                let (id, error) = match result {
+                    Ok((id, result)) => (id, result.err()),
+                    Err(err) => (err.id(), Some(err.into())),
+                };
+                let name = names.remove(&id).unwrap_or_default();
+                Exit::Task { name, error }
+            }
+        };
+
+        background.abort_all();
+        #[cfg(feature = "schedule")]
+        scheduler.abort();
+        #[cfg(feature = "metrics")]
+        reporter.abort();
+        for hook in on_shutdown {
+            hook(&exit).await;
+        }
+        exit
+    }
+}
+
+/// Waits for Ctrl-C, or `SIGTERM` on Unix.
+async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
     }
 }
 
@@ -121,3 +459,66 @@ pub enum PluginInitError {
     #[error("Connection closed")]
     ConnectionClosed,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use sithra_server::transport::peer::Peer;
+
+    use super::{Exit, Plugin};
+
+    async fn run(plugin: Plugin<()>) -> Exit {
+        tokio::time::timeout(Duration::from_secs(1), plugin.run()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn hooks() {
+        let (peer, _host) = Peer::duplex(1024);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let push = |event: &'static str| {
+            let events = events.clone();
+            async move || events.lock().unwrap().push(event.to_owned())
+        };
+        let on_shutdown = {
+            let events = events.clone();
+            move |exit: &Exit| {
+                events.lock().unwrap().push(format!("shutdown: {exit}"));
+                async {}
+            }
+        };
+        let plugin = Plugin::<()>::from_peer(peer)
+            .on_start(push("start"))
+            .on_ready(push("ready"))
+            .on_shutdown(on_shutdown);
+        plugin.shutdown_handle().shutdown();
+
+        assert!(matches!(run(plugin).await, Exit::Requested));
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["start", "ready", "shutdown: Shutdown requested"]
+        );
+    }
+
+    #[tokio::test]
+    async fn exits() {
+        let (peer, _host) = Peer::duplex(1024);
+        let plugin = Plugin::<()>::from_peer(peer).task("fail", async { Err::<(), _>("boom") });
+        let exit = run(plugin).await;
+        assert!(exit.is_error());
+        assert_eq!(exit.to_string(), "Task fail failed: boom");
+
+        let (peer, _host) = Peer::duplex(1024);
+        let plugin = Plugin::<()>::from_peer(peer).task("done", async {});
+        let exit = run(plugin).await;
+        assert!(matches!(&exit, Exit::Task { name, error: None } if name == "done"));
+
+        let (peer, host) = Peer::duplex(1024);
+        drop(host);
+        let exit = run(Plugin::<()>::from_peer(peer)).await;
+        assert!(matches!(exit, Exit::Disconnected), "{exit}");
+    }
+}
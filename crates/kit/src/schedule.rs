@@ -0,0 +1,336 @@
+//! Timed and recurring jobs.
+//!
+//! A [`Scheduler`] runs jobs on a [`Trigger`]: a cron expression, a fixed
+//! interval or a one-shot delay. Jobs can be added before the scheduler runs
+//! and at any time after, e.g. from a handler that has the scheduler in its
+//! state. [`Plugin::run`](crate::plugin::Plugin::run) runs the plugin's
+//! scheduler alongside the server, and its jobs are cancelled when the
+//! plugin's tasks are dropped.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use sithra_kit::{plugin::Plugin, schedule::Trigger};
+//! # async fn example() {
+//! let (plugin, ()) = Plugin::new().await.unwrap();
+//! let client = plugin.server.client();
+//! plugin.scheduler.add(Trigger::cron("0 9 * * *").unwrap(), move || {
+//!     let client = client.clone();
+//!     async move {
+//!         // client.send(...)
+//!     }
+//! });
+//! plugin.run().await;
+//! # }
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::Local;
+use futures_util::{FutureExt, future::BoxFuture};
+use thiserror::Error;
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::{self, AbortHandle, JoinSet},
+    time::{Instant, MissedTickBehavior},
+};
+
+/// When a job runs.
+#[derive(Clone)]
+pub enum Trigger {
+    /// At the times matching a cron expression, in local time.
+    Cron(Box<cron::Schedule>),
+    /// Every period, starting one period from now.
+    Every(Duration),
+    /// Once, after a delay.
+    After(Duration),
+}
+
+impl Trigger {
+    /// Parses a cron expression.
+    ///
+    /// Both the classic five fields (`minute hour day month weekday`) and the
+    /// extended form with leading seconds and an optional trailing year are
+    /// accepted.
+    ///
+    /// # Errors
+    /// Returns [`ScheduleError::Cron`] if `expr` is not a valid cron
+    /// expression.
+    pub fn cron(expr: &str) -> Result<Self, ScheduleError> {
+        let schedule = if expr.split_whitespace().count() == 5 {
+            cron::Schedule::from_str(&format!("0 {expr}"))
+        } else {
+            cron::Schedule::from_str(expr)
+        };
+        Ok(Self::Cron(Box::new(schedule?)))
+    }
+
+    #[must_use]
+    pub const fn every(period: Duration) -> Self {
+        Self::Every(period)
+    }
+
+    #[must_use]
+    pub const fn after(delay: Duration) -> Self {
+        Self::After(delay)
+    }
+
+    /// Runs `job` whenever the trigger fires, until there are no more times.
+    async fn run(self, job: Job) {
+        match self {
+            Self::Cron(schedule) => {
+                while let Some(next) = schedule.upcoming(Local).next() {
+                    let delay = (next - Local::now()).to_std().unwrap_or_default();
+                    tokio::time::sleep(delay).await;
+                    job().await;
+                }
+            }
+            Self::Every(period) => {
+                let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                loop {
+                    interval.tick().await;
+                    job().await;
+                }
+            }
+            Self::After(delay) => {
+                tokio::time::sleep(delay).await;
+                job().await;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cron(schedule) => f.debug_tuple("Cron").field(&schedule.to_string()).finish(),
+            Self::Every(period) => f.debug_tuple("Every").field(period).finish(),
+            Self::After(delay) => f.debug_tuple("After").field(delay).finish(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("Invalid cron expression: {0}")]
+    Cron(#[from] cron::error::Error),
+}
+
+/// Identifies a job of a [`Scheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(u64);
+
+type Job = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+enum Command {
+    Add(JobId, Trigger, Job),
+    Remove(JobId),
+    Shutdown,
+}
+
+/// Runs jobs on triggers, shared between its clones.
+///
+/// Jobs run one at a time per job: a job that takes longer than its period
+/// skips the missed runs instead of overlapping with itself.
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    next_id: AtomicU64,
+    jobs:    Mutex<HashSet<JobId>>,
+    tx:      UnboundedSender<Command>,
+    rx:      Mutex<Option<UnboundedReceiver<Command>>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    #[must_use]
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            inner: Arc::new(Inner {
+                next_id: AtomicU64::new(0),
+                jobs: Mutex::default(),
+                tx,
+                rx: Mutex::new(Some(rx)),
+            }),
+        }
+    }
+
+    fn jobs(&self) -> std::sync::MutexGuard<'_, HashSet<JobId>> {
+        self.inner.jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds a job that calls `job` whenever `trigger` fires.
+    ///
+    /// Jobs added before the scheduler runs start when it does.
+    pub fn add<F, Fut>(&self, trigger: Trigger, job: F) -> JobId
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let id = JobId(self.inner.next_id.fetch_add(1, Ordering::Relaxed));
+        let job: Job = Arc::new(move || job().boxed());
+        self.jobs().insert(id);
+        if self.inner.tx.send(Command::Add(id, trigger, job)).is_err() {
+            self.jobs().remove(&id);
+        }
+        id
+    }
+
+    /// Cancels a job. Returns `false` if it had already finished or been
+    /// removed.
+    #[must_use]
+    pub fn remove(&self, id: JobId) -> bool {
+        let removed = self.jobs().remove(&id);
+        if removed {
+            self.inner.tx.send(Command::Remove(id)).ok();
+        }
+        removed
+    }
+
+    /// Returns `true` if the job has neither finished nor been removed.
+    #[must_use]
+    pub fn contains(&self, id: JobId) -> bool {
+        self.jobs().contains(&id)
+    }
+
+    /// Returns the number of jobs that have neither finished nor been
+    /// removed.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.jobs().len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stops the scheduler and cancels all of its jobs.
+    pub fn shutdown(&self) {
+        self.inner.tx.send(Command::Shutdown).ok();
+    }
+
+    /// Runs the jobs until [`Scheduler::shutdown`] is called. Dropping the
+    /// returned future cancels all jobs.
+    ///
+    /// Only the first call runs the jobs; later calls return immediately.
+    pub async fn run(self) {
+        let rx = self.inner.rx.lock().unwrap_or_else(PoisonError::into_inner).take();
+        let Some(mut rx) = rx else {
+            return;
+        };
+        let mut tasks = JoinSet::new();
+        let mut handles: HashMap<JobId, AbortHandle> = HashMap::new();
+        let mut ids: HashMap<task::Id, JobId> = HashMap::new();
+        loop {
+            tokio::select! {
+                command = rx.recv() => match command {
+                    Some(Command::Add(id, trigger, job)) => {
+                        let handle = tasks.spawn(trigger.run(job));
+                        ids.insert(handle.id(), id);
+                        handles.insert(id, handle);
+                    }
+                    Some(Command::Remove(id)) => {
+                        if let Some(handle) = handles.remove(&id) {
+                            handle.abort();
+                        }
+                    }
+                    Some(Command::Shutdown) | None => break,
+                },
+                Some(result) = tasks.join_next_with_id() => {
+                    let task = result.map_or_else(|err| err.id(), |(task, ())| task);
+                    if let Some(id) = ids.remove(&task) {
+                        handles.remove(&id);
+                        self.jobs().remove(&id);
+                    }
+                }
+            }
+        }
+        self.jobs().clear();
+    }
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scheduler").field("jobs", &self.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use super::{Scheduler, Trigger};
+
+    #[test]
+    fn cron() {
+        let Trigger::Cron(five) = Trigger::cron("30 9 * * Mon").unwrap() else {
+            unreachable!();
+        };
+        let Trigger::Cron(six) = Trigger::cron("0 30 9 * * Mon").unwrap() else {
+            unreachable!();
+        };
+        assert_eq!(five.to_string(), six.to_string());
+        assert!(Trigger::cron("every day").is_err());
+    }
+
+    #[tokio::test]
+    async fn jobs() {
+        let scheduler = Scheduler::new();
+        let count = |counter: &Arc<AtomicUsize>| {
+            let counter = counter.clone();
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async {}
+            }
+        };
+        let every = Arc::new(AtomicUsize::new(0));
+        let once = Arc::new(AtomicUsize::new(0));
+        let removed = Arc::new(AtomicUsize::new(0));
+
+        let every_id = scheduler.add(Trigger::every(Duration::from_millis(10)), count(&every));
+        let once_id = scheduler.add(Trigger::after(Duration::from_millis(10)), count(&once));
+        let removed_id = scheduler.add(Trigger::after(Duration::from_millis(30)), count(&removed));
+        let run = tokio::spawn(scheduler.clone().run());
+        assert!(scheduler.remove(removed_id));
+        assert!(!scheduler.remove(removed_id));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(every.load(Ordering::SeqCst) >= 3);
+        assert_eq!(once.load(Ordering::SeqCst), 1);
+        assert_eq!(removed.load(Ordering::SeqCst), 0);
+        assert!(scheduler.contains(every_id));
+        assert!(!scheduler.contains(once_id));
+
+        scheduler.shutdown();
+        run.await.unwrap();
+        let ran = every.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(every.load(Ordering::SeqCst), ran);
+        assert!(scheduler.is_empty());
+    }
+}
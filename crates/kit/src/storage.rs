@@ -0,0 +1,553 @@
+//! Persistent key-value storage.
+//!
+//! [`Storage`] is an embedded database file in the plugin's data directory,
+//! and a [`Store`] is a namespace of it holding values of one type, keyed by
+//! strings. Values are encoded with `MessagePack`, may expire after a TTL, and
+//! survive plugin restarts.
+//!
+//! Handlers get a store with the [`Store`] extractor, for any type
+//! implementing [`Record`], by putting the storage in their state:
+//!
+//! ```no_run
+//! # use serde::{Deserialize, Serialize};
+//! # use sithra_kit::{
+//! #     server::{extract::payload::Payload, on, routing::router::Router},
+//! #     storage::{Record, Storage, Store},
+//! # };
+//! #[derive(Default, Serialize, Deserialize)]
+//! struct Counter(u64);
+//!
+//! impl Record for Counter {
+//!     const NAMESPACE: &str = "counter";
+//! }
+//!
+//! async fn count(store: Store<Counter>) -> Option<Payload<u64>> {
+//!     let counter = store.update("hits", |counter| {
+//!         let Counter(n) = counter.unwrap_or_default();
+//!         Some(Counter(n + 1))
+//!     });
+//!     counter.await.ok().flatten().map(|Counter(n)| Payload(n))
+//! }
+//!
+//! let storage = Storage::open_default().unwrap();
+//! let router: Router = Router::new().route("/count", on(count)).with_state(storage);
+//! ```
+//!
+//! Each operation is a single transaction, run on the blocking thread pool of
+//! the runtime so that it does not stall other handlers.
+
+use std::{
+    env,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use redb::{Database, ReadableTable, TableDefinition, TableError};
+use serde::{Serialize, de::DeserializeOwned};
+use sithra_server::{
+    extract::{FromRequest, from_ref::FromRef},
+    transport::datapack::RequestDataPack,
+};
+use thiserror::Error;
+use tokio::task::{JoinError, spawn_blocking};
+
+/// The environment variable holding the plugin's data directory, set by the
+/// host.
+pub const DATA_DIR_ENV: &str = "SITHRA_DATA_DIR";
+
+/// The name of the database file in the data directory.
+pub const DATABASE_FILE: &str = "storage.redb";
+
+/// Returns the plugin's data directory: [`DATA_DIR_ENV`] if set, otherwise
+/// `data` in the working directory.
+#[must_use]
+pub fn data_dir() -> PathBuf {
+    env::var_os(DATA_DIR_ENV).map_or_else(|| PathBuf::from("data"), PathBuf::from)
+}
+
+/// A type stored in its own namespace.
+pub trait Record: Serialize + DeserializeOwned {
+    const NAMESPACE: &'static str;
+}
+
+/// An embedded database, shared between its clones.
+#[derive(Clone)]
+pub struct Storage {
+    db: Arc<Database>,
+}
+
+impl Storage {
+    /// Opens the database at `path`, creating it if it does not exist.
+    ///
+    /// # Errors
+    /// Returns [`StorageError::Database`] if the file cannot be opened, e.g.
+    /// because another process has it open.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let db = Database::create(path)?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Opens the database in the plugin's [`data_dir`], creating the
+    /// directory if needed.
+    ///
+    /// # Errors
+    /// Returns [`StorageError::Io`] if the directory cannot be created, and
+    /// otherwise as [`Storage::open`].
+    pub fn open_default() -> Result<Self, StorageError> {
+        let dir = data_dir();
+        std::fs::create_dir_all(&dir)?;
+        Self::open(dir.join(DATABASE_FILE))
+    }
+
+    /// Opens a database that lives in memory, for tests.
+    ///
+    /// # Errors
+    /// Returns [`StorageError::Database`] if the database cannot be created.
+    pub fn in_memory() -> Result<Self, StorageError> {
+        let backend = redb::backends::InMemoryBackend::new();
+        let db = Database::builder().create_with_backend(backend)?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Returns the store of values of type `T` in `namespace`.
+    #[must_use]
+    pub fn store<T>(&self, namespace: impl Into<Arc<str>>) -> Store<T> {
+        Store {
+            storage:   self.clone(),
+            namespace: namespace.into(),
+            _marker:   PhantomData,
+        }
+    }
+}
+
+/// The values of one namespace of a [`Storage`].
+///
+/// As an extractor, it is the store of [`Record::NAMESPACE`] in the
+/// [`Storage`] of the state.
+pub struct Store<T> {
+    storage:   Storage,
+    namespace: Arc<str>,
+    _marker:   PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Store<T> {
+    fn clone(&self) -> Self {
+        Self {
+            storage:   self.storage.clone(),
+            namespace: self.namespace.clone(),
+            _marker:   PhantomData,
+        }
+    }
+}
+
+/// A stored value with its expiry, in milliseconds since the Unix epoch.
+type Entry<T> = (Option<u64>, T);
+
+fn now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    u64::try_from(now.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn expiry(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now().saturating_add(ttl)
+}
+
+fn is_live(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], now: u64) -> Result<Option<Entry<T>>, StorageError> {
+    let (expires_at, value): Entry<T> = rmp_serde::from_slice(bytes)?;
+    Ok(is_live(expires_at, now).then_some((expires_at, value)))
+}
+
+const fn table(namespace: &str) -> TableDefinition<'_, &'static str, &'static [u8]> {
+    TableDefinition::new(namespace)
+}
+
+fn encode<T: Serialize>(expires_at: Option<u64>, value: &T) -> Result<Vec<u8>, StorageError> {
+    Ok(rmp_serde::to_vec(&(expires_at, value))?)
+}
+
+impl<T> Store<T> {
+    #[must_use]
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Runs `f` on the table in a read transaction, on the blocking thread
+    /// pool. A missing table is empty.
+    async fn read<R, F>(&self, f: F, empty: R) -> Result<R, StorageError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&redb::ReadOnlyTable<&'static str, &'static [u8]>) -> Result<R, StorageError>
+            + Send
+            + 'static,
+    {
+        let db = self.storage.db.clone();
+        let namespace = self.namespace.clone();
+        spawn_blocking(move || {
+            let tx = db.begin_read()?;
+            match tx.open_table(table(&namespace)) {
+                Ok(table) => f(&table),
+                Err(TableError::TableDoesNotExist(_)) => Ok(empty),
+                Err(err) => Err(err.into()),
+            }
+        })
+        .await?
+    }
+
+    /// Runs `f` on the table in a write transaction, on the blocking thread
+    /// pool, committing if it succeeds.
+    async fn write<R, F>(&self, f: F) -> Result<R, StorageError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut redb::Table<'_, &'static str, &'static [u8]>) -> Result<R, StorageError>
+            + Send
+            + 'static,
+    {
+        let db = self.storage.db.clone();
+        let namespace = self.namespace.clone();
+        spawn_blocking(move || {
+            let tx = db.begin_write()?;
+            let result = {
+                let mut table = tx.open_table(table(&namespace))?;
+                f(&mut table)?
+            };
+            tx.commit()?;
+            Ok(result)
+        })
+        .await?
+    }
+
+    /// Returns `true` if there is a live value for `key`.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be read.
+    pub async fn contains_key(&self, key: &str) -> Result<bool, StorageError> {
+        let now = now();
+        let key = key.to_owned();
+        self.read(
+            move |table| {
+                let Some(bytes) = table.get(key.as_str())? else {
+                    return Ok(false);
+                };
+                let (expires_at, _): Entry<serde::de::IgnoredAny> =
+                    rmp_serde::from_slice(bytes.value())?;
+                Ok(is_live(expires_at, now))
+            },
+            false,
+        )
+        .await
+    }
+
+    /// Returns the keys of all live values.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be read.
+    pub async fn keys(&self) -> Result<Vec<String>, StorageError> {
+        let now = now();
+        self.read(
+            move |table| {
+                let mut keys = Vec::new();
+                for entry in table.iter()? {
+                    let (key, bytes) = entry?;
+                    let (expires_at, _): Entry<serde::de::IgnoredAny> =
+                        rmp_serde::from_slice(bytes.value())?;
+                    if is_live(expires_at, now) {
+                        keys.push(key.value().to_owned());
+                    }
+                }
+                Ok(keys)
+            },
+            Vec::new(),
+        )
+        .await
+    }
+
+    /// Removes all values in the namespace.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be written.
+    pub async fn clear(&self) -> Result<(), StorageError> {
+        self.write(|table| {
+            table.retain(|_, _| false)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the expired values in the namespace. Expired values are never
+    /// returned, but only removed when overwritten or purged.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be written.
+    pub async fn purge_expired(&self) -> Result<(), StorageError> {
+        let now = now();
+        self.write(move |table| {
+            table.retain(|_, bytes| {
+                rmp_serde::from_slice::<Entry<serde::de::IgnoredAny>>(bytes)
+                    .is_ok_and(|(expires_at, _)| is_live(expires_at, now))
+            })?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl<T> Store<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Returns the value for `key`, unless it is missing or expired.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be read or the value cannot be
+    /// decoded as `T`.
+    pub async fn get(&self, key: &str) -> Result<Option<T>, StorageError> {
+        let now = now();
+        let key = key.to_owned();
+        self.read(
+            move |table| {
+                let Some(bytes) = table.get(key.as_str())? else {
+                    return Ok(None);
+                };
+                Ok(decode(bytes.value(), now)?.map(|(_, value)| value))
+            },
+            None,
+        )
+        .await
+    }
+
+    /// Sets the value for `key`, without expiry.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be written or the value cannot
+    /// be encoded.
+    pub async fn insert(&self, key: &str, value: &T) -> Result<(), StorageError> {
+        self.insert_entry(key, None, value).await
+    }
+
+    /// Sets the value for `key`, expiring after `ttl`.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be written or the value cannot
+    /// be encoded.
+    pub async fn insert_with_ttl(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<(), StorageError> {
+        self.insert_entry(key, Some(expiry(ttl)), value).await
+    }
+
+    async fn insert_entry(
+        &self,
+        key: &str,
+        expires_at: Option<u64>,
+        value: &T,
+    ) -> Result<(), StorageError> {
+        let bytes = encode(expires_at, value)?;
+        let key = key.to_owned();
+        self.write(move |table| {
+            table.insert(key.as_str(), bytes.as_slice())?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the value for `key`, returning it unless it was missing or
+    /// expired.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be written.
+    pub async fn remove(&self, key: &str) -> Result<Option<T>, StorageError> {
+        let now = now();
+        let key = key.to_owned();
+        self.write(move |table| {
+            let Some(bytes) = table.remove(key.as_str())? else {
+                return Ok(None);
+            };
+            Ok(decode(bytes.value(), now)?.map(|(_, value)| value))
+        })
+        .await
+    }
+
+    /// Replaces the value for `key` with the result of `f` on the current
+    /// one, atomically, and returns the new value. `None` removes the value.
+    ///
+    /// A value that had a TTL keeps its expiry.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be written, or the values
+    /// cannot be encoded or decoded. Nothing is changed then.
+    pub async fn update<F>(&self, key: &str, f: F) -> Result<Option<T>, StorageError>
+    where
+        F: FnOnce(Option<T>) -> Option<T> + Send + 'static,
+    {
+        let now = now();
+        let key = key.to_owned();
+        self.write(move |table| {
+            let current = match table.get(key.as_str())? {
+                Some(bytes) => decode::<T>(bytes.value(), now)?,
+                None => None,
+            };
+            let (expires_at, current) =
+                current.map_or((None, None), |(at, value)| (at, Some(value)));
+            let Some(value) = f(current) else {
+                table.remove(key.as_str())?;
+                return Ok(None);
+            };
+            let bytes = encode(expires_at, &value)?;
+            table.insert(key.as_str(), bytes.as_slice())?;
+            Ok(Some(value))
+        })
+        .await
+    }
+}
+
+impl<S, T> FromRequest<S> for Store<T>
+where
+    Storage: FromRef<S>,
+    T: Record,
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request(
+        _req: sithra_server::sync::Arc<RequestDataPack>,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Storage::from_ref(state).store(T::NAMESPACE))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Failed to create data directory: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    Database(Box<redb::Error>),
+    #[error("Failed to encode value: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("Failed to decode value: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+    #[error("Storage task failed: {0}")]
+    Task(#[from] JoinError),
+}
+
+macro_rules! from_redb_error {
+    ($($error:ty),*) => {$(
+        impl From<$error> for StorageError {
+            fn from(error: $error) -> Self {
+                Self::Database(Box::new(error.into()))
+            }
+        }
+    )*};
+}
+
+from_redb_error!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+    use sithra_server::{
+        extract::payload::Payload, on, testing::TestHost, transport::datapack::RequestDataPack,
+    };
+
+    use super::{Record, Storage, Store};
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Counter(u64);
+
+    impl Record for Counter {
+        const NAMESPACE: &str = "counter";
+    }
+
+    #[tokio::test]
+    async fn store() {
+        let storage = Storage::in_memory().unwrap();
+        let store = storage.store::<String>("names");
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert!(store.keys().await.unwrap().is_empty());
+
+        store.insert("a", &"alice".to_owned()).await.unwrap();
+        store.insert("b", &"bob".to_owned()).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap().as_deref(), Some("alice"));
+        assert!(store.contains_key("b").await.unwrap());
+        assert_eq!(
+            storage.store::<String>("other").get("a").await.unwrap(),
+            None
+        );
+
+        assert_eq!(store.remove("a").await.unwrap().as_deref(), Some("alice"));
+        assert_eq!(store.keys().await.unwrap(), ["b"]);
+        store.clear().await.unwrap();
+        assert!(!store.contains_key("b").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn ttl() {
+        let storage = Storage::in_memory().unwrap();
+        let store = storage.store::<u32>("ttl");
+        store.insert_with_ttl("short", &1, Duration::from_millis(20)).await.unwrap();
+        store.insert_with_ttl("long", &2, Duration::from_mins(1)).await.unwrap();
+        store.update("long", |n| n.map(|n| n + 1)).await.unwrap();
+        assert_eq!(store.get("short").await.unwrap(), Some(1));
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(store.get("short").await.unwrap(), None);
+        assert_eq!(store.update("short", |n| n).await.unwrap(), None);
+        store.purge_expired().await.unwrap();
+        assert_eq!(store.keys().await.unwrap(), ["long"]);
+        assert_eq!(store.get("long").await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn persist() {
+        let path = std::env::temp_dir().join(format!("sithra-storage-{}.redb", std::process::id()));
+        Storage::open(&path).unwrap().store::<u32>("n").insert("k", &7).await.unwrap();
+        let value = Storage::open(&path).unwrap().store::<u32>("n").get("k").await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(value, Some(7));
+    }
+
+    #[tokio::test]
+    async fn extractor() {
+        let storage = Storage::in_memory().unwrap();
+        let router = sithra_server::routing::router::Router::new()
+            .route(
+                "/count",
+                on(async |store: Store<Counter>| {
+                    let counter = store.update("hits", |counter| {
+                        let Counter(n) = counter.unwrap_or_default();
+                        Some(Counter(n + 1))
+                    });
+                    counter.await.unwrap().map(|Counter(n)| Payload(n))
+                }),
+            )
+            .with_state(storage.clone());
+        let mut host = TestHost::new(router);
+        for expected in 1..=2 {
+            let response = host.request(RequestDataPack::default().path("/count")).await.unwrap();
+            assert_eq!(response.payload::<u64>().unwrap(), expected);
+        }
+        assert_eq!(
+            storage.store::<Counter>("counter").get("hits").await.unwrap(),
+            Some(Counter(2))
+        );
+    }
+}
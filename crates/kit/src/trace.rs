@@ -0,0 +1,165 @@
+//! Forwards [`tracing`] spans to the host.
+//!
+//! [`SpanForwarder`] is a [`tracing_subscriber`] layer that sends closed
+//! spans to the host as a [`Span`], the same way [`logger`](crate::logger)
+//! forwards log records. Spans opened by the
+//! [`Trace`](sithra_server::trace::Trace) layer carry the trace context of
+//! their request, which the host's routing span is the parent of; other spans
+//! inherit it from their parent. Spans outside of any trace are not sent.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sithra_server::{
+    server::ClientSink,
+    transport::{
+        datapack::RequestDataPack,
+        trace::{TraceContext, new_span_id},
+    },
+};
+use sithra_types::trace::Span;
+use tracing::{
+    Level, Subscriber,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+};
+use tracing_subscriber::{
+    Layer, filter::Targets, layer::Context, prelude::*, registry::LookupSpan,
+};
+
+pub struct SpanForwarder(ClientSink);
+
+impl SpanForwarder {
+    #[must_use]
+    pub const fn new(client_sink: ClientSink) -> Self {
+        Self(client_sink)
+    }
+}
+
+/// Installs a global subscriber that forwards spans to the host.
+///
+/// Only spans at `INFO` or above are forwarded, except for the `request`
+/// spans of the [`Trace`](sithra_server::trace::Trace) layer, which are at
+/// `DEBUG`.
+pub fn init_trace(client_sink: ClientSink) {
+    let filter = Targets::new()
+        .with_target("sithra_server::trace", Level::DEBUG)
+        .with_default(Level::INFO);
+    let forwarder = SpanForwarder::new(client_sink).with_filter(filter);
+    let subscriber = tracing_subscriber::registry().with(forwarder);
+    tracing::subscriber::set_global_default(subscriber).ok();
+}
+
+struct SpanData {
+    trace_id:       Option<String>,
+    span_id:        Option<String>,
+    parent_span_id: Option<String>,
+    fields:         BTreeMap<String, String>,
+    start:          SystemTime,
+    started:        Instant,
+}
+
+impl Visit for SpanData {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = value.to_owned();
+        match field.name() {
+            "trace_id" => self.trace_id = Some(value),
+            "span_id" => self.span_id = Some(value),
+            "parent_span_id" => self.parent_span_id = Some(value),
+            name => {
+                self.fields.insert(name.to_owned(), value);
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let mut text = String::new();
+        if write!(text, "{value:?}").is_ok() {
+            self.record_str(field, &text);
+        }
+    }
+}
+
+impl<S> Layer<S> for SpanForwarder
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut data = SpanData {
+            trace_id:       None,
+            span_id:        None,
+            parent_span_id: None,
+            fields:         BTreeMap::new(),
+            start:          SystemTime::now(),
+            started:        Instant::now(),
+        };
+        attrs.record(&mut data);
+        if data.trace_id.is_none() {
+            let parent = span.parent().and_then(|parent| {
+                parent
+                    .extensions()
+                    .get::<SpanData>()
+                    .map(|parent| (parent.trace_id.clone(), parent.span_id.clone()))
+            });
+            if let Some((trace_id, span_id)) = parent {
+                data.trace_id = trace_id;
+                data.parent_span_id = span_id;
+            }
+        }
+        if data.trace_id.is_none() {
+            return;
+        }
+        data.span_id.get_or_insert_with(new_span_id);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+            values.record(data);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        let metadata = span.metadata();
+        let start = data.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let span = Span {
+            name:           metadata.name().to_owned(),
+            target:         metadata.target().to_owned(),
+            level:          level(*metadata.level()),
+            trace:          TraceContext {
+                trace_id: data.trace_id.unwrap_or_default(),
+                span_id:  data.span_id.unwrap_or_default(),
+            },
+            parent_span_id: data.parent_span_id,
+            fields:         data.fields,
+            start:          u64::try_from(start.as_micros()).unwrap_or(u64::MAX),
+            duration:       u64::try_from(data.started.elapsed().as_micros()).unwrap_or(u64::MAX),
+        };
+        self.0.send(RequestDataPack::from(span)).ok();
+    }
+}
+
+const fn level(level: tracing::Level) -> log::Level {
+    match level {
+        tracing::Level::ERROR => log::Level::Error,
+        tracing::Level::WARN => log::Level::Warn,
+        tracing::Level::INFO => log::Level::Info,
+        tracing::Level::DEBUG => log::Level::Debug,
+        tracing::Level::TRACE => log::Level::Trace,
+    }
+}
@@ -1,10 +1,10 @@
-use std::{convert::Infallible, fmt};
+use std::{cmp::Reverse, convert::Infallible, fmt};
 
 use tower::Service;
 
 use crate::{
     handler::Handler,
-    multi::MultiServiceRaceAnyError,
+    multi::{ChainService, MultiServiceRaceAnyError},
     request::Request,
     routing::{
         endpoint::Endpoint,
@@ -44,6 +44,25 @@ where
             },
         }))
     }
+
+    /// Calls `endpoints` by descending priority, see [`ChainService`].
+    ///
+    /// Endpoints with the same priority are called in the given order.
+    #[must_use]
+    pub fn from_chain(endpoints: impl IntoIterator<Item = (i32, Endpoint<S, Infallible>)>) -> Self {
+        let mut endpoints = endpoints.into_iter().collect::<Vec<_>>();
+        endpoints.sort_by_key(|(priority, _)| Reverse(*priority));
+        let endpoints = endpoints.into_iter().map(|(_, endpoint)| endpoint).collect::<Vec<_>>();
+        Self(Box::new(MakeErasedHandler {
+            handler:    endpoints,
+            into_route: |endpoints, state: S| {
+                Route::new(ChainService::new(endpoints.into_iter().map(|h| match h {
+                    Endpoint::Route(r) => r,
+                    Endpoint::BoxedHandler(s) => s.into_route(state.clone()),
+                })))
+            },
+        }))
+    }
 }
 
 impl<S, E> BoxedIntoRoute<S, E> {
@@ -5,12 +5,17 @@ use crate::{boxed::BoxedIntoRoute, handler::Handler, routing::endpoint::Endpoint
 pub mod boxed;
 pub mod extract;
 pub mod handler;
+pub mod metrics;
 pub mod multi;
 pub mod request;
 pub mod response;
 pub mod routing;
 pub mod server;
 pub mod shared;
+pub mod stream;
+pub mod subscription;
+pub mod testing;
+pub mod trace;
 pub use sithra_transport as transport;
 pub mod sync {
     pub use triomphe::*;
@@ -54,6 +59,34 @@ where
     Endpoint::BoxedHandler(BoxedIntoRoute::from_multi(endpoints))
 }
 
+/// Calls `endpoints` one after another by descending priority, until one of
+/// them consumes the request.
+///
+/// A handler passes the request on by returning
+/// [`Flow::Next`](multi::Flow::Next); any other response consumes it. This
+/// lets filters, commands and a fallback share one path:
+///
+/// ```
+/// # use sithra_server::{chain, multi::Flow, on, routing::router::Router};
+/// let router: Router = Router::new().route(
+///     "/event/message.created",
+///     chain([
+///         (100, on(async || Flow::<()>::Next)),
+///         (0, on(async || Flow::Consumed(()))),
+///         (-100, on(async || ())),
+///     ]),
+/// );
+/// ```
+#[must_use]
+pub fn chain<S, const N: usize>(
+    endpoints: [(i32, Endpoint<S, Infallible>); N],
+) -> Endpoint<S, Infallible>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Endpoint::BoxedHandler(BoxedIntoRoute::from_chain(endpoints))
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::atomic::{AtomicUsize, Ordering};
@@ -129,6 +162,6 @@ mod tests {
         let response = router.call(request).await.unwrap();
         tokio::task::yield_now().await;
         assert_eq!(state.counter.load(Ordering::SeqCst), 4);
-        assert_eq!(response.data.map(|r| r.correlation), Some(correlation));
+        assert_eq!(response.data.first().map(|r| r.correlation), Some(correlation));
     }
 }
@@ -0,0 +1,489 @@
+//! Metrics collection.
+//!
+//! A process-wide [`Registry`] holds counters, gauges and histograms, keyed by
+//! name and labels. The [`Metrics`] layer records per-path request counts,
+//! errors and latency, and [`Server`](crate::server::Server) records its
+//! queue depth and in-flight requests. A [`snapshot`](Registry::snapshot) of
+//! the registry can be sent to the host, which [`render`]s the snapshots of
+//! all plugins in the Prometheus text format.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use futures_util::{FutureExt, future::BoxFuture};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sithra_transport::datapack::DataResult;
+use tower::{Layer, Service};
+
+use crate::{request::Request, response::Response, routing::route::Route};
+
+/// Upper bounds of the buckets of every histogram, in seconds.
+pub const BUCKETS: [f64; 12] =
+    [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// The `path` label of requests that did not match a route.
+const UNMATCHED: &str = "unmatched";
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+/// Returns the registry of this process.
+#[must_use]
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+type Labels = Vec<(String, String)>;
+
+enum Series {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+struct Family {
+    help:   &'static str,
+    kind:   MetricKind,
+    series: BTreeMap<Labels, Series>,
+}
+
+/// A set of metrics.
+#[derive(Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Registry {
+    fn series<T>(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        kind: MetricKind,
+        labels: &[(&str, &str)],
+        new: impl FnOnce() -> Series,
+        get: impl FnOnce(&Series) -> Option<T>,
+    ) -> T {
+        let mut labels: Labels = labels
+            .iter()
+            .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+            .collect();
+        labels.sort();
+        let mut families = self.families.lock();
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            kind,
+            series: BTreeMap::new(),
+        });
+        assert!(
+            family.kind == kind,
+            "metric `{name}` is already registered as a {:?}",
+            family.kind
+        );
+        let series = get(family.series.entry(labels).or_insert_with(new));
+        drop(families);
+        series.expect("unreachable")
+    }
+
+    /// Returns the counter `name` with `labels`, registering it if needed.
+    ///
+    /// # Panics
+    /// Panics if `name` is already registered as another kind of metric.
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Counter {
+        self.series(
+            name,
+            help,
+            MetricKind::Counter,
+            labels,
+            || Series::Counter(Counter::default()),
+            |series| match series {
+                Series::Counter(counter) => Some(counter.clone()),
+                _ => None,
+            },
+        )
+    }
+
+    /// Returns the gauge `name` with `labels`, registering it if needed.
+    ///
+    /// # Panics
+    /// Panics if `name` is already registered as another kind of metric.
+    pub fn gauge(&self, name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Gauge {
+        self.series(
+            name,
+            help,
+            MetricKind::Gauge,
+            labels,
+            || Series::Gauge(Gauge::default()),
+            |series| match series {
+                Series::Gauge(gauge) => Some(gauge.clone()),
+                _ => None,
+            },
+        )
+    }
+
+    /// Returns the histogram `name` with `labels`, registering it if needed.
+    ///
+    /// # Panics
+    /// Panics if `name` is already registered as another kind of metric.
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Histogram {
+        self.series(
+            name,
+            help,
+            MetricKind::Histogram,
+            labels,
+            || Series::Histogram(Histogram::default()),
+            |series| match series {
+                Series::Histogram(histogram) => Some(histogram.clone()),
+                _ => None,
+            },
+        )
+    }
+
+    /// Returns the current value of every metric.
+    #[must_use]
+    pub fn snapshot(&self) -> Vec<MetricFamily> {
+        self.families
+            .lock()
+            .iter()
+            .map(|(name, family)| MetricFamily {
+                name:    (*name).to_owned(),
+                help:    family.help.to_owned(),
+                kind:    family.kind,
+                samples: family
+                    .series
+                    .iter()
+                    .map(|(labels, series)| Sample {
+                        labels: labels.clone(),
+                        value:  match series {
+                            Series::Counter(counter) => SampleValue::Counter(counter.get()),
+                            Series::Gauge(gauge) => SampleValue::Gauge(gauge.get()),
+                            Series::Histogram(histogram) => histogram.sample(),
+                        },
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// A monotonically increasing count.
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A distribution of values, counted in [`BUCKETS`].
+#[derive(Debug, Clone, Default)]
+pub struct Histogram(Arc<HistogramInner>);
+
+#[derive(Debug, Default)]
+struct HistogramInner {
+    /// One count per bucket, plus one for `+Inf`.
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    /// The sum of all values, as `f64` bits.
+    sum:    AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: f64) {
+        let bucket = BUCKETS.iter().position(|bound| value <= *bound).unwrap_or(BUCKETS.len());
+        self.0.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.0
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            })
+            .ok();
+    }
+
+    fn sample(&self) -> SampleValue {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(BUCKETS.len());
+        for (bound, bucket) in BUCKETS.iter().zip(&self.0.counts) {
+            count += bucket.load(Ordering::Relaxed);
+            buckets.push((*bound, count));
+        }
+        count += self.0.counts[BUCKETS.len()].load(Ordering::Relaxed);
+        SampleValue::Histogram {
+            buckets,
+            sum: f64::from_bits(self.0.sum.load(Ordering::Relaxed)),
+            count,
+        }
+    }
+}
+
+/// All samples of one metric.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetricFamily {
+    pub name:    String,
+    pub help:    String,
+    pub kind:    MetricKind,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sample {
+    pub labels: Vec<(String, String)>,
+    pub value:  SampleValue,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum SampleValue {
+    Counter(u64),
+    Gauge(i64),
+    /// Cumulative bucket counts, keyed by their upper bound.
+    Histogram {
+        buckets: Vec<(f64, u64)>,
+        sum:     f64,
+        count:   u64,
+    },
+}
+
+/// Renders metric families in the Prometheus text format.
+///
+/// Families with the same name are merged, so the snapshots of several
+/// processes can be rendered together once they are told apart by a label.
+#[must_use]
+pub fn render<'a>(families: impl IntoIterator<Item = &'a MetricFamily>) -> String {
+    let mut merged: BTreeMap<&str, (&MetricFamily, Vec<&Sample>)> = BTreeMap::new();
+    for family in families {
+        merged
+            .entry(&family.name)
+            .or_insert_with(|| (family, Vec::new()))
+            .1
+            .extend(&family.samples);
+    }
+    let mut out = String::new();
+    for (name, (family, samples)) in merged {
+        let kind = match family.kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {name} {}", family.help);
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for sample in samples {
+            let labels = &sample.labels;
+            match &sample.value {
+                SampleValue::Counter(value) => {
+                    let _ = writeln!(out, "{name}{} {value}", format_labels(labels, None));
+                }
+                SampleValue::Gauge(value) => {
+                    let _ = writeln!(out, "{name}{} {value}", format_labels(labels, None));
+                }
+                SampleValue::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    for (bound, value) in buckets {
+                        let le = bound.to_string();
+                        let labels = format_labels(labels, Some(&le));
+                        let _ = writeln!(out, "{name}_bucket{labels} {value}");
+                    }
+                    let inf = format_labels(labels, Some("+Inf"));
+                    let labels = format_labels(labels, None);
+                    let _ = writeln!(out, "{name}_bucket{inf} {count}");
+                    let _ = writeln!(out, "{name}_sum{labels} {sum}");
+                    let _ = writeln!(out, "{name}_count{labels} {count}");
+                }
+            }
+        }
+    }
+    out
+}
+
+fn format_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .chain(le.map(|le| ("le", le)));
+    let mut out = String::new();
+    for (key, value) in labels {
+        out.push(if out.is_empty() { '{' } else { ',' });
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        let _ = write!(out, "{key}=\"{value}\"");
+    }
+    if !out.is_empty() {
+        out.push('}');
+    }
+    out
+}
+
+/// A layer that records the count, errors and latency of requests per path.
+///
+/// Records `sithra_requests_total`, `sithra_request_errors_total` and
+/// `sithra_request_duration_seconds` in the [`registry`], labelled with the
+/// path pattern of the matched route as `path`, so that parameterised routes
+/// like `/user/{id}` share one series.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metrics;
+
+impl Layer<Route> for Metrics {
+    type Service = MetricsLayer;
+
+    fn layer(&self, inner: Route) -> Self::Service {
+        MetricsLayer { svc: inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    svc: Route,
+}
+
+impl Service<Request> for MetricsLayer {
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+    type Response = Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.svc.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let labels = [("path", req.route().unwrap_or(UNMATCHED))];
+        let registry = registry();
+        registry.counter("sithra_requests_total", "Requests handled.", &labels).inc();
+        let errors = registry.counter(
+            "sithra_request_errors_total",
+            "Requests answered with an error.",
+            &labels,
+        );
+        let duration = registry.histogram(
+            "sithra_request_duration_seconds",
+            "Time spent handling requests.",
+            &labels,
+        );
+        let start = Instant::now();
+        self.svc
+            .call(req)
+            .map(move |response| {
+                duration.observe(start.elapsed().as_secs_f64());
+                if let Ok(response) = &response {
+                    if response.data.iter().any(|data| matches!(data.result, DataResult::Error(_)))
+                    {
+                        errors.inc();
+                    }
+                }
+                response
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sithra_transport::datapack::RequestDataPack;
+    use tower::Service;
+
+    use super::{MetricFamily, Metrics, Registry, registry, render};
+    use crate::{on, request::Request, routing::router::Router};
+
+    #[test]
+    fn render_text() {
+        let registry = Registry::default();
+        registry.counter("requests_total", "Requests.", &[("path", "/a")]).add(2);
+        registry.gauge("in_flight", "In flight.", &[]).set(3);
+        let histogram = registry.histogram("duration_seconds", "Duration.", &[]);
+        histogram.observe(0.003);
+        histogram.observe(7.0);
+
+        let mut other = registry.snapshot();
+        for family in &mut other {
+            for sample in &mut family.samples {
+                sample.labels.push(("plugin".to_owned(), "b".to_owned()));
+            }
+        }
+        let families: Vec<MetricFamily> = registry.snapshot().into_iter().chain(other).collect();
+        let text = render(&families);
+
+        assert!(text.contains("# TYPE requests_total counter\n"));
+        assert!(text.contains("requests_total{path=\"/a\"} 2\n"));
+        assert!(text.contains("requests_total{path=\"/a\",plugin=\"b\"} 2\n"));
+        assert!(text.contains("in_flight 3\n"));
+        assert!(text.contains("duration_seconds_bucket{le=\"0.0025\"} 0\n"));
+        assert!(text.contains("duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("duration_seconds_count 2\n"));
+        assert_eq!(text.matches("# TYPE in_flight gauge").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn route_labels() {
+        let mut router: Router =
+            Router::new().route("/metrics-test/{id}", on(async || {})).layer(Metrics);
+        for path in ["/metrics-test/1", "/metrics-test/2"] {
+            let data = RequestDataPack::default().path(path);
+            router.call(Request::new(data)).await.unwrap();
+        }
+
+        let labels = [("path", "/metrics-test/{id}")];
+        let requests = registry().counter("sithra_requests_total", "", &labels);
+        assert_eq!(requests.get(), 2);
+        let text = render(&registry().snapshot());
+        assert!(!text.contains("/metrics-test/1"));
+    }
+}
@@ -1,16 +1,25 @@
 use std::{
+    convert::Infallible,
     fmt::{Debug, Display},
     future::Future,
     mem::MaybeUninit,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 
+use futures_util::future::BoxFuture;
 use pin_project::pin_project;
 use smallvec::SmallVec;
-use tokio::{pin, task::JoinSet};
+use tokio::task::JoinSet;
 use tower::Service;
 
+use crate::{
+    request::Request,
+    response::{IntoResponse, Response},
+    routing::route::Route,
+};
+
 /// A service that wraps multiple services of the same type and dispatches
 /// requests to all of them concurrently, returning a future that acts as a
 /// `JoinSet`.
@@ -545,15 +554,100 @@ where
     }
 }
 
+/// What a handler of a [`chain`](crate::chain) does with a request.
+///
+/// Returning [`Flow::Next`] passes the request on to the next handler, and
+/// [`Flow::Consumed`] stops the chain with a response. Any other response
+/// consumes the request as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow<R = ()> {
+    Next,
+    Consumed(R),
+}
+
+impl<R: IntoResponse> IntoResponse for Flow<R> {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Next => {
+                let mut response = Response::none();
+                response.next = true;
+                response
+            }
+            Self::Consumed(response) => {
+                let mut response = response.into_response();
+                response.next = false;
+                response
+            }
+        }
+    }
+}
+
+/// A service that calls routes one after another until one of them consumes
+/// the request.
+///
+/// The packs of every called route are sent. The chain itself passes the
+/// request on if all of its routes do, so chains can be nested.
+#[derive(Debug, Clone)]
+pub struct ChainService {
+    routes: Arc<[Route]>,
+}
+
+impl ChainService {
+    /// Creates a new `ChainService` calling `routes` in order.
+    #[must_use]
+    pub fn new(routes: impl IntoIterator<Item = Route>) -> Self {
+        Self {
+            routes: routes.into_iter().collect(),
+        }
+    }
+}
+
+impl Service<Request> for ChainService {
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+    type Response = Response;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let routes = self.routes.clone();
+        Box::pin(async move {
+            let mut response = Response::none();
+            for route in routes.iter() {
+                let Ok(current) = route.clone().call_owned(req.clone()).await;
+                let next = current.next;
+                response.push(current);
+                response.next = next;
+                if !next {
+                    break;
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use std::sync::atomic::{AtomicUsize, Ordering};
 
     use futures_util::future::BoxFuture;
+    use sithra_transport::datapack::RequestDataPack;
+    use tower::Service;
     use triomphe::Arc;
 
-    use crate::multi::{MultiError, MultiFutureJoin, MultiFutureRace};
+    use super::Flow;
+    use crate::{
+        chain,
+        extract::payload::Payload,
+        multi::{MultiError, MultiFutureJoin, MultiFutureRace},
+        on,
+        request::Request,
+        routing::router::Router,
+    };
 
     #[tokio::test]
     async fn multi_future_all_completed() {
@@ -651,4 +745,56 @@ mod tests {
             tokio::task::yield_now().await;
         }
     }
+
+    #[tokio::test]
+    async fn chain_priority() {
+        let calls = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let record = |name: &'static str, flow: Flow<Payload<&'static str>>| {
+            let calls = calls.clone();
+            on(async move || {
+                calls.lock().push(name);
+                flow
+            })
+        };
+        let mut router: Router = Router::new()
+            .route(
+                "/message",
+                chain([
+                    (0, record("command", Flow::Consumed(Payload("command")))),
+                    (
+                        -100,
+                        record("fallback", Flow::Consumed(Payload("fallback"))),
+                    ),
+                    (100, record("filter", Flow::Next)),
+                ]),
+            )
+            .route(
+                "/other",
+                chain([
+                    (
+                        10,
+                        chain([(0, on(async || (Payload("logged"), Flow::<()>::Next)))]),
+                    ),
+                    (0, record("fallback", Flow::Consumed(Payload("fallback")))),
+                ]),
+            );
+
+        let request = Request::new(RequestDataPack::default().path("/message"));
+        let response = router.call(request).await.unwrap();
+        assert_eq!(*calls.lock(), ["filter", "command"]);
+        assert!(!response.is_next());
+        assert_eq!(response.data.len(), 1);
+        assert_eq!(response.data[0].payload::<String>().unwrap(), "command");
+
+        calls.lock().clear();
+        let request = Request::new(RequestDataPack::default().path("/other"));
+        let response = router.call(request).await.unwrap();
+        assert_eq!(*calls.lock(), ["fallback"]);
+        let payloads = response
+            .data
+            .iter()
+            .map(|data| data.payload::<String>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, ["logged", "fallback"]);
+    }
 }
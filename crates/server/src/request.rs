@@ -6,6 +6,7 @@ use ulid::Ulid;
 #[derive(Clone, Debug)]
 pub struct Request {
     pub data: Arc<RequestDataPack>,
+    route:    Option<std::sync::Arc<str>>,
 }
 
 impl From<RequestDataPack> for Request {
@@ -16,7 +17,7 @@ impl From<RequestDataPack> for Request {
 
 impl From<Arc<RequestDataPack>> for Request {
     fn from(value: Arc<RequestDataPack>) -> Self {
-        Self { data: value }
+        Self::from_raw(value)
     }
 }
 
@@ -33,14 +34,24 @@ impl Request {
 
     #[must_use]
     pub const fn from_raw(data: Arc<RequestDataPack>) -> Self {
-        Self { data }
+        Self { data, route: None }
     }
 
     #[must_use]
     pub fn new(data: RequestDataPack) -> Self {
-        Self {
-            data: Arc::new(data),
-        }
+        Self::from_raw(Arc::new(data))
+    }
+
+    /// The path pattern of the route that matched the request, such as
+    /// `/user/{id}`, once the router has routed it.
+    #[must_use]
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    pub(crate) fn with_route(mut self, route: std::sync::Arc<str>) -> Self {
+        self.route = Some(route);
+        self
     }
 
     #[must_use]
@@ -1,23 +1,45 @@
 use std::{
     convert::Infallible,
+    fmt,
     pin::Pin,
     task::{Context, Poll},
 };
 
-use futures_util::ready;
+use futures_util::{Stream, StreamExt, ready, stream::BoxStream};
 use pin_project::pin_project;
 use serde::Serialize;
 use sithra_transport::{
     channel::Channel,
     datapack::{DataPack, RequestDataPack},
 };
+use smallvec::{SmallVec, smallvec};
 use tower::Service;
 use ulid::Ulid;
 
 use crate::{extract::payload::Payload, request::Request};
 
+/// The packs a handler emits for one request.
+///
+/// A response holds zero or more [`DataPack`]s, optionally followed by a
+/// stream of further responses (see [`Streaming`]). When a route finishes,
+/// every pack is correlated with the request, and packs without a channel or
+/// bot ID inherit the request's.
+#[derive(Default)]
 pub struct Response {
-    pub data: Option<DataPack>,
+    pub data:        SmallVec<[DataPack; 1]>,
+    pub stream:      Option<BoxStream<'static, Self>>,
+    /// Whether a [`chain`](crate::chain) should go on to its next handler.
+    pub(crate) next: bool,
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("data", &self.data)
+            .field("stream", &self.stream.is_some())
+            .field("next", &self.next)
+            .finish()
+    }
 }
 
 pub struct Error<E: ToString>(E);
@@ -35,42 +57,198 @@ impl Response {
     #[must_use]
     pub fn new(data: impl Into<DataPack>) -> Self {
         Self {
-            data: Some(data.into()),
+            data:   smallvec![data.into()],
+            stream: None,
+            next:   false,
+        }
+    }
+
+    #[must_use]
+    pub fn none() -> Self {
+        Self {
+            data:   SmallVec::new(),
+            stream: None,
+            next:   false,
+        }
+    }
+
+    /// Creates a response that streams the items of `stream`.
+    #[must_use]
+    pub fn streaming<S>(stream: S) -> Self
+    where
+        S: Stream + Send + 'static,
+        S::Item: IntoResponse,
+    {
+        Self {
+            data:   SmallVec::new(),
+            stream: Some(stream.map(IntoResponse::into_response).boxed()),
+            next:   false,
         }
     }
 
     #[must_use]
-    pub const fn none() -> Self {
-        Self { data: None }
+    pub fn is_none(&self) -> bool {
+        self.data.is_empty() && self.stream.is_none()
     }
 
+    /// Returns whether a [`chain`](crate::chain) should go on to its next
+    /// handler, see [`Flow`](crate::multi::Flow).
     #[must_use]
-    pub const fn is_none(&self) -> bool {
-        self.data.is_none()
+    pub const fn is_next(&self) -> bool {
+        self.next
+    }
+
+    /// Appends the packs of `response`, streaming its items after ours.
+    ///
+    /// The result passes a [`chain`](crate::chain) on if either does.
+    pub fn push(&mut self, response: impl IntoResponse) {
+        let Self { data, stream, next } = response.into_response();
+        self.data.extend(data);
+        self.next |= next;
+        if let Some(stream) = stream {
+            self.stream = Some(match self.stream.take() {
+                Some(ours) => ours.chain(stream).boxed(),
+                None => stream,
+            });
+        }
     }
 
-    pub const fn correlate(&mut self, id: Ulid) {
-        if let Some(data) = self.data.as_mut() {
+    /// Applies `f` to every item of the stream, if any.
+    pub fn map_stream<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Self) + Send + 'static,
+    {
+        if let Some(stream) = self.stream.take() {
+            let stream = stream.map(move |mut response| {
+                f(&mut response);
+                response
+            });
+            self.stream = Some(stream.boxed());
+        }
+    }
+
+    /// Correlates the response packs with the request `id`.
+    ///
+    /// A single pack is correlated even if it is a request, such as a
+    /// `SendMessage` replying to a message. When there are several, the
+    /// requests among them keep their own correlation ID, so that their
+    /// replies do not answer the request being handled; the
+    /// [`Trace`](crate::trace::Trace) layer links them to it instead.
+    pub fn correlate(&mut self, id: Ulid) {
+        self.map_stream(move |response| response.correlate(id));
+        let single = self.data.len() == 1;
+        for data in self.data.iter_mut().filter(|data| single || data.path.is_none()) {
             data.correlate(id);
         }
     }
 
+    /// Sets the bot ID of every pack that has none.
     pub fn set_bot_id(&mut self, bot_id: &impl ToString) {
-        if let Some(data) = self.data.as_mut() {
-            data.bot_id = Some(bot_id.to_string());
+        let bot_id = bot_id.to_string();
+        for data in &mut self.data {
+            data.bot_id.get_or_insert_with(|| bot_id.clone());
         }
+        self.map_stream(move |response| response.set_bot_id(&bot_id));
     }
 
+    /// Sets the channel of every pack that has none.
     pub fn set_channel(&mut self, channel: Channel) {
-        if let Some(data) = self.data.as_mut() {
-            data.channel = Some(channel);
+        if self.stream.is_some() {
+            let channel = channel.clone();
+            self.map_stream(move |response| response.set_channel(channel.clone()));
+        }
+        let mut missing = self.data.iter_mut().filter(|data| data.channel.is_none()).peekable();
+        while let Some(data) = missing.next() {
+            if missing.peek().is_none() {
+                data.channel = Some(channel);
+                return;
+            }
+            data.channel = Some(channel.clone());
         }
     }
 
     pub fn error(error: &impl ToString) -> Self {
-        Self {
-            data: Some(DataPack::builder().build_with_error(error)),
+        Self::new(DataPack::builder().build_with_error(error))
+    }
+}
+
+/// Iterates over the packs of the response, ignoring its stream.
+impl IntoIterator for Response {
+    type IntoIter = smallvec::IntoIter<[DataPack; 1]>;
+    type Item = DataPack;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.into_iter()
+    }
+}
+
+impl<R: IntoResponse> FromIterator<R> for Response {
+    fn from_iter<T: IntoIterator<Item = R>>(iter: T) -> Self {
+        let mut response = Self::none();
+        for item in iter {
+            response.push(item);
         }
+        response
+    }
+}
+
+impl<R: IntoResponse> Extend<R> for Response {
+    fn extend<T: IntoIterator<Item = R>>(&mut self, iter: T) {
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+/// A builder for handlers that emit several packs.
+///
+/// ```
+/// # use sithra_server::{response::Responses, transport::datapack::RequestDataPack};
+/// let responses = Responses::new()
+///     .with(RequestDataPack::default().path("/message.create").payload("first"))
+///     .with(RequestDataPack::default().path("/message.create").payload("second"));
+/// ```
+#[derive(Debug, Default)]
+pub struct Responses(Response);
+
+impl Responses {
+    #[must_use]
+    pub fn new() -> Self {
+        Self(Response::none())
+    }
+
+    /// Appends `response`, returning the builder.
+    #[must_use]
+    pub fn with(mut self, response: impl IntoResponse) -> Self {
+        self.0.push(response);
+        self
+    }
+
+    /// Appends `response`.
+    pub fn push(&mut self, response: impl IntoResponse) {
+        self.0.push(response);
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.data.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl<R: IntoResponse> FromIterator<R> for Responses {
+    fn from_iter<T: IntoIterator<Item = R>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<R: IntoResponse> Extend<R> for Responses {
+    fn extend<T: IntoIterator<Item = R>>(&mut self, iter: T) {
+        self.0.extend(iter);
     }
 }
 
@@ -86,6 +264,12 @@ impl IntoResponse for Response {
     }
 }
 
+impl IntoResponse for Responses {
+    fn into_response(self) -> Response {
+        self.0
+    }
+}
+
 impl IntoResponse for RequestDataPack {
     fn into_response(self) -> Response {
         Response::new(self)
@@ -94,15 +278,19 @@ impl IntoResponse for RequestDataPack {
 
 impl IntoResponse for () {
     fn into_response(self) -> Response {
-        Response { data: None }
+        Response::none()
     }
 }
 
 impl<R: IntoResponse> IntoResponse for Option<R> {
     fn into_response(self) -> Response {
-        Response {
-            data: self.and_then(|v| v.into_response().data),
-        }
+        self.map_or_else(Response::none, IntoResponse::into_response)
+    }
+}
+
+impl<R: IntoResponse> IntoResponse for Vec<R> {
+    fn into_response(self) -> Response {
+        self.into_iter().collect()
     }
 }
 
@@ -114,10 +302,50 @@ impl IntoResponse for Infallible {
 
 impl IntoResponse for DataPack {
     fn into_response(self) -> Response {
-        Response { data: Some(self) }
+        Response::new(self)
     }
 }
 
+/// Streams the items of a [`Stream`] as they are produced.
+///
+/// The caller receives one response pack per item, the last one flagged as
+/// the end of the stream (see [`Client::post_stream`]). The stream is dropped
+/// if the caller cancels it.
+///
+/// [`Client::post_stream`]: crate::server::Client::post_stream
+pub struct Streaming<S>(pub S);
+
+impl<S> IntoResponse for Streaming<S>
+where
+    S: Stream + Send + 'static,
+    S::Item: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        Response::streaming(self.0)
+    }
+}
+
+macro_rules! into_response_for_tuple {
+    () => {};
+    ($first:ident $(, $rest:ident)*) => {
+        #[allow(non_snake_case, unused_mut)]
+        impl<$first, $($rest,)*> IntoResponse for ($first, $($rest,)*)
+        where
+            $first: IntoResponse,
+            $( $rest: IntoResponse, )*
+        {
+            fn into_response(self) -> Response {
+                let ($first, $($rest,)*) = self;
+                let mut response = $first.into_response();
+                $( response.push($rest); )*
+                response
+            }
+        }
+        into_response_for_tuple!($($rest),*);
+    };
+}
+into_response_for_tuple!(A, B, C, D, E, F, G, H, I, J, K, L);
+
 impl<S> IntoResponse for Error<S>
 where
     S: ToString,
@@ -200,3 +428,82 @@ where
         Poll::Ready(Ok(res.into_response()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sithra_transport::{
+        channel::{Channel, ChannelType},
+        datapack::{DataPack, RequestDataPack},
+    };
+    use tower::Service;
+
+    use super::Responses;
+    use crate::{extract::payload::Payload, on, request::Request, routing::router::Router};
+
+    fn channel(id: &str) -> Channel {
+        Channel {
+            id:        id.to_owned(),
+            ty:        ChannelType::Group,
+            name:      id.to_owned(),
+            parent_id: None,
+            self_id:   None,
+        }
+    }
+
+    #[tokio::test]
+    async fn multiple() {
+        let mut router: Router = Router::new()
+            .route(
+                "/tuple",
+                on(async || {
+                    (
+                        Payload("ok"),
+                        RequestDataPack::default().path("/event").channel(channel("other")),
+                    )
+                }),
+            )
+            .route(
+                "/reply",
+                on(async || RequestDataPack::default().path("/message.create")),
+            )
+            .route(
+                "/many",
+                on(async || {
+                    Responses::new()
+                        .with(vec![Payload(1), Payload(2)])
+                        .with(None::<Payload<()>>)
+                        .with(Payload(3))
+                }),
+            );
+
+        let request = RequestDataPack::default()
+            .path("/tuple")
+            .bot_id("bot")
+            .channel(channel("origin"));
+        let correlation = request.correlation();
+        let response = router.call(Request::new(request)).await.unwrap();
+        let [reply, event]: [DataPack; 2] = response.data.into_vec().try_into().unwrap();
+        assert!(reply.path.is_none());
+        assert_eq!(event.path.as_deref(), Some("/event"));
+        assert_eq!(reply.correlation, correlation);
+        assert_ne!(event.correlation, correlation);
+        assert!([&reply, &event].iter().all(|data| data.bot_id.as_deref() == Some("bot")));
+        assert_eq!(reply.channel.unwrap().id, "origin");
+        assert_eq!(event.channel.unwrap().id, "other");
+
+        let request = RequestDataPack::default().path("/reply");
+        let correlation = request.correlation();
+        let response = router.call(Request::new(request)).await.unwrap();
+        let [reply]: [DataPack; 1] = response.data.into_vec().try_into().unwrap();
+        assert_eq!(reply.path.as_deref(), Some("/message.create"));
+        assert_eq!(reply.correlation, correlation);
+
+        let response = router.call(Request::new(RequestDataPack::default().path("/many"))).await;
+        let values = response
+            .unwrap()
+            .into_iter()
+            .map(|data| data.payload::<i32>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(values, [1, 2, 3]);
+    }
+}
@@ -27,7 +27,8 @@ pub struct RouteId(u32);
 #[derive(Debug)]
 pub struct RouterInner<S> {
     routes:        HashMap<RouteId, Endpoint<S>>,
-    route_router:  RouteRouter<RouteId>,
+    route_router:  RouteRouter<(RouteId, std::sync::Arc<str>)>,
+    paths:         Vec<String>,
     prev_route_id: RouteId,
 }
 
@@ -43,6 +44,7 @@ impl<S> RouterInner<S> {
         Self {
             routes:        HashMap::new(),
             route_router:  RouteRouter::new(),
+            paths:         Vec::new(),
             prev_route_id: RouteId(0),
         }
     }
@@ -112,6 +114,7 @@ where
             Err(arc) => RouterInner {
                 routes:        arc.routes.clone(),
                 route_router:  arc.route_router.clone(),
+                paths:         arc.paths.clone(),
                 prev_route_id: arc.prev_route_id,
             },
         }
@@ -178,6 +181,11 @@ where
         !self.inner.routes.is_empty()
     }
 
+    /// Returns the path patterns of the routes, in the order they were added.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.inner.paths()
+    }
+
     pub fn with_state<S2>(self, state: S) -> Router<S2> {
         map_inner!(self, this => this.with_state(state))
     }
@@ -212,8 +220,10 @@ where
 {
     fn set_node(&mut self, path: &str, id: RouteId) -> Result<(), String> {
         self.route_router
-            .insert(path, id)
-            .map_err(|err| format!("Invalid route {path:?}: {err}"))
+            .insert(path, (id, path.into()))
+            .map_err(|err| format!("Invalid route {path:?}: {err}"))?;
+        self.paths.push(path.to_owned());
+        Ok(())
     }
 
     /// # Errors
@@ -270,6 +280,7 @@ where
         Self {
             routes,
             route_router: self.route_router,
+            paths: self.paths,
             prev_route_id: self.prev_route_id,
         }
     }
@@ -304,6 +315,7 @@ where
         Self {
             routes,
             route_router: self.route_router,
+            paths: self.paths,
             prev_route_id: self.prev_route_id,
         }
     }
@@ -312,6 +324,11 @@ where
         !self.routes.is_empty()
     }
 
+    /// Returns the path patterns of the routes, in the order they were added.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.paths.iter().map(String::as_str)
+    }
+
     pub(super) fn with_state<S2>(self, state: S) -> RouterInner<S2> {
         let routes = self
             .routes
@@ -330,6 +347,7 @@ where
         RouterInner {
             routes,
             route_router: self.route_router,
+            paths: self.paths,
             prev_route_id: self.prev_route_id,
         }
     }
@@ -343,14 +361,14 @@ where
         let raw = req.into_raw();
         match self.route_router.at(&raw.path) {
             Ok(match_) => {
-                let id = *match_.value;
+                let (id, pattern) = match_.value;
 
                 let endpoint = self
                     .routes
-                    .get(&id)
+                    .get(id)
                     .expect("no route for id. This is a bug in sithra. Please file an issue");
 
-                let req = Request::from_raw(raw);
+                let req = Request::from_raw(raw).with_route(pattern.clone());
                 match endpoint {
                     Endpoint::BoxedHandler(handler) => {
                         let route = handler.clone().into_route(state);
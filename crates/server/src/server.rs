@@ -7,17 +7,21 @@
 //! The `Client` provides a simple way to send requests to the `Server` and
 //! receive responses.
 
-use std::convert::Infallible;
+use std::{convert::Infallible, sync::Arc, task::Poll};
 
 use either::Either;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{SinkExt, StreamExt, poll};
+use matchit::InsertError;
 use sithra_transport::{
-    datapack::{DataPack, DataPackCodec, DataPackCodecError, RequestDataPack},
+    datapack::{
+        DataPack, DataPackCodec, DataPackCodecError, DataResult, RequestDataPack, StreamFlag,
+    },
     peer::{Reader, Writer},
 };
 use thiserror::Error;
 use tokio::{
     sync::{
+        Semaphore,
         mpsc::{UnboundedReceiver, UnboundedSender, error::SendError},
         oneshot,
     },
@@ -28,9 +32,13 @@ use tower::Service;
 use ulid::Ulid;
 
 use crate::{
+    metrics,
     request::Request,
     response::Response,
     shared::{ReceiverGuard, SharedOneshotMap},
+    stream::{Cancellations, ResponseStream, Streams, respond},
+    subscription::{Subscription, Subscriptions},
+    trace,
 };
 
 /// The core server component for handling connections.
@@ -53,6 +61,9 @@ pub struct Server<S = ()> {
     response_rx:        UnboundedReceiver<DataPack>,
     response_tx:        UnboundedSender<DataPack>,
     shared_oneshot_map: SharedOneshotMap<Ulid, DataPack>,
+    streams:            Streams,
+    subscriptions:      Subscriptions,
+    concurrency:        usize,
 }
 
 /// A client for communicating with a `Server`.
@@ -63,6 +74,8 @@ pub struct Server<S = ()> {
 pub struct Client {
     writer_tx:          UnboundedSender<DataPack>,
     shared_oneshot_map: SharedOneshotMap<Ulid, DataPack>,
+    streams:            Streams,
+    subscriptions:      Subscriptions,
 }
 
 pub struct ClientSink {
@@ -74,6 +87,8 @@ impl Clone for Client {
         Self {
             writer_tx:          self.writer_tx.clone(),
             shared_oneshot_map: self.shared_oneshot_map.clone(),
+            streams:            self.streams.clone(),
+            subscriptions:      self.subscriptions.clone(),
         }
     }
 }
@@ -104,11 +119,27 @@ impl Server<()> {
             response_rx,
             response_tx,
             shared_oneshot_map: SharedOneshotMap::new(),
+            streams: Streams::default(),
+            subscriptions: Subscriptions::new(),
+            concurrency: 1,
         }
     }
 }
 
 impl<S> Server<S> {
+    /// Handles up to `limit` requests at once, instead of one after the
+    /// other.
+    ///
+    /// With a `limit` above 1, a handler waiting on something (e.g. a
+    /// [`Subscription`]) does not block the others, but responses may be sent
+    /// in a different order than the requests arrived. Requests beyond the
+    /// limit wait until a handler finishes.
+    #[must_use]
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = limit.max(1);
+        self
+    }
+
     /// Attaches a `tower::Service` to the server.
     ///
     /// The provided service will be used to process incoming requests.
@@ -136,6 +167,9 @@ impl<S> Server<S> {
             response_rx,
             response_tx,
             shared_oneshot_map,
+            streams,
+            subscriptions,
+            concurrency,
         } = self;
         Server {
             service: svc,
@@ -146,6 +180,9 @@ impl<S> Server<S> {
             response_rx,
             response_tx,
             shared_oneshot_map,
+            streams,
+            subscriptions,
+            concurrency,
         }
     }
 
@@ -158,6 +195,8 @@ impl<S> Server<S> {
         Client {
             writer_tx:          self.writer_tx.clone(),
             shared_oneshot_map: self.shared_oneshot_map.clone(),
+            streams:            self.streams.clone(),
+            subscriptions:      self.subscriptions.clone(),
         }
     }
 }
@@ -174,9 +213,11 @@ where
     /// 1. Receiving responses and completing one-shot channels.
     /// 2. Sending data from the writer channel to the `Writer`.
     /// 3. Reading data from the `Reader` and dispatching it as requests or
-    ///    responses.
+    ///    responses. Requests are also delivered to any matching
+    ///    [`Subscription`]s, and are not routed if one of them intercepts it.
     /// 4. Processing requests with the `tower::Service` and sending back
-    ///    responses.
+    ///    responses, one request at a time unless
+    ///    [`concurrency`](Server::concurrency) allows more.
     ///
     /// # Arguments
     ///
@@ -198,14 +239,34 @@ where
             response_rx,
             response_tx,
             shared_oneshot_map,
+            streams,
+            subscriptions,
+            concurrency,
         } = self;
+        let cancellations = Cancellations::default();
+        let queue_depth = metrics::registry().gauge(
+            "sithra_server_queue_depth",
+            "Requests received but not yet dispatched.",
+            &[],
+        );
         let framed_writer = FramedWrite::new(writer, DataPackCodec::default());
         let framed_reader = FramedRead::new(reader, DataPackCodec::default());
         let mut join_set = JoinSet::new();
         join_set.spawn(async move {
             let mut response_rx = response_rx;
             let shared_oneshot_map = shared_oneshot_map;
+            let error_responses = metrics::registry().counter(
+                "sithra_client_error_responses_total",
+                "Responses to client requests that carried an error.",
+                &[],
+            );
             while let Some(response) = response_rx.recv().await {
+                if matches!(response.result, DataResult::Error(_)) {
+                    error_responses.inc();
+                }
+                let Some(response) = streams.deliver(response) else {
+                    continue;
+                };
                 let key = response.correlation();
                 shared_oneshot_map.complete(&key, response);
             }
@@ -219,41 +280,99 @@ where
             }
             Ok(())
         });
+        let reader_queue_depth = queue_depth.clone();
+        let reader_cancellations = cancellations.clone();
         join_set.spawn(async move {
             let mut framed_reader = framed_reader;
+            let queue_depth = reader_queue_depth;
             let request_tx = request_tx;
             let response_tx = response_tx;
             while let Some(data) = framed_reader.next().await {
                 let data = data?;
+                if data.stream == Some(StreamFlag::Cancel) {
+                    reader_cancellations.cancel(&data.correlation());
+                    continue;
+                }
                 match data.either_request() {
                     Either::Left(response) => {
                         response_tx.send(response)?;
                     }
                     Either::Right(request_datapack) => {
                         let request = Request::new(request_datapack);
+                        if subscriptions.dispatch(&request) {
+                            continue;
+                        }
+                        queue_depth.inc();
                         request_tx.send(request)?;
                     }
                 }
             }
             Ok(())
         });
-        join_set.spawn(async move {
-            let writer_tx = writer_tx;
-            let mut request_rx = request_rx;
-            let mut service = service;
-            while let Some(request) = request_rx.recv().await {
-                let response = service.call(request).await?;
-                let Some(response_datapack) = response.data else {
-                    continue;
-                };
-                writer_tx.send(response_datapack)?;
-            }
-            Ok(())
-        });
+        join_set.spawn(dispatch(
+            service,
+            request_rx,
+            writer_tx,
+            queue_depth,
+            cancellations,
+            concurrency,
+        ));
         join_set
     }
 }
 
+/// Processes requests with `service` and sends back the responses, handling
+/// up to `concurrency` requests at once.
+async fn dispatch<S>(
+    mut service: S,
+    mut request_rx: UnboundedReceiver<Request>,
+    writer_tx: UnboundedSender<DataPack>,
+    queue_depth: metrics::Gauge,
+    cancellations: Cancellations,
+    concurrency: usize,
+) -> Result<(), ServerError>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let in_flight =
+        metrics::registry().gauge("sithra_server_in_flight", "Requests being handled.", &[]);
+    let permits = Arc::new(Semaphore::new(concurrency));
+    while let Some(request) = request_rx.recv().await {
+        queue_depth.dec();
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            unreachable!("the semaphore is never closed");
+        };
+        let guard = InFlight::new(&in_flight);
+        let correlation = request.correlation();
+        let mut future = Box::pin(service.call(request));
+        // Poll once in place so services observe requests in arrival order,
+        // then let slow handlers finish in the background. The next request
+        // waits for a permit, so with a concurrency of 1 it waits for this
+        // one to be answered.
+        let response = match poll!(&mut future) {
+            Poll::Ready(response) => {
+                drop(guard);
+                drop(permit);
+                response?
+            }
+            Poll::Pending => {
+                let writer_tx = writer_tx.clone();
+                let cancellations = cancellations.clone();
+                tokio::spawn(async move {
+                    let _guard = guard;
+                    let Ok(response) = future.await;
+                    respond(response, correlation, &writer_tx, &cancellations).ok();
+                    drop(permit);
+                });
+                continue;
+            }
+        };
+        respond(response, correlation, &writer_tx, &cancellations)?;
+    }
+    Ok(())
+}
+
 impl Client {
     /// Sends a request to the server and returns a future for the response.
     ///
@@ -281,15 +400,44 @@ impl Client {
         &self,
         datapack: impl Into<RequestDataPack>,
     ) -> Result<ReceiverGuard<Ulid, DataPack>, PostError> {
-        let datapack = datapack.into();
+        let datapack = with_trace(datapack.into());
         let key = datapack.correlation();
         let guard = self.shared_oneshot_map.register(key).expect("Ulid Conflict");
-        self.writer_tx
-            .send(datapack.into())
-            .map_err(|err| PostError::ChannelClosed(err.0))?;
+        let datapack = DataPack {
+            awaits_reply: true,
+            ..datapack.into()
+        };
+        self.writer_tx.send(datapack).map_err(|err| channel_closed(err.0))?;
         Ok(guard)
     }
 
+    /// Sends a request to the server and returns a stream of its responses.
+    ///
+    /// The stream yields every response pack of a handler that returns
+    /// [`Streaming`](crate::response::Streaming), and the single response of
+    /// any other handler. Dropping it before the end cancels the handler's
+    /// stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Err(DataPack)` if the connection to the server is closed
+    /// before the request can be sent.
+    #[allow(clippy::result_large_err)]
+    pub fn post_stream(
+        &self,
+        datapack: impl Into<RequestDataPack>,
+    ) -> Result<ResponseStream, PostError> {
+        let datapack = with_trace(datapack.into());
+        let key = datapack.correlation();
+        let stream = self.streams.register(key, self.writer_tx.clone());
+        let datapack = DataPack {
+            awaits_reply: true,
+            ..datapack.into()
+        };
+        self.writer_tx.send(datapack).map_err(|err| channel_closed(err.0))?;
+        Ok(stream)
+    }
+
     /// Sends a request to the server without waiting for a response.
     ///
     /// # Arguments
@@ -309,13 +457,68 @@ impl Client {
     /// correlation ID. This is extremely unlikely to happen in practice.
     #[allow(clippy::result_large_err)]
     pub fn send(&self, datapack: impl Into<RequestDataPack>) -> Result<(), PostError> {
-        let datapack = datapack.into();
-        self.writer_tx
-            .send(datapack.into())
-            .map_err(|err| PostError::ChannelClosed(err.0))?;
+        let datapack = with_trace(datapack.into());
+        self.writer_tx.send(datapack.into()).map_err(|err| channel_closed(err.0))?;
         Ok(())
     }
 
+    /// Subscribes to incoming requests at runtime.
+    ///
+    /// Every request whose path matches `path_pattern` and for which `filter`
+    /// returns `true` is yielded by the returned [`Subscription`], in
+    /// addition to being handled by the router. The subscription is removed
+    /// when the stream is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `path_pattern` - A route pattern, using the same syntax as
+    ///   [`Router::route`](crate::routing::router::Router::route).
+    /// * `filter` - A predicate applied to every request matching the pattern.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path_pattern` is not a valid route pattern.
+    pub fn subscribe<F>(&self, path_pattern: &str, filter: F) -> Result<Subscription, InsertError>
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        self.subscriptions.subscribe(path_pattern, filter)
+    }
+
+    /// Intercepts incoming requests at runtime.
+    ///
+    /// Like [`Client::subscribe`], but matching requests are consumed and
+    /// not handled by the router.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path_pattern` is not a valid route pattern.
+    pub fn intercept<F>(&self, path_pattern: &str, filter: F) -> Result<Subscription, InsertError>
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        self.subscriptions.intercept(path_pattern, filter)
+    }
+
+    /// Intercepts the next incoming request matching `path_pattern` and
+    /// `filter`.
+    ///
+    /// The returned [`Subscription`] yields at most one request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path_pattern` is not a valid route pattern.
+    pub fn intercept_once<F>(
+        &self,
+        path_pattern: &str,
+        filter: F,
+    ) -> Result<Subscription, InsertError>
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        self.subscriptions.intercept_once(path_pattern, filter)
+    }
+
     #[must_use]
     pub fn sink(&self) -> ClientSink {
         ClientSink {
@@ -324,6 +527,42 @@ impl Client {
     }
 }
 
+/// Counts a request that could not be sent.
+fn channel_closed(datapack: DataPack) -> PostError {
+    metrics::registry()
+        .counter(
+            "sithra_client_post_errors_total",
+            "Requests that could not be sent.",
+            &[],
+        )
+        .inc();
+    PostError::ChannelClosed(datapack)
+}
+
+/// Decrements the in-flight gauge when a request is done.
+struct InFlight(metrics::Gauge);
+
+impl InFlight {
+    fn new(gauge: &metrics::Gauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Stamps `datapack` with the current trace context, unless it has one.
+fn with_trace(mut datapack: RequestDataPack) -> RequestDataPack {
+    if datapack.trace.is_none() {
+        datapack.trace = trace::current();
+    }
+    datapack
+}
+
 impl ClientSink {
     /// Sends a request to the server without waiting for a response.
     ///
@@ -345,12 +584,13 @@ impl ClientSink {
     #[allow(clippy::result_large_err)]
     pub fn send(&self, datapack: impl Into<DataPack>) -> Result<(), PostError> {
         let datapack = datapack.into();
-        self.writer_tx.send(datapack).map_err(|err| PostError::ChannelClosed(err.0))?;
+        self.writer_tx.send(datapack).map_err(|err| channel_closed(err.0))?;
         Ok(())
     }
 }
 
 #[derive(Debug, Error)]
+#[allow(clippy::large_enum_variant)]
 pub enum PostError {
     #[error("Channel closed")]
     ChannelClosed(DataPack),
@@ -0,0 +1,362 @@
+//! Streamed responses.
+//!
+//! A handler streams its response by returning a
+//! [`Streaming`](crate::response::Streaming), and a caller consumes it with
+//! [`Client::post_stream`](crate::server::Client::post_stream). Each item is
+//! sent as soon as it is produced, as a response pack flagged
+//! [`StreamFlag::Item`], and an empty pack flagged [`StreamFlag::End`] follows
+//! the last one. Dropping a [`ResponseStream`] before
+//! the end sends a [`StreamFlag::Cancel`] pack, and the server drops the
+//! handler's stream.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+};
+
+use ahash::RandomState;
+use futures_util::{Stream, StreamExt, stream};
+use parking_lot::Mutex;
+use sithra_transport::datapack::{DataPack, StreamFlag};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+use ulid::Ulid;
+
+use crate::{response::Response, server::ServerError};
+
+type StreamMapInner = Mutex<HashMap<Ulid, UnboundedSender<DataPack>, RandomState>>;
+type CancellationMapInner = Mutex<HashMap<Ulid, CancellationToken, RandomState>>;
+
+/// The streams a client is waiting on, by correlation.
+#[derive(Clone, Default)]
+pub(crate) struct Streams {
+    inner: Arc<StreamMapInner>,
+}
+
+impl Streams {
+    pub(crate) fn register(
+        &self,
+        key: Ulid,
+        writer_tx: UnboundedSender<DataPack>,
+    ) -> ResponseStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.lock().insert(key, tx);
+        ResponseStream {
+            key,
+            rx,
+            streams: Arc::downgrade(&self.inner),
+            writer_tx,
+            done: false,
+        }
+    }
+
+    /// Delivers `data` to the stream waiting on it.
+    ///
+    /// Returns `data` back if no stream is waiting on its correlation.
+    pub(crate) fn deliver(&self, data: DataPack) -> Option<DataPack> {
+        let key = data.correlation();
+        let mut streams = self.inner.lock();
+        let tx = if data.stream == Some(StreamFlag::Item) {
+            streams.get(&key).cloned()
+        } else {
+            streams.remove(&key)
+        };
+        drop(streams);
+        let Some(tx) = tx else {
+            return Some(data);
+        };
+        tx.send(data).ok();
+        None
+    }
+}
+
+/// The response packs of a streamed request.
+///
+/// Yields the packs flagged [`StreamFlag::Item`] until the one flagged
+/// [`StreamFlag::End`]. A handler that does not stream yields a single pack.
+/// Dropping the stream early cancels it.
+pub struct ResponseStream {
+    key:       Ulid,
+    rx:        UnboundedReceiver<DataPack>,
+    streams:   Weak<StreamMapInner>,
+    writer_tx: UnboundedSender<DataPack>,
+    done:      bool,
+}
+
+impl ResponseStream {
+    /// Returns the correlation of the request.
+    #[must_use]
+    pub const fn correlation(&self) -> Ulid {
+        self.key
+    }
+}
+
+impl Stream for ResponseStream {
+    type Item = DataPack;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let data = std::task::ready!(self.rx.poll_recv(cx));
+        let flag = data.as_ref().and_then(|data| data.stream);
+        if flag != Some(StreamFlag::Item) {
+            self.done = true;
+        }
+        if flag == Some(StreamFlag::End) {
+            return Poll::Ready(None);
+        }
+        Poll::Ready(data)
+    }
+}
+
+impl Drop for ResponseStream {
+    fn drop(&mut self) {
+        if let Some(streams) = self.streams.upgrade() {
+            streams.lock().remove(&self.key);
+        }
+        if !self.done {
+            let cancel = DataPack::builder().correlate(self.key).stream(StreamFlag::Cancel).build();
+            self.writer_tx.send(cancel).ok();
+        }
+    }
+}
+
+/// The streams a server is producing, by correlation.
+#[derive(Clone, Default)]
+pub(crate) struct Cancellations {
+    inner: Arc<CancellationMapInner>,
+}
+
+impl Cancellations {
+    fn register(&self, key: Ulid) -> CancelGuard {
+        let token = CancellationToken::new();
+        self.inner.lock().insert(key, token.clone());
+        CancelGuard {
+            key,
+            token,
+            map: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Cancels the stream with correlation `key`, if it is still running.
+    pub(crate) fn cancel(&self, key: &Ulid) {
+        let token = self.inner.lock().remove(key);
+        if let Some(token) = token {
+            token.cancel();
+        }
+    }
+}
+
+struct CancelGuard {
+    key:   Ulid,
+    token: CancellationToken,
+    map:   Weak<CancellationMapInner>,
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(map) = self.map.upgrade() {
+            map.lock().remove(&self.key);
+        }
+    }
+}
+
+/// Sends the packs of `response`, forwarding its stream in the background.
+pub(crate) fn respond(
+    response: Response,
+    correlation: Ulid,
+    writer_tx: &UnboundedSender<DataPack>,
+    cancellations: &Cancellations,
+) -> Result<(), ServerError> {
+    if response.stream.is_none() {
+        for data in response {
+            writer_tx.send(data)?;
+        }
+        return Ok(());
+    }
+    let guard = cancellations.register(correlation);
+    tokio::spawn(forward(response, correlation, writer_tx.clone(), guard));
+    Ok(())
+}
+
+/// Sends the packs of `response` and of its stream as they are produced until
+/// it ends or is cancelled, then an empty pack flagged as the end.
+async fn forward(
+    mut response: Response,
+    correlation: Ulid,
+    writer_tx: UnboundedSender<DataPack>,
+    guard: CancelGuard,
+) {
+    let rest = response.stream.take().unwrap_or_else(|| stream::empty().boxed());
+    let mut items = stream::iter([response]).chain(rest);
+    loop {
+        let item = tokio::select! {
+            () = guard.token.cancelled() => return,
+            item = items.next() => item,
+        };
+        let Some(item) = item else {
+            break;
+        };
+        for mut data in item {
+            if !data.is_request() {
+                data.stream = Some(StreamFlag::Item);
+            }
+            if writer_tx.send(data).is_err() {
+                return;
+            }
+        }
+    }
+    let end = DataPack::builder().correlate(correlation).stream(StreamFlag::End).build();
+    writer_tx.send(end).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures_util::{StreamExt, stream};
+    use parking_lot::Mutex;
+    use sithra_transport::datapack::{DataPack, RequestDataPack, StreamFlag};
+    use tokio::sync::{mpsc, oneshot};
+    use ulid::Ulid;
+
+    use crate::{
+        extract::payload::Payload, on, response::Streaming, routing::router::Router,
+        testing::TestHost,
+    };
+
+    /// Signals when dropped.
+    struct OnDrop(Option<oneshot::Sender<()>>);
+
+    impl Drop for OnDrop {
+        fn drop(&mut self) {
+            if let Some(tx) = self.0.take() {
+                tx.send(()).ok();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn items_and_end() {
+        let router = Router::new()
+            .route(
+                "/count",
+                on(async || Streaming(stream::iter(1..=3).map(Payload))),
+            )
+            .route("/once", on(async || Payload(0)));
+        let mut host = TestHost::new(router);
+
+        host.send(RequestDataPack::default().path("/count")).unwrap();
+        let mut packs = Vec::new();
+        for _ in 0..4 {
+            packs.push(host.next().await.unwrap());
+        }
+        let flags = packs.iter().map(|data| data.stream).collect::<Vec<_>>();
+        let values =
+            packs[..3].iter().map(|data| data.payload::<i32>().unwrap()).collect::<Vec<_>>();
+        assert_eq!(values, [1, 2, 3]);
+        assert_eq!(
+            flags,
+            [
+                Some(StreamFlag::Item),
+                Some(StreamFlag::Item),
+                Some(StreamFlag::Item),
+                Some(StreamFlag::End)
+            ]
+        );
+
+        let once = host.request(RequestDataPack::default().path("/once")).await.unwrap();
+        assert_eq!(once.stream, None);
+    }
+
+    fn item(correlation: Ulid, value: i32, flag: StreamFlag) -> DataPack {
+        DataPack::builder().correlate(correlation).payload(value).stream(flag).build()
+    }
+
+    #[tokio::test]
+    async fn client() {
+        let mut host = TestHost::new(Router::new());
+        let mut responses =
+            host.client().post_stream(RequestDataPack::default().path("/count")).unwrap();
+        let correlation = host.expect("/count").await.unwrap().correlation();
+        host.send_raw(item(correlation, 1, StreamFlag::Item)).unwrap();
+        host.send_raw(item(correlation, 2, StreamFlag::Item)).unwrap();
+        let end = DataPack::builder().correlate(correlation).stream(StreamFlag::End).build();
+        host.send_raw(end).unwrap();
+        let values = (&mut responses)
+            .map(|data| data.payload::<i32>().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(values, [1, 2]);
+
+        let mut responses =
+            host.client().post_stream(RequestDataPack::default().path("/count")).unwrap();
+        let correlation = host.expect("/count").await.unwrap().correlation();
+        host.send_raw(item(correlation, 1, StreamFlag::Item)).unwrap();
+        assert_eq!(responses.next().await.unwrap().payload::<i32>().unwrap(), 1);
+        drop(responses);
+        let cancel = host.next().await.unwrap();
+        assert_eq!(
+            (cancel.correlation(), cancel.stream),
+            (correlation, Some(StreamFlag::Cancel))
+        );
+    }
+
+    #[tokio::test]
+    async fn immediate() {
+        let (tx, rx) = mpsc::unbounded_channel::<i32>();
+        let rx = Arc::new(Mutex::new(Some(rx)));
+        let router = Router::new().route(
+            "/live",
+            on(async move || {
+                let rx = rx.lock().take().unwrap();
+                Streaming(stream::unfold(rx, async |mut rx| {
+                    Some((Payload(rx.recv().await?), rx))
+                }))
+            }),
+        );
+        let mut host = TestHost::new(router);
+
+        host.send(RequestDataPack::default().path("/live")).unwrap();
+        tx.send(1).unwrap();
+        let first = host.next().await.unwrap();
+        assert_eq!(
+            (first.payload::<i32>().unwrap(), first.stream),
+            (1, Some(StreamFlag::Item))
+        );
+        tx.send(2).unwrap();
+        assert_eq!(host.next().await.unwrap().payload::<i32>().unwrap(), 2);
+        drop(tx);
+        assert_eq!(host.next().await.unwrap().stream, Some(StreamFlag::End));
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let router = Router::new().route(
+            "/forever",
+            on(async move || {
+                let guard = OnDrop(tx.lock().take());
+                let ticks = stream::unfold(0, async |n| {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    Some((Payload(n), n + 1))
+                });
+                Streaming(ticks.map(move |item| {
+                    let _ = &guard;
+                    item
+                }))
+            }),
+        );
+        let mut host = TestHost::new(router);
+
+        let correlation = host.send(RequestDataPack::default().path("/forever")).unwrap();
+        assert_eq!(host.next().await.unwrap().payload::<i32>().unwrap(), 0);
+        let cancel = DataPack::builder().correlate(correlation).stream(StreamFlag::Cancel).build();
+        host.send_raw(cancel).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), rx).await.unwrap().unwrap();
+    }
+}
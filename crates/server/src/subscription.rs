@@ -0,0 +1,260 @@
+//! Runtime subscriptions to incoming requests.
+//!
+//! Routes registered on a [`Router`](crate::routing::router::Router) are fixed
+//! once the server starts. A [`Subscription`] lets a plugin listen for a path
+//! while it is running, e.g. to wait for the next `/event/message.created` in
+//! a particular channel. Subscriptions are fed by the server's dispatch loop
+//! alongside the router and are removed automatically when dropped.
+//!
+//! An *intercepting* subscription additionally consumes the requests it
+//! matches, so they are not handled by the router.
+
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use matchit::InsertError;
+use parking_lot::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::request::Request;
+
+type Filter = Box<dyn Fn(&Request) -> bool + Send + Sync>;
+type SubscriberMap = Mutex<BTreeMap<u64, Arc<Subscriber>>>;
+
+struct Subscriber {
+    matcher: matchit::Router<()>,
+    filter:  Filter,
+    tx:      UnboundedSender<Request>,
+    mode:    Mode,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Receives a copy of every matching request.
+    Listen,
+    /// Consumes every matching request.
+    Intercept,
+    /// Consumes the first matching request, then unsubscribes.
+    InterceptOnce,
+}
+
+impl Subscriber {
+    fn matches(&self, request: &Request) -> bool {
+        self.matcher.at(&request.data.path).is_ok() && (self.filter)(request)
+    }
+}
+
+/// A shared registry of runtime subscriptions.
+///
+/// Cloning a `Subscriptions` yields a handle to the same registry.
+#[derive(Clone, Default)]
+pub struct Subscriptions {
+    next_id: Arc<AtomicU64>,
+    inner:   Arc<SubscriberMap>,
+}
+
+impl Subscriptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new subscription.
+    ///
+    /// `pattern` uses the same syntax as
+    /// [`Router::route`](crate::routing::router::Router::route). Requests
+    /// whose path matches `pattern` and for which `filter` returns `true`
+    /// are delivered to the returned [`Subscription`].
+    ///
+    /// # Errors
+    /// Returns an error if `pattern` is not a valid route pattern.
+    pub fn subscribe<F>(&self, pattern: &str, filter: F) -> Result<Subscription, InsertError>
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        self.register(pattern, filter, Mode::Listen)
+    }
+
+    /// Registers a new intercepting subscription.
+    ///
+    /// Like [`Subscriptions::subscribe`], but matching requests are consumed:
+    /// they are delivered to this subscription only and are not passed on to
+    /// the router. If several intercepting subscriptions match, the oldest
+    /// one wins.
+    ///
+    /// # Errors
+    /// Returns an error if `pattern` is not a valid route pattern.
+    pub fn intercept<F>(&self, pattern: &str, filter: F) -> Result<Subscription, InsertError>
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        self.register(pattern, filter, Mode::Intercept)
+    }
+
+    /// Registers an intercepting subscription that ends after consuming a
+    /// single request.
+    ///
+    /// # Errors
+    /// Returns an error if `pattern` is not a valid route pattern.
+    pub fn intercept_once<F>(&self, pattern: &str, filter: F) -> Result<Subscription, InsertError>
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        self.register(pattern, filter, Mode::InterceptOnce)
+    }
+
+    fn register<F>(&self, pattern: &str, filter: F, mode: Mode) -> Result<Subscription, InsertError>
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        let mut matcher = matchit::Router::new();
+        matcher.insert(pattern, ())?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let subscriber = Subscriber {
+            matcher,
+            filter: Box::new(filter),
+            tx,
+            mode,
+        };
+        self.inner.lock().insert(id, Arc::new(subscriber));
+        Ok(Subscription {
+            id,
+            rx,
+            map: Arc::downgrade(&self.inner),
+        })
+    }
+
+    /// Delivers `request` to every subscription that matches it.
+    ///
+    /// Returns `true` if an intercepting subscription consumed the request.
+    pub(crate) fn dispatch(&self, request: &Request) -> bool {
+        // The filters run without the lock held, so they may subscribe or drop
+        // subscriptions themselves.
+        let subscribers = {
+            let mut map = self.inner.lock();
+            map.retain(|_, subscriber| !subscriber.tx.is_closed());
+            map.iter().map(|(id, subscriber)| (*id, subscriber.clone())).collect::<Vec<_>>()
+        };
+        let mut consumed = false;
+        for (id, subscriber) in subscribers {
+            if (subscriber.mode != Mode::Listen && consumed) || !subscriber.matches(request) {
+                continue;
+            }
+            match subscriber.mode {
+                Mode::Listen => {
+                    subscriber.tx.send(request.clone()).ok();
+                }
+                Mode::Intercept => consumed |= subscriber.tx.send(request.clone()).is_ok(),
+                // Only the dispatch that unregisters it delivers to it.
+                Mode::InterceptOnce => {
+                    if self.inner.lock().remove(&id).is_some() {
+                        consumed |= subscriber.tx.send(request.clone()).is_ok();
+                    }
+                }
+            }
+        }
+        consumed
+    }
+}
+
+/// A stream of requests matching a runtime subscription.
+///
+/// Created with [`Client::subscribe`](crate::server::Client::subscribe). The
+/// subscription is unregistered when this value is dropped.
+pub struct Subscription {
+    id:  u64,
+    rx:  UnboundedReceiver<Request>,
+    map: Weak<SubscriberMap>,
+}
+
+impl Stream for Subscription {
+    type Item = Request;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().rx.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(map) = self.map.upgrade() {
+            map.lock().remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use sithra_transport::datapack::RequestDataPack;
+
+    use super::Subscriptions;
+    use crate::request::Request;
+
+    fn request(path: &str, bot_id: &str) -> Request {
+        Request::new(RequestDataPack::default().path(path).bot_id(bot_id))
+    }
+
+    #[tokio::test]
+    async fn subscribe_and_unsubscribe() {
+        let subscriptions = Subscriptions::new();
+        let mut subscription = subscriptions
+            .subscribe("/event/{name}", |req| req.bot_id_ref() == Some("a"))
+            .unwrap();
+
+        subscriptions.dispatch(&request("/event/message.created", "b"));
+        subscriptions.dispatch(&request("/command/message.create", "a"));
+        subscriptions.dispatch(&request("/event/message.created", "a"));
+
+        let received = subscription.next().await.unwrap();
+        assert_eq!(received.data.path, "/event/message.created");
+        assert_eq!(received.bot_id_ref(), Some("a"));
+
+        drop(subscription);
+        assert!(subscriptions.inner.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn intercept_once() {
+        let subscriptions = Subscriptions::new();
+        let mut listener = subscriptions.subscribe("/event/message.created", |_| true).unwrap();
+        let mut first = subscriptions.intercept_once("/event/message.created", |_| true).unwrap();
+        let mut second = subscriptions.intercept_once("/event/message.created", |_| true).unwrap();
+
+        assert!(subscriptions.dispatch(&request("/event/message.created", "a")));
+        assert!(subscriptions.dispatch(&request("/event/message.created", "b")));
+        assert!(!subscriptions.dispatch(&request("/event/message.created", "c")));
+
+        assert_eq!(first.next().await.unwrap().bot_id_ref(), Some("a"));
+        assert!(first.next().await.is_none());
+        assert_eq!(second.next().await.unwrap().bot_id_ref(), Some("b"));
+        assert!(second.next().await.is_none());
+        for bot_id in ["a", "b", "c"] {
+            assert_eq!(listener.next().await.unwrap().bot_id_ref(), Some(bot_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn reentrant_filter() {
+        let subscriptions = Subscriptions::new();
+        let registry = subscriptions.clone();
+        let mut subscription = subscriptions
+            .subscribe("/event/{name}", move |_| {
+                drop(registry.subscribe("/command/{name}", |_| true).unwrap());
+                true
+            })
+            .unwrap();
+
+        subscriptions.dispatch(&request("/event/message.created", "a"));
+        assert_eq!(subscription.next().await.unwrap().bot_id_ref(), Some("a"));
+    }
+}
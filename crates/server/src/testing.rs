@@ -0,0 +1,346 @@
+//! An in-memory harness for testing routers.
+//!
+//! [`TestHost`] serves a [`Router`] over an in-memory [`Peer`] and plays the
+//! part of the host: a test injects requests as if they came from an adapter,
+//! stubs the responses to the requests the plugin sends, and asserts on
+//! everything else the plugin emits.
+//!
+//! ```
+//! # use sithra_server::{extract::payload::Payload, on, routing::router::Router,
+//! #     testing::TestHost, transport::datapack::RequestDataPack};
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let router = Router::new().route(
+//!     "/echo",
+//!     on(async |Payload(text): Payload<String>| Payload(text)),
+//! );
+//! let mut host = TestHost::new(router);
+//! let response = host
+//!     .request(RequestDataPack::default().path("/echo").payload("hi"))
+//!     .await
+//!     .unwrap();
+//! assert_eq!(response.payload::<String>().unwrap(), "hi");
+//! # }
+//! ```
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use sithra_transport::{
+    datapack::{DataPack, DataPackCodec, DataResult, RequestDataPack},
+    peer::Peer,
+};
+use thiserror::Error;
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinSet,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use ulid::Ulid;
+
+use crate::{
+    routing::router::Router,
+    server::{Client, Server, ServerError},
+};
+
+/// How long [`TestHost`] waits for a pack by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+type Stub = Box<dyn Fn(&RequestDataPack) -> DataResult + Send + Sync>;
+type Stubs = Mutex<Vec<(matchit::Router<()>, Stub)>>;
+
+#[derive(Debug, Error)]
+pub enum TestError {
+    #[error("Timed out waiting for a data pack")]
+    Timeout,
+    #[error("Connection closed")]
+    Closed,
+}
+
+/// A fake host connected to a [`Router`] in memory.
+///
+/// Packs emitted by the router that are neither answered by a stub nor
+/// consumed by [`TestHost::request`] are kept, in order, for
+/// [`TestHost::next`] and [`TestHost::expect`].
+pub struct TestHost {
+    client:   Client,
+    tx:       UnboundedSender<DataPack>,
+    rx:       UnboundedReceiver<DataPack>,
+    pending:  VecDeque<DataPack>,
+    stubs:    Arc<Stubs>,
+    timeout:  Duration,
+    _servers: JoinSet<Result<(), ServerError>>,
+    _tasks:   JoinSet<()>,
+}
+
+impl TestHost {
+    /// Serves `router` with a new [`Server`].
+    #[must_use]
+    pub fn new(router: Router) -> Self {
+        Self::with_server(Server::new(), router)
+    }
+
+    /// Serves `router` with `server`.
+    ///
+    /// Use this when the router's state needs the server's
+    /// [`Client`](Server::client).
+    #[must_use]
+    pub fn with_server(server: Server, router: Router) -> Self {
+        let client = server.client();
+        let (plugin, host) = Peer::duplex(BUFFER_SIZE);
+        let (writer, reader) = plugin.split();
+        let servers = server.service(router).serve(writer, reader);
+
+        let (writer, reader) = host.split();
+        let mut framed_writer = FramedWrite::new(writer, DataPackCodec::new());
+        let mut framed_reader = FramedRead::new(reader, DataPackCodec::new());
+        let (tx, mut writer_rx) = mpsc::unbounded_channel::<DataPack>();
+        let (received_tx, rx) = mpsc::unbounded_channel();
+        let stubs: Arc<Stubs> = Arc::default();
+
+        let mut tasks = JoinSet::new();
+        tasks.spawn(async move {
+            while let Some(data) = writer_rx.recv().await {
+                if framed_writer.send(data).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let stub_tx = tx.clone();
+        let task_stubs = stubs.clone();
+        tasks.spawn(async move {
+            while let Some(Ok(data)) = framed_reader.next().await {
+                let response = run_stub(&task_stubs, &data).map(|result| {
+                    DataPack::builder().correlate(data.correlation()).result(result).build()
+                });
+                let sent = match response {
+                    Some(response) => stub_tx.send(response),
+                    None => received_tx.send(data),
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            client,
+            tx,
+            rx,
+            pending: VecDeque::new(),
+            stubs,
+            timeout: DEFAULT_TIMEOUT,
+            _servers: servers,
+            _tasks: tasks,
+        }
+    }
+
+    /// Sets how long to wait for a pack before failing.
+    #[must_use]
+    pub const fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns a client of the server under test.
+    #[must_use]
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// Answers requests sent to `path` with the result of `stub`.
+    ///
+    /// `path` uses the same syntax as
+    /// [`Router::route`](crate::routing::router::Router::route). Stubs are
+    /// tried in the order they were added.
+    ///
+    /// # Panics
+    /// Panics if `path` is not a valid route pattern.
+    pub fn stub<F, R>(&self, path: &str, stub: F)
+    where
+        F: Fn(&RequestDataPack) -> R + Send + Sync + 'static,
+        R: Into<DataResult>,
+    {
+        let mut matcher = matchit::Router::new();
+        matcher.insert(path, ()).expect("invalid stub path");
+        self.stubs.lock().push((matcher, Box::new(move |req| stub(req).into())));
+    }
+
+    /// Sends a request to the router without waiting for the response.
+    ///
+    /// Returns the correlation of the request.
+    ///
+    /// # Errors
+    /// Returns [`TestError::Closed`] if the server has stopped.
+    pub fn send(&self, request: impl Into<RequestDataPack>) -> Result<Ulid, TestError> {
+        let request = request.into();
+        let correlation = request.correlation();
+        self.tx.send(request.into()).map_err(|_| TestError::Closed)?;
+        Ok(correlation)
+    }
+
+    /// Sends `data` to the router as is, such as a stream item answering a
+    /// request the router sent, or a
+    /// [`Cancel`](sithra_transport::datapack::StreamFlag::Cancel).
+    ///
+    /// # Errors
+    /// Returns [`TestError::Closed`] if the server has stopped.
+    pub fn send_raw(&self, data: DataPack) -> Result<(), TestError> {
+        self.tx.send(data).map_err(|_| TestError::Closed)
+    }
+
+    /// Sends a request to the router and waits for its response.
+    ///
+    /// # Errors
+    /// Returns [`TestError::Timeout`] if the router does not respond in time,
+    /// and [`TestError::Closed`] if the server has stopped.
+    pub async fn request(
+        &mut self,
+        request: impl Into<RequestDataPack>,
+    ) -> Result<DataPack, TestError> {
+        let correlation = self.send(request)?;
+        self.find(|data| data.path.is_none() && data.correlation() == correlation).await
+    }
+
+    /// Waits for the next pack emitted by the router.
+    ///
+    /// # Errors
+    /// Returns [`TestError::Timeout`] if no pack arrives in time, and
+    /// [`TestError::Closed`] if the server has stopped.
+    pub async fn next(&mut self) -> Result<DataPack, TestError> {
+        self.find(|_| true).await
+    }
+
+    /// Waits for the next request the router sends to `path`.
+    ///
+    /// Packs emitted before it are kept for later calls.
+    ///
+    /// # Errors
+    /// Returns [`TestError::Timeout`] if no such request arrives in time, and
+    /// [`TestError::Closed`] if the server has stopped.
+    pub async fn expect(&mut self, path: &str) -> Result<RequestDataPack, TestError> {
+        self.find(|data| data.path.as_deref() == Some(path))
+            .await
+            .map(DataPack::into_request)
+    }
+
+    /// Asserts that the router emits nothing within the timeout.
+    ///
+    /// # Panics
+    /// Panics if a pack is emitted.
+    pub async fn expect_none(&mut self) {
+        if let Ok(data) = self.next().await {
+            panic!("expected no data pack, got {data:?}");
+        }
+    }
+
+    async fn find(&mut self, mut f: impl FnMut(&DataPack) -> bool) -> Result<DataPack, TestError> {
+        if let Some(index) = self.pending.iter().position(&mut f) {
+            return Ok(self.pending.remove(index).expect("unreachable"));
+        }
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        loop {
+            let data = tokio::time::timeout_at(deadline, self.rx.recv())
+                .await
+                .map_err(|_| TestError::Timeout)?
+                .ok_or(TestError::Closed)?;
+            if f(&data) {
+                return Ok(data);
+            }
+            self.pending.push_back(data);
+        }
+    }
+}
+
+/// Answers `data` with the first matching stub, if it is a request.
+fn run_stub(stubs: &Stubs, data: &DataPack) -> Option<DataResult> {
+    let path = data.path.as_deref()?;
+    let request = data.clone().into_request();
+    stubs
+        .lock()
+        .iter()
+        .find(|(matcher, _)| matcher.at(path).is_ok())
+        .map(|(_, stub)| stub(&request))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sithra_transport::datapack::RequestDataPack;
+
+    use super::TestHost;
+    use crate::{
+        extract::{context::Clientful, payload::Payload, state::State},
+        on,
+        routing::router::Router,
+        server::{Client, Server},
+    };
+
+    #[derive(Clone)]
+    struct AppState {
+        client: Client,
+    }
+
+    impl Clientful for AppState {
+        fn client(&self) -> &Client {
+            &self.client
+        }
+    }
+
+    async fn greet(State(state): State<AppState>, Payload(name): Payload<String>) {
+        let request = RequestDataPack::default().path("/user/nick").payload(&name);
+        let nick = state.client.post(request).unwrap().await.unwrap();
+        let nick = nick.payload::<String>().unwrap();
+        let reply = RequestDataPack::default().path("/reply").payload(format!("hello, {nick}"));
+        state.client.send(reply).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stub_and_expect() {
+        let server = Server::new();
+        let state = AppState {
+            client: server.client(),
+        };
+        let router = Router::new().route("/greet", on(greet)).with_state(state);
+        let mut host = TestHost::with_server(server, router).timeout(Duration::from_millis(200));
+        host.stub("/user/nick", |req| {
+            let name = req.payload.as_str().unwrap_or_default().to_owned();
+            Ok::<_, String>(format!("{name}!"))
+        });
+
+        host.send(RequestDataPack::default().path("/greet").payload("ann")).unwrap();
+        let reply = host.expect("/reply").await.unwrap();
+        assert_eq!(reply.payload, "hello, ann!".into());
+        host.expect_none().await;
+    }
+
+    async fn order(server: Server) -> Vec<String> {
+        let router = Router::new()
+            .route(
+                "/slow",
+                on(async || {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Payload("slow")
+                }),
+            )
+            .route("/fast", on(async || Payload("fast")));
+        let mut host = TestHost::with_server(server, router);
+        host.send(RequestDataPack::default().path("/slow")).unwrap();
+        host.send(RequestDataPack::default().path("/fast")).unwrap();
+        let mut order = Vec::new();
+        for _ in 0..2 {
+            order.push(host.next().await.unwrap().payload::<String>().unwrap());
+        }
+        order
+    }
+
+    #[tokio::test]
+    async fn concurrency() {
+        assert_eq!(order(Server::new()).await, ["slow", "fast"]);
+        assert_eq!(order(Server::new().concurrency(2)).await, ["fast", "slow"]);
+    }
+}
@@ -0,0 +1,128 @@
+//! Request tracing.
+//!
+//! The [`Trace`] layer opens a [`tracing`] span for every request and makes
+//! its [`TraceContext`] current while the handler runs. Requests sent with
+//! [`Client`](crate::server::Client) during that time, and the response
+//! itself, carry the context, so the peer receiving them continues the same
+//! trace.
+
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+use futures_util::{FutureExt, future::BoxFuture};
+use sithra_transport::trace::TraceContext;
+use tower::{Layer, Service};
+use tracing::{Instrument, field::Empty};
+
+use crate::{request::Request, response::Response, routing::route::Route};
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Returns the trace context of the request being handled, if any.
+#[must_use]
+pub fn current() -> Option<TraceContext> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+/// Runs `future` with `context` as the current trace context.
+pub async fn scope<F: Future>(context: TraceContext, future: F) -> F::Output {
+    CURRENT.scope(context, future).await
+}
+
+/// A layer that traces every request.
+///
+/// The span is named `request` and records the path, correlation, bot ID and
+/// channel of the request, as well as the `trace_id`, `span_id` and
+/// `parent_span_id` of its [`TraceContext`]. A request without a context
+/// starts a new trace.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Trace;
+
+impl Layer<Route> for Trace {
+    type Service = TraceLayer;
+
+    fn layer(&self, inner: Route) -> Self::Service {
+        TraceLayer { svc: inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceLayer {
+    svc: Route,
+}
+
+impl Service<Request> for TraceLayer {
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+    type Response = Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.svc.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let parent = req.data.trace.as_ref();
+        let context = parent.map_or_else(TraceContext::new_root, TraceContext::child);
+        let span = tracing::debug_span!(
+            "request",
+            path = %req.data.path,
+            correlation = %req.correlation(),
+            bot_id = req.bot_id_ref(),
+            channel = req.data.channel.as_ref().map(|channel| channel.id.as_str()),
+            trace_id = %context.trace_id,
+            span_id = %context.span_id,
+            parent_span_id = Empty,
+        );
+        if let Some(parent) = parent {
+            span.record("parent_span_id", parent.span_id.as_str());
+        }
+        let future = self.svc.call(req).instrument(span);
+        scope(context.clone(), future)
+            .map(move |response| {
+                response.map(|mut response| {
+                    for data in &mut response.data {
+                        data.trace.get_or_insert_with(|| context.clone());
+                    }
+                    response
+                })
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sithra_transport::{datapack::RequestDataPack, trace::TraceContext};
+    use tower::{Service, ServiceExt};
+
+    use super::{Trace, current};
+    use crate::{extract::payload::Payload, on, request::Request, routing::router::Router};
+
+    #[tokio::test]
+    async fn propagate() {
+        let mut router: Router = Router::new()
+            .route(
+                "/trace",
+                on(async || Payload(current().map(|context| context.span_id))),
+            )
+            .layer(Trace);
+
+        let parent = TraceContext::new_root();
+        let request = Request::new(RequestDataPack::default().path("/trace").trace(parent.clone()));
+        let response = router.ready().await.unwrap().call(request).await.unwrap();
+        let data = response.data.into_iter().next().unwrap();
+        let trace = data.trace.clone().unwrap();
+        assert_eq!(trace.trace_id, parent.trace_id);
+        assert_ne!(trace.span_id, parent.span_id);
+        assert_eq!(
+            data.payload::<Option<String>>().unwrap(),
+            Some(trace.span_id)
+        );
+
+        assert!(current().is_none());
+    }
+}
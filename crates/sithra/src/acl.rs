@@ -0,0 +1,190 @@
+//! Enforcement of the permissions of plugins.
+//!
+//! The host checks every request a plugin sends against its
+//! [`AclConfig`]. A denied request is not routed: the plugin gets an error
+//! response instead, and the denial is logged with the `sithra::audit`
+//! target. Requests the plugin may not receive are not routed to it, and
+//! are logged there too; the host's own packs, such as `/config.updated`,
+//! are exempt.
+//!
+//! The host services that start, stop or restart plugins are denied unless
+//! the `send` list of the plugin grants them explicitly.
+
+use sithra_kit::{
+    transport::datapack::DataPack,
+    types::host::{RestartPlugin, StartPlugin, StopPlugin},
+};
+use thiserror::Error;
+
+use crate::conf::AclConfig;
+
+/// The log target of denied requests.
+pub const AUDIT_TARGET: &str = "sithra::audit";
+
+/// Why a request was denied.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AclError {
+    #[error("Permission denied: may not send {0}")]
+    Path(String),
+    #[error("Permission denied: may not address bot {0}")]
+    Bot(String),
+    #[error("Permission denied: may not address channel {0}")]
+    Channel(String),
+}
+
+impl AclConfig {
+    /// Checks that the plugin may send `data`. Responses are always allowed,
+    /// and the host services that change the plugins only when `send` lists
+    /// them.
+    ///
+    /// # Errors
+    /// Returns the first permission `data` lacks.
+    pub fn check_send(&self, data: &DataPack) -> Result<(), AclError> {
+        let Some(path) = data.path.as_deref() else {
+            return Ok(());
+        };
+        let allowed = if privileged(path) {
+            self.send.as_deref().is_some_and(|patterns| matches(patterns, path))
+        } else {
+            allows(self.send.as_deref(), path)
+        };
+        if !allowed {
+            return Err(AclError::Path(path.to_owned()));
+        }
+        if let Some(bot_id) = data.bot_id.as_deref()
+            && !allows(self.bots.as_deref(), bot_id)
+        {
+            return Err(AclError::Bot(bot_id.to_owned()));
+        }
+        if let Some(channel) = &data.channel
+            && !allows(self.channels.as_deref(), &channel.id)
+        {
+            return Err(AclError::Channel(channel.id.clone()));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the plugin may receive `data`. Responses are always
+    /// allowed.
+    #[must_use]
+    pub fn may_receive(&self, data: &DataPack) -> bool {
+        data.path.as_deref().is_none_or(|path| allows(self.receive.as_deref(), path))
+    }
+}
+
+/// Returns whether `path` is a host service that changes the running plugins.
+fn privileged(path: &str) -> bool {
+    [StartPlugin::path(), StopPlugin::path(), RestartPlugin::path()].contains(&path)
+}
+
+fn allows(patterns: Option<&[String]>, text: &str) -> bool {
+    patterns.is_none_or(|patterns| matches(patterns, text))
+}
+
+fn matches(patterns: &[String], text: &str) -> bool {
+    patterns.iter().any(|pattern| glob(pattern, text))
+}
+
+/// Matches `text` against `pattern`, where `*` matches any text and `?` any
+/// character.
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // The last `*` and the text it was matched at, to backtrack to.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                let Some((star_p, star_t)) = star else {
+                    return false;
+                };
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use sithra_kit::transport::{channel::Channel, datapack::DataPack};
+
+    use super::{AclError, glob};
+    use crate::conf::AclConfig;
+
+    #[test]
+    fn globs() {
+        assert!(glob("/command/*", "/command/message.create"));
+        assert!(glob("*", ""));
+        assert!(glob("qq-?", "qq-1"));
+        assert!(glob("*.create", "/message.create"));
+        assert!(glob("a*b*c", "axxbyyc"));
+        assert!(!glob("a*b*c", "axxbyy"));
+        assert!(!glob("/command", "/command/help"));
+        assert!(!glob("qq-?", "qq-12"));
+    }
+
+    #[test]
+    fn check() {
+        let acl: AclConfig = toml::from_str(
+            r#"
+            send = ["/command/*"]
+            receive = ["/message"]
+            bots = ["qq-*"]
+            channels = ["100*"]
+            "#,
+        )
+        .unwrap();
+        let request = |path: &str| DataPack::builder().path(&path).bot_id("qq-1");
+        let channel = |id: &str| Channel::Group(id.to_owned(), String::new());
+
+        assert_eq!(acl.check_send(&request("/command/help").build()), Ok(()));
+        assert_eq!(
+            acl.check_send(&request("/message").build()),
+            Err(AclError::Path("/message".to_owned()))
+        );
+        assert_eq!(
+            acl.check_send(&request("/command/help").bot_id("tg-1").build()),
+            Err(AclError::Bot("tg-1".to_owned()))
+        );
+        assert_eq!(
+            acl.check_send(&request("/command/help").channel(channel("1001")).build()),
+            Ok(())
+        );
+        assert_eq!(
+            acl.check_send(&request("/command/help").channel(channel("2001")).build()),
+            Err(AclError::Channel("2001".to_owned()))
+        );
+        assert_eq!(acl.check_send(&DataPack::builder().build()), Ok(()));
+        assert!(acl.may_receive(&request("/message").build()));
+        assert!(!acl.may_receive(&request("/command/help").build()));
+        assert!(AclConfig::default().check_send(&request("/message").build()).is_ok());
+    }
+
+    #[test]
+    fn privileged() {
+        let stop = DataPack::builder().path(&"/host/plugin.stop").build();
+        assert_eq!(
+            AclConfig::default().check_send(&stop),
+            Err(AclError::Path("/host/plugin.stop".to_owned()))
+        );
+        let admin = AclConfig {
+            send: Some(vec!["/host/*".to_owned()]),
+            ..AclConfig::default()
+        };
+        assert_eq!(admin.check_send(&stop), Ok(()));
+        let info = DataPack::builder().path(&"/host/info").build();
+        assert_eq!(AclConfig::default().check_send(&info), Ok(()));
+    }
+}
@@ -1,45 +1,501 @@
-use std::path::PathBuf;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use ahash::HashMap;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+#[derive(Debug)]
 pub struct Config {
     pub raw: String,
+    pub host: HostConfig,
     pub config: HashMap<String, BaseConfig>,
 }
 
+/// Settings of the host itself, from the `[host]` table.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HostConfig {
+    /// Address to serve Prometheus metrics on, e.g. `127.0.0.1:9100`.
+    #[serde(default)]
+    pub metrics_addr:         Option<SocketAddr>,
+    /// Path of the control socket used by the `sithra` subcommands, relative
+    /// to the config file.
+    #[serde(default)]
+    pub control_socket:       Option<PathBuf>,
+    /// How long a request may wait for a response before the host answers
+    /// it with a timeout error.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        Self {
+            metrics_addr:         None,
+            control_socket:       None,
+            request_timeout_secs: default_request_timeout_secs(),
+        }
+    }
+}
+
+const fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+impl HostConfig {
+    /// Returns the path of the control socket, `sithra.sock` in the config
+    /// directory `dir` unless configured otherwise.
+    #[must_use]
+    pub fn control_socket_path(&self, dir: &Path) -> PathBuf {
+        match &self.control_socket {
+            Some(path) => dir.join(path),
+            None => dir.join("sithra.sock"),
+        }
+    }
+}
+
+/// Names the config file when `--config` is not given.
+pub const CONFIG_ENV: &str = "SITHRA_CONFIG";
+/// Names the profile when `--profile` is not given.
+pub const PROFILE_ENV: &str = "SITHRA_PROFILE";
+
+/// Where the configuration is read from: a base file, and optionally the file
+/// of a profile layered over it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSource {
+    pub path:    PathBuf,
+    pub profile: Option<String>,
+}
+
+impl ConfigSource {
+    /// Uses `path` and `profile` if given, and otherwise `SITHRA_CONFIG` and
+    /// `SITHRA_PROFILE`. The config file defaults to `config.toml` next to
+    /// the executable.
+    ///
+    /// # Errors
+    /// Returns an error if the path of the current executable is needed but
+    /// unknown.
+    pub fn resolve(path: Option<PathBuf>, profile: Option<String>) -> std::io::Result<Self> {
+        let path = match path.or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from)) {
+            Some(path) => path,
+            None => config_path()?,
+        };
+        let profile = profile
+            .or_else(|| std::env::var(PROFILE_ENV).ok())
+            .filter(|profile| !profile.is_empty());
+        Ok(Self { path, profile })
+    }
+
+    /// Returns the file of the profile, e.g. `config.prod.toml` for the
+    /// profile `prod` of `config.toml`.
+    #[must_use]
+    pub fn profile_path(&self) -> Option<PathBuf> {
+        let profile = self.profile.as_ref()?;
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(extension) => format!("{stem}.{profile}.{}", extension.to_string_lossy()),
+            None => format!("{stem}.{profile}"),
+        };
+        Some(self.path.with_file_name(name))
+    }
+
+    /// Returns the directory of the config file, against which relative
+    /// paths in it are resolved.
+    ///
+    /// # Errors
+    /// Returns an error if the current directory is needed but unknown.
+    pub fn dir(&self) -> std::io::Result<PathBuf> {
+        let path = std::path::absolute(&self.path)?;
+        Ok(path.parent().map_or_else(|| PathBuf::from("/"), Path::to_path_buf))
+    }
+
+    /// Returns the files the configuration is read from.
+    #[must_use]
+    pub fn paths(&self) -> Vec<PathBuf> {
+        std::iter::once(self.path.clone()).chain(self.profile_path()).collect()
+    }
+
+    /// Reads the configuration.
+    ///
+    /// The tables of the profile are merged into those of the base file, and
+    /// its other values replace theirs. `${VAR}` in strings is replaced with
+    /// the environment variable `VAR`, and `$${` stands for `${`. Relative
+    /// paths are relative to the base file, and the data directory of a
+    /// plugin defaults to `data/<plugin>` next to it.
+    ///
+    /// # Errors
+    /// Returns an error if a file cannot be read or parsed, if a variable is
+    /// not set, or if a table is invalid.
+    pub fn load(&self) -> Result<Config, LoadConfigError> {
+        let mut table = read_table(&self.path)?;
+        if let Some(path) = self.profile_path() {
+            merge(&mut table, read_table(&path)?);
+        }
+        let env = |var: &str| std::env::var(var).ok();
+        for (key, value) in &mut table {
+            expand_value(value, key, &env)?;
+        }
+        let raw = table.to_string();
+
+        let dir = self.dir()?;
+        let mut host: HostConfig = match table.remove("host") {
+            Some(host) => host.try_into().map_err(LoadConfigError::HostError)?,
+            None => HostConfig::default(),
+        };
+        host.control_socket = Some(host.control_socket_path(&dir));
+        let mut plugins = HashMap::default();
+        for (name, value) in table {
+            let plugin = value.try_into::<BaseConfig>();
+            let mut plugin = plugin.map_err(|source| LoadConfigError::PluginError {
+                name: name.clone(),
+                source,
+            })?;
+            let resolve = |path: &mut PathBuf| {
+                if path.is_relative() {
+                    *path = dir.join(&*path);
+                }
+            };
+            resolve(&mut plugin.path);
+            for path in [&mut plugin.cwd, &mut plugin.data_dir].into_iter().flatten() {
+                resolve(path);
+            }
+            if let Stderr::File(file) = &mut plugin.stderr {
+                resolve(&mut file.path);
+            }
+            plugin.data_dir.get_or_insert_with(|| dir.join("data").join(&name));
+            plugins.insert(name, plugin);
+        }
+
+        Ok(Config {
+            raw,
+            host,
+            config: plugins,
+        })
+    }
+}
+
+/// Reads the configuration named by `SITHRA_CONFIG` and `SITHRA_PROFILE`,
+/// or `config.toml` next to the executable.
+///
 /// # Errors
 ///
-/// * `ReadError` - Failed to read config file
-/// * `ParseError` - Failed to parse config file
+/// See [`ConfigSource::load`].
+///
+/// # Panics
+///
+/// Panics if the current executable has no parent directory.
 pub fn load_config() -> Result<Config, LoadConfigError> {
+    ConfigSource::resolve(None, None)?.load()
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, LoadConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|source| LoadConfigError::ReadError {
+        path: path.to_owned(),
+        source,
+    })?;
+    toml::from_str(&text).map_err(|source| LoadConfigError::ParseError {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Merges `overlay` into `base`: tables are merged key by key, and other
+/// values are replaced.
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Expands the variables in the strings of `value`, which is at `key`.
+fn expand_value(
+    value: &mut toml::Value,
+    key: &str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), LoadConfigError> {
+    match value {
+        toml::Value::String(text) => *text = expand(text, key, env)?,
+        toml::Value::Array(values) => {
+            for (index, value) in values.iter_mut().enumerate() {
+                expand_value(value, &format!("{key}[{index}]"), env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, value) in table {
+                expand_value(value, &format!("{key}.{name}"), env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces `${VAR}` in `text` with the value of `VAR`, and `$${` with `${`.
+fn expand(
+    text: &str,
+    key: &str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<String, LoadConfigError> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some((var, after)) = rest.strip_prefix("${").and_then(|after| after.split_once('}'))
+        else {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let value = env(var).ok_or_else(|| LoadConfigError::EnvError {
+            key: key.to_owned(),
+            var: var.to_owned(),
+        })?;
+        expanded.push_str(&value);
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Returns the path of `config.toml`, next to the executable.
+///
+/// # Errors
+/// Returns an error if the path of the current executable is unknown.
+pub fn config_path() -> std::io::Result<PathBuf> {
+    Ok(exe_dir()?.join("config.toml"))
+}
+
+/// # Panics
+///
+/// Panics if the current executable has no parent directory.
+fn exe_dir() -> std::io::Result<PathBuf> {
     let curexedir = std::env::current_exe()?
         .parent().unwrap().to_owned();
-    let config_path = curexedir.join("config.toml");
-    let config_file = std::fs::read_to_string(&config_path)?;
-    let config: HashMap<String, BaseConfig> = toml::from_str(&config_file)?;
+    Ok(curexedir)
+}
 
-    Ok(Config {
-        raw: config_file,
-        config,
-    })
+/// Polls config files for changes.
+pub struct ConfigWatcher {
+    paths:    Vec<PathBuf>,
+    interval: Duration,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl ConfigWatcher {
+    /// Watches `paths`, taking their current state as unchanged.
+    #[must_use]
+    pub fn new(paths: Vec<PathBuf>, interval: Duration) -> Self {
+        let modified = paths.iter().map(|path| modified(path)).collect();
+        Self {
+            paths,
+            interval,
+            modified,
+        }
+    }
+
+    /// Waits until one of the files is modified, created or removed.
+    pub async fn changed(&mut self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let modified = self.paths.iter().map(|path| modified(path)).collect::<Vec<_>>();
+            if modified != self.modified {
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
 }
 
 #[derive(Debug, Error)]
 pub enum LoadConfigError {
-    #[error("Failed to read config file")]
-    ReadError(#[from] std::io::Error),
-    #[error("Failed to parse config file")]
-    ParseError(#[from] toml::de::Error),
+    #[error("Failed to locate config file: {0}")]
+    LocateError(#[from] std::io::Error),
+    #[error("Failed to read {}: {source}", path.display())]
+    ReadError {
+        path:   PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse {}: {source}", path.display())]
+    ParseError {
+        path:   PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Environment variable {var} used in `{key}` is not set")]
+    EnvError { key: String, var: String },
+    #[error("Invalid [host] table: {0}")]
+    HostError(toml::de::Error),
+    #[error("Invalid [{name}] table: {source}")]
+    PluginError {
+        name:   String,
+        source: toml::de::Error,
+    },
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct BaseConfig {
-    pub path: PathBuf,
+    pub path:               PathBuf,
+    #[serde(default)]
+    pub args:               Vec<String>,
+    /// The paths of the requests the plugin handles. If unset, the plugin
+    /// registers them itself.
+    #[serde(default)]
+    pub paths:              Option<Vec<String>>,
+    /// When to restart the plugin after it exits.
     #[serde(default)]
-    pub args:   Vec<String>,
-    pub config: Option<toml::Value>,
+    pub restart:            RestartConfig,
+    /// The plugins that must be ready before this one starts.
+    #[serde(default)]
+    pub depends_on:         Vec<String>,
+    /// How long to wait for the plugin to report ready before sending it
+    /// requests anyway, and between warnings while it waits for the plugins
+    /// it depends on.
+    #[serde(default = "default_ready_timeout_secs")]
+    pub ready_timeout_secs: u64,
+    /// Environment variables set for the plugin, on top of the host's.
+    #[serde(default)]
+    pub env:                HashMap<String, String>,
+    /// The working directory of the plugin. Defaults to the host's.
+    #[serde(default)]
+    pub cwd:                Option<PathBuf>,
+    /// The directory of the plugin's storage. Defaults to `data/<plugin>`
+    /// next to the config file.
+    #[serde(default)]
+    pub data_dir:           Option<PathBuf>,
+    /// Where the standard error of the plugin goes.
+    #[serde(default)]
+    pub stderr:             Stderr,
+    /// What the plugin may send and receive.
+    #[serde(default)]
+    pub acl:                AclConfig,
+    pub config:             Option<toml::Value>,
+}
+
+const fn default_ready_timeout_secs() -> u64 {
+    30
+}
+
+/// Where the standard error of a plugin goes:
+///
+/// ```toml
+/// stderr = "inherit"
+/// stderr = "log"
+/// stderr = { file = { path = "logs/echo.log", max-bytes = 1048576, keep = 3 } }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stderr {
+    /// To the standard error of the host.
+    #[default]
+    Inherit,
+    /// To the host's logger, line by line, with the plugin's name as target.
+    Log,
+    /// To a file that is rotated when it grows too large.
+    File(StderrFile),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StderrFile {
+    pub path:      PathBuf,
+    /// The size past which the file is rotated.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    /// How many rotated files are kept, as `<path>.1` to `<path>.<keep>`.
+    #[serde(default = "default_keep")]
+    pub keep:      u32,
+}
+
+const fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+const fn default_keep() -> u32 {
+    3
+}
+
+/// The permissions of a plugin, from the `[<plugin>.acl]` table:
+///
+/// ```toml
+/// [echo.acl]
+/// send = ["/command/message.create"]
+/// receive = ["/message", "/command/*"]
+/// bots = ["qq-*"]
+/// channels = ["10086", "test-*"]
+/// ```
+///
+/// Each list holds glob patterns, where `*` matches any text and `?` any
+/// character. An unset list allows everything, except the host services that
+/// start, stop or restart plugins: `send` has to list those explicitly.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// The paths of the requests the plugin may send.
+    pub send:     Option<Vec<String>>,
+    /// The paths of the requests the plugin may receive.
+    pub receive:  Option<Vec<String>>,
+    /// The bots the plugin may address with `bot_id`.
+    pub bots:     Option<Vec<String>>,
+    /// The ids of the channels the plugin may address.
+    pub channels: Option<Vec<String>>,
+}
+
+/// Which exits of a plugin the host restarts it after.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    /// Only after exiting with an error or being killed by a signal.
+    #[default]
+    OnFailure,
+    Never,
+}
+
+/// Settings for restarting a plugin, from the `[<plugin>.restart]` table.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct RestartConfig {
+    pub policy:         RestartPolicy,
+    /// The host gives up on a plugin restarted this many times within
+    /// `window-secs`.
+    pub max_restarts:   u32,
+    pub window_secs:    u64,
+    /// The delay before restarting, doubled on every restart up to
+    /// `backoff-max-ms`. It is reset once the plugin has run for
+    /// `window-secs`.
+    pub backoff_ms:     u64,
+    pub backoff_max_ms: u64,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            policy:         RestartPolicy::default(),
+            max_restarts:   5,
+            window_secs:    60,
+            backoff_ms:     500,
+            backoff_max_ms: 30_000,
+        }
+    }
 }
 
 impl Config {
@@ -47,3 +503,125 @@ impl Config {
         self.config.iter().map(|(key, value)| (key.as_str(), value))
     }
 }
+
+#[cfg(test)]
+impl Config {
+    /// The config of the plugins in the TOML `raw`, with the default host
+    /// settings.
+    pub(crate) fn plugins(raw: &str) -> Self {
+        Self {
+            raw:    raw.to_owned(),
+            host:   HostConfig::default(),
+            config: toml::from_str(raw).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{ConfigSource, LoadConfigError, RestartPolicy, expand};
+
+    fn write(dir: &std::path::Path, name: &str, text: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn profiles() {
+        let dir = std::env::temp_dir().join(format!("sithra-conf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = write(
+            &dir,
+            "config.toml",
+            r#"
+            [host]
+            metrics-addr = "127.0.0.1:9100"
+            [onebot]
+            path = "onebot"
+            config = { url = "ws://localhost", token = "$${TOKEN}" }
+            [onebot.restart]
+            policy = "never"
+            "#,
+        );
+        write(
+            &dir,
+            "config.prod.toml",
+            r#"
+            [onebot.config]
+            url = "ws://bot"
+            [echo]
+            path = "/opt/echo"
+            "#,
+        );
+
+        let base = ConfigSource {
+            path:    path.clone(),
+            profile: None,
+        };
+        assert_eq!(base.paths(), std::slice::from_ref(&path));
+        let prod = ConfigSource {
+            path,
+            profile: Some("prod".to_owned()),
+        };
+        assert_eq!(prod.profile_path(), Some(dir.join("config.prod.toml")));
+
+        let config = prod.load().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let onebot = &config.config["onebot"];
+        assert_eq!(onebot.path, dir.join("onebot"));
+        assert_eq!(onebot.data_dir, Some(dir.join("data").join("onebot")));
+        assert_eq!(config.host.control_socket, Some(dir.join("sithra.sock")));
+        assert_eq!(onebot.restart.policy, RestartPolicy::Never);
+        let settings = onebot.config.as_ref().unwrap();
+        assert_eq!(settings["url"].as_str(), Some("ws://bot"));
+        assert_eq!(settings["token"].as_str(), Some("${TOKEN}"));
+        assert_eq!(config.config["echo"].path, PathBuf::from("/opt/echo"));
+        assert!(config.host.metrics_addr.is_some());
+        assert!(base.load().is_err());
+    }
+
+    #[test]
+    fn variables() {
+        let env = |var: &str| (var == "TOKEN").then(|| "secret".to_owned());
+        let expand = |text: &str| expand(text, "onebot.config.token", &env);
+        assert_eq!(expand("Bearer ${TOKEN}").unwrap(), "Bearer secret");
+        assert_eq!(
+            expand("$5 $${TOKEN} ${TOKEN").unwrap(),
+            "$5 ${TOKEN} ${TOKEN"
+        );
+        let err = expand("${MISSING}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable MISSING used in `onebot.config.token` is not set"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let dir = std::env::temp_dir().join(format!("sithra-conf-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let load = |text: &str| {
+            let path = write(&dir, "config.toml", text);
+            ConfigSource {
+                path,
+                profile: None,
+            }
+            .load()
+        };
+
+        let err = load("[echo]\npath = \"echo\"\ndepend-on = [\"onebot\"]").unwrap_err();
+        assert!(matches!(&err, LoadConfigError::PluginError { name, .. } if name == "echo"));
+        assert!(err.to_string().contains("unknown field `depend-on`"));
+        let err =
+            load("[echo]\npath = \"echo\"\n[echo.restart]\npolicy = \"sometimes\"").unwrap_err();
+        assert!(err.to_string().contains("restart.policy"));
+        let err = load("[echo]\npath = \"${SITHRA_TEST_UNSET}\"").unwrap_err();
+        assert!(matches!(err, LoadConfigError::EnvError { key, .. } if key == "echo.path"));
+        let err = load("[echo\n").unwrap_err();
+        assert!(matches!(err, LoadConfigError::ParseError { .. }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+//! The control socket of the host.
+//!
+//! The `sithra` subcommands manage a running host through a Unix socket: each
+//! connection sends [`Request`]s as lines of JSON, and reads back one
+//! [`Response`] line per request.
+
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sithra_kit::{
+    transport::datapack::{DataPack, StreamFlag},
+    types::host::{HostInfo, PluginStatus},
+};
+use tokio::sync::{Mutex, mpsc};
+use ulid::Ulid;
+
+use crate::{conf::AclConfig, loader::Loader, routing::Routes};
+
+/// How long [`Request::Send`] waits for the responses.
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    Plugins,
+    Start {
+        name: String,
+    },
+    Stop {
+        name: String,
+    },
+    Restart {
+        name: String,
+    },
+    /// Sends a request to the plugins, as if from a plugin that handles
+    /// nothing, and waits for the responses.
+    Send {
+        path:    String,
+        payload: serde_json::Value,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum Response {
+    Status(HostInfo),
+    Plugins {
+        plugins: Vec<PluginStatus>,
+    },
+    Done,
+    /// The payloads of the responses received within [`SEND_TIMEOUT`].
+    Sent {
+        responses: Vec<serde_json::Value>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Response {
+    fn error(message: &impl ToString) -> Self {
+        Self::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Serves the control socket at `path` until it fails.
+///
+/// A stale socket left by a host that did not exit cleanly is replaced.
+///
+/// # Errors
+/// Returns an error if another host is listening on `path`, or if the socket
+/// cannot be created.
+#[cfg(unix)]
+pub async fn serve(path: &Path, loader: Arc<Mutex<Loader>>) -> io::Result<()> {
+    use tokio::net::UnixStream;
+
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("Another host is listening on {}", path.display()),
+        ));
+    }
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let loader = loader.clone();
+        tokio::spawn(async move {
+            let (read, write) = stream.into_split();
+            if let Err(err) = handle(read, write, &loader).await {
+                log::warn!("Control connection failed: {err}");
+            }
+        });
+    }
+}
+
+/// Binds a socket at `path` that only the current user can connect to.
+///
+/// The socket is bound in a private directory and only moved to `path` once
+/// its permissions are restricted, so others never get to connect to it.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::{
+        fs::{DirBuilder, Permissions},
+        os::unix::fs::{DirBuilderExt, PermissionsExt},
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{name}.{}", std::process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let bind = || {
+        let socket = dir.join("socket");
+        let listener = tokio::net::UnixListener::bind(&socket)?;
+        std::fs::set_permissions(&socket, Permissions::from_mode(0o600))?;
+        std::fs::rename(&socket, path)?;
+        Ok(listener)
+    };
+    let listener = bind();
+    std::fs::remove_dir_all(&dir).ok();
+    listener
+}
+
+/// # Errors
+/// Always returns an error: the control socket needs Unix sockets.
+#[cfg(not(unix))]
+pub async fn serve(_path: &Path, _loader: Arc<Mutex<Loader>>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The control socket is only supported on Unix",
+    ))
+}
+
+/// Sends `request` to the host listening on `path`, and returns its response.
+///
+/// # Errors
+/// Returns an error if no host is listening on `path`, or if the connection
+/// fails.
+#[cfg(unix)]
+pub async fn request(path: &Path, request: &Request) -> io::Result<Response> {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixStream,
+    };
+
+    let stream = UnixStream::connect(path).await?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    let line = BufReader::new(read).lines().next_line().await?;
+    let line = line.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// # Errors
+/// Always returns an error: the control socket needs Unix sockets.
+#[cfg(not(unix))]
+pub async fn request(_path: &Path, _request: &Request) -> io::Result<Response> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The control socket is only supported on Unix",
+    ))
+}
+
+/// Answers the requests of a connection until it is closed.
+async fn handle<R, W>(read: R, mut write: W, loader: &Mutex<Loader>) -> io::Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => execute(request, loader).await,
+            Err(err) => Response::error(&format!("Invalid request: {err}")),
+        };
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn execute(request: Request, loader: &Mutex<Loader>) -> Response {
+    let result = match request {
+        Request::Status => return Response::Status(loader.lock().await.info()),
+        Request::Plugins => {
+            return Response::Plugins {
+                plugins: loader.lock().await.plugins(),
+            };
+        }
+        Request::Send { path, payload } => {
+            let routes = loader.lock().await.routes();
+            return send(&routes, &path, payload).await;
+        }
+        Request::Start { name } => loader.lock().await.start_plugin(&name),
+        Request::Stop { name } => loader.lock().await.stop_plugin(&name).await,
+        Request::Restart { name } => loader.lock().await.restart_plugin(&name).await,
+    };
+    result.map_or_else(|err| Response::error(&err), |()| Response::Done)
+}
+
+/// Dispatches a request for `path` and collects the payloads of its
+/// responses, until one that does not continue a stream.
+async fn send(routes: &Routes, path: &str, payload: serde_json::Value) -> Response {
+    let payload = match rmpv::ext::to_value(payload) {
+        Ok(payload) => payload,
+        Err(err) => return Response::error(&format!("Invalid payload: {err}")),
+    };
+    let name = format!("@control-{}", Ulid::new());
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    if let Err(err) = routes.insert(&name, tx, Some(&[]), AclConfig::default()) {
+        return Response::error(&err);
+    }
+    routes.set_ready(&name);
+    let request = DataPack::builder().path(&path).payload(payload).awaits_reply().build();
+    let key = request.correlation();
+    routes.dispatch(&name, request);
+
+    let mut responses = Vec::new();
+    let received = tokio::time::timeout(SEND_TIMEOUT, async {
+        while let Some(data) = rx.recv().await {
+            if data.is_request() || data.correlation() != key {
+                continue;
+            }
+            responses.push(data.payload::<serde_json::Value>()?);
+            if data.stream != Some(StreamFlag::Item) {
+                break;
+            }
+        }
+        Ok::<_, String>(())
+    })
+    .await;
+    routes.remove(&name);
+    match received {
+        Ok(Err(message)) => Response::Error { message },
+        Ok(Ok(())) | Err(_) => Response::Sent { responses },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sithra_kit::transport::datapack::DataPack;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    use super::{Request, Response, handle};
+    use crate::loader::Loader;
+
+    #[test]
+    fn protocol() {
+        let request = Request::Restart {
+            name: "echo".to_owned(),
+        };
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, r#"{"command":"restart","name":"echo"}"#);
+        let line = serde_json::to_string(&Response::Done).unwrap();
+        assert_eq!(line, r#"{"result":"done"}"#);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn private_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("sithra-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sithra.sock");
+        let _listener = super::bind_private(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(entries, 1);
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        let (loader, mut rx) = Loader::connected(r#"echo = { path = "echo" }"#, "echo", None);
+        let routes = loader.lock().await.routes();
+        tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                let text = data.payload::<String>().unwrap();
+                let reply = DataPack::builder().correlate(data.correlation()).payload(text).build();
+                routes.dispatch("echo", reply);
+            }
+        });
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(server);
+        tokio::spawn(async move { handle(read, write, &loader).await });
+        let (read, mut write) = tokio::io::split(client);
+        let mut lines = BufReader::new(read).lines();
+        let mut request = async |request: &str| {
+            write.write_all(format!("{request}\n").as_bytes()).await.unwrap();
+            let line = lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str::<Response>(&line).unwrap()
+        };
+
+        let Response::Plugins { plugins } = request(r#"{"command":"plugins"}"#).await else {
+            panic!("expected plugins");
+        };
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(
+            request(r#"{"command":"stop","name":"echo"}"#).await,
+            Response::error(&"Plugin echo is not running")
+        );
+        assert_eq!(
+            request(r#"{"command":"stop","name":"missing"}"#).await,
+            Response::error(&"Unknown plugin missing")
+        );
+        assert!(matches!(request("not json").await, Response::Error { .. }));
+        assert_eq!(
+            request(r#"{"command":"send","path":"/echo","payload":"hi"}"#).await,
+            Response::Sent {
+                responses: vec![json!("hi")],
+            }
+        );
+    }
+}
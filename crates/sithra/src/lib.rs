@@ -1,5 +1,11 @@
+pub mod acl;
 pub mod conf;
+pub mod control;
 pub mod loader;
+pub mod metrics;
+pub mod routing;
+pub mod services;
+pub mod stderr;
 
 #[cfg(test)]
 mod test {
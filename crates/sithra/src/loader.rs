@@ -1,156 +1,737 @@
-use std::{ffi::OsStr, io, process::Stdio};
+use std::{
+    collections::VecDeque,
+    io,
+    path::Path,
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use ahash::HashMap;
+use ahash::{HashMap, HashSet};
 use futures_util::{SinkExt, StreamExt};
 use sithra_kit::{
+    server::metrics::{Counter, registry},
+    storage::DATA_DIR_ENV,
     transport::{
-        datapack::{DataPack, DataPackCodec},
+        datapack::{DataPack, DataPackCodec, RawDataPack},
         peer::{Peer, Reader, Writer},
     },
-    types::{initialize::Initialize, log::Log},
+    types::{
+        host::{HostInfo, PluginState, PluginStatus, Ready, Register},
+        initialize::{ConfigUpdated, Initialize},
+        log::Log,
+        metrics::{GetMetrics, MetricsReport},
+        trace::Span,
+    },
+};
+use thiserror::Error;
+use tokio::{
+    process::{Child, Command},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    task::JoinHandle,
 };
-use tokio::{process::Command, sync::broadcast, task::JoinHandle};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
-use crate::conf::Config;
+use crate::{
+    acl::AUDIT_TARGET,
+    conf::{AclConfig, BaseConfig, Config, RestartConfig, RestartPolicy, Stderr},
+    metrics::HostMetrics,
+    routing::{RequestError, Routes},
+    services::{self, HostRequest},
+    stderr,
+};
 
 pub struct Loader {
-    config:        Config,
-    broadcast_tx:  broadcast::Sender<DataPack>,
-    _broadcast_rx: broadcast::Receiver<DataPack>,
-    join_map:      HashMap<String, (JoinHandle<()>, JoinHandle<()>)>,
+    config:   Config,
+    routes:   Arc<Routes>,
+    join_map: HashMap<String, Running>,
+    /// Plugins stopped by an operator, which reloading does not start.
+    stopped:  HashSet<String>,
+    metrics:  Arc<HostMetrics>,
+    /// Answers the requests that time out, once loaded.
+    expiry:   Option<JoinHandle<()>>,
+    services: UnboundedSender<HostRequest>,
+    /// The requests for host services, until taken by [`services::serve`].
+    requests: Option<UnboundedReceiver<HostRequest>>,
+    started:  Instant,
+}
+
+/// A plugin under supervision.
+struct Running {
+    handle: JoinHandle<()>,
+    /// The init pack sent to the plugin whenever it starts.
+    init:   watch::Sender<RawDataPack>,
 }
 
 impl Loader {
     #[must_use]
     pub fn new(config: Config) -> Self {
-        let (broadcast_tx, broadcast_rx) = broadcast::channel(32);
         let join_map = HashMap::default();
+        let timeout = Duration::from_secs(config.host.request_timeout_secs);
+        let (services, requests) = mpsc::unbounded_channel();
 
         Self {
             config,
-            broadcast_tx,
-            _broadcast_rx: broadcast_rx,
+            routes: Arc::new(Routes::new(timeout)),
             join_map,
+            stopped: HashSet::default(),
+            metrics: Arc::default(),
+            expiry: None,
+            services,
+            requests: Some(requests),
+            started: Instant::now(),
+        }
+    }
+
+    #[must_use]
+    pub fn routes(&self) -> Arc<Routes> {
+        self.routes.clone()
+    }
+
+    /// Takes the requests plugins send for host services.
+    pub const fn host_requests(&mut self) -> Option<UnboundedReceiver<HostRequest>> {
+        self.requests.take()
+    }
+
+    #[must_use]
+    pub fn info(&self) -> HostInfo {
+        let plugins = self.plugins();
+        let running = plugins.iter().filter(|plugin| plugin.state != PluginState::Stopped);
+        HostInfo {
+            version:     env!("CARGO_PKG_VERSION").to_owned(),
+            pid:         std::process::id(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            running:     running.count(),
+            plugins:     plugins.len(),
+        }
+    }
+
+    /// Returns the state of every configured plugin, by name.
+    #[must_use]
+    pub fn plugins(&self) -> Vec<PluginStatus> {
+        let mut plugins = self
+            .config
+            .iter()
+            .map(|(name, config)| {
+                let state = if !self.is_running(name) {
+                    PluginState::Stopped
+                } else if self.routes.is_ready(name) {
+                    PluginState::Ready
+                } else if self.routes.is_waiting(name) {
+                    PluginState::Waiting
+                } else {
+                    PluginState::Starting
+                };
+                PluginStatus {
+                    name: name.to_owned(),
+                    state,
+                    path: config.path.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        plugins.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        plugins
+    }
+
+    /// Starts a stopped plugin.
+    ///
+    /// # Errors
+    /// Returns an error if the plugin is unknown or already running.
+    pub fn start_plugin(&mut self, name: &str) -> Result<(), LoaderError> {
+        self.check_known(name)?;
+        if self.is_running(name) {
+            return Err(LoaderError::Running(name.to_owned()));
+        }
+        self.stopped.remove(name);
+        self.start(name);
+        Ok(())
+    }
+
+    /// Stops a plugin until it is started again, even if the configuration
+    /// is reloaded.
+    ///
+    /// # Errors
+    /// Returns an error if the plugin is unknown or not running.
+    pub async fn stop_plugin(&mut self, name: &str) -> Result<(), LoaderError> {
+        self.check_known(name)?;
+        if !self.is_running(name) {
+            return Err(LoaderError::NotRunning(name.to_owned()));
+        }
+        log::info!("Stopping {name}");
+        self.stop(name).await;
+        self.routes.remove(name);
+        self.stopped.insert(name.to_owned());
+        Ok(())
+    }
+
+    /// Restarts a plugin, or starts it if it is stopped.
+    ///
+    /// # Errors
+    /// Returns an error if the plugin is unknown.
+    pub async fn restart_plugin(&mut self, name: &str) -> Result<(), LoaderError> {
+        self.check_known(name)?;
+        log::info!("Restarting {name}");
+        self.stop(name).await;
+        self.routes.set_stopped(name);
+        self.stopped.remove(name);
+        self.start(name);
+        Ok(())
+    }
+
+    fn check_known(&self, name: &str) -> Result<(), LoaderError> {
+        if self.config.config.contains_key(name) {
+            Ok(())
+        } else {
+            Err(LoaderError::Unknown(name.to_owned()))
         }
     }
 
+    fn is_running(&self, name: &str) -> bool {
+        self.join_map.get(name).is_some_and(|running| !running.handle.is_finished())
+    }
+
+    /// Returns the metrics reported by the plugins.
+    #[must_use]
+    pub fn metrics(&self) -> Arc<HostMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Starts the plugins, each under a supervisor that restarts it according
+    /// to its [`RestartConfig`], once its dependencies are ready.
     pub fn load(&mut self) {
-        for (name, config) in self.config.iter() {
-            log::info!("Loading {name}");
-            let broadcast_tx = self.broadcast_tx.clone();
-            let broadcast_rx = broadcast_tx.subscribe();
-            
-            let config_path = if config.path.is_relative() {
-                std::env::current_exe()
-                    .expect("Failed to get current executable path")
-                    .parent()
-                    .expect("Failed to get parent directory")
-                    .join(&config.path)
-            } else {
-                config.path.clone()
+        let routes = self.routes.clone();
+        self.expiry = Some(tokio::spawn(async move { routes.expire_pending().await }));
+        for name in self.start_order() {
+            self.start(&name);
+        }
+    }
+
+    /// Applies a new configuration without restarting the host.
+    ///
+    /// Added plugins are started and removed ones stopped. Plugins whose
+    /// `config` table changed receive `/config.updated`; those whose other
+    /// settings changed are restarted.
+    pub async fn reload(&mut self, config: Config) {
+        if config.raw == self.config.raw {
+            return;
+        }
+        if config.host != self.config.host {
+            log::warn!("Changes to [host] take effect when the host restarts");
+        }
+        let old = std::mem::replace(&mut self.config, config);
+        let changes = Changes::new(&old, &self.config);
+        for name in &changes.removed {
+            log::info!("Stopping {name}");
+            self.stop(name).await;
+            self.routes.remove(name);
+            self.metrics.remove(name);
+        }
+        for name in &changes.restarted {
+            log::info!("Restarting {name}");
+            self.stop(name).await;
+            self.routes.set_stopped(name);
+        }
+        for name in &changes.updated {
+            self.update(name);
+        }
+        self.stopped.retain(|name| self.config.config.contains_key(name));
+        for name in self.start_order() {
+            if !self.join_map.contains_key(&name) && !self.stopped.contains(&name) {
+                self.start(&name);
+            }
+        }
+    }
+
+    fn start_order(&self) -> Vec<String> {
+        start_order(&self.config).into_iter().map(str::to_owned).collect()
+    }
+
+    fn start(&mut self, name: &str) {
+        let Some(config) = self.config.config.get(name) else {
+            return;
+        };
+        log::info!("Loading {name}");
+        let (tx, _) = mpsc::unbounded_channel();
+        let acl = config.acl.clone();
+        if let Err(err) = self.routes.insert(name, tx, config.paths.as_deref(), acl) {
+            log::error!("Invalid paths for {name}: {err}");
+            return;
+        }
+        let Some(raw) = config_data(name, config).and_then(|data| init_raw(name, data)) else {
+            return;
+        };
+        let (init, rx) = watch::channel(raw);
+        let supervisor = Supervisor {
+            name:     name.to_owned(),
+            config:   config.clone(),
+            init:     rx,
+            routes:   self.routes.clone(),
+            metrics:  self.metrics.clone(),
+            services: self.services.clone(),
+        };
+        let handle = tokio::spawn(supervisor.run());
+        self.join_map.insert(name.to_owned(), Running { handle, init });
+    }
+
+    /// Sends the new `config` table to the plugin `name`, and to it again
+    /// whenever it restarts.
+    fn update(&self, name: &str) {
+        let (Some(running), Some(config)) = (self.join_map.get(name), self.config.config.get(name))
+        else {
+            return;
+        };
+        let Some(data) = config_data(name, config) else {
+            return;
+        };
+        let Some(raw) = init_raw(name, data.clone()) else {
+            return;
+        };
+        log::info!("Updating config of {name}");
+        running.init.send_replace(raw);
+        let updated = ConfigUpdated::new(data);
+        let updated = DataPack::builder().payload(updated).path(&<ConfigUpdated>::path()).build();
+        self.routes.send(name, updated);
+    }
+
+    /// Aborts the supervisor of the plugin `name` and waits until the plugin
+    /// is killed.
+    async fn stop(&mut self, name: &str) {
+        if let Some(Running { handle, .. }) = self.join_map.remove(name) {
+            handle.abort();
+            handle.await.ok();
+        }
+    }
+
+    pub fn abort(&mut self, name: &str) {
+        if let Some(running) = self.join_map.remove(name) {
+            running.handle.abort();
+        }
+        self.routes.remove(name);
+        self.metrics.remove(name);
+    }
+
+    pub fn abort_all(&mut self) {
+        for (_, running) in self.join_map.drain() {
+            running.handle.abort();
+        }
+        if let Some(expiry) = self.expiry.take() {
+            expiry.abort();
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LoaderError {
+    #[error("Unknown plugin {0}")]
+    Unknown(String),
+    #[error("Plugin {0} is already running")]
+    Running(String),
+    #[error("Plugin {0} is not running")]
+    NotRunning(String),
+}
+
+/// The plugins affected by a change of configuration.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Changes {
+    removed:   Vec<String>,
+    /// Plugins whose settings other than `config` changed.
+    restarted: Vec<String>,
+    /// Plugins whose `config` table alone changed.
+    updated:   Vec<String>,
+}
+
+impl Changes {
+    fn new(old: &Config, new: &Config) -> Self {
+        let mut changes = Self::default();
+        for (name, old) in old.iter() {
+            let Some(new) = new.config.get(name) else {
+                changes.removed.push(name.to_owned());
+                continue;
             };
-    
-            let peer = run(config_path, &config.args);
-            let peer = match peer {
-                Ok(peer) => peer,
-                Err(err) => {
-                    log::error!("Failed to start peer {name}: {err}");
-                    continue;
-                }
+            let launch = |config: &BaseConfig| BaseConfig {
+                config: None,
+                ..config.clone()
             };
-            let (write, read) = split_peer(peer);
-            let config_data = rmpv::ext::to_value(config.config.clone());
-            let config_data = match config_data {
-                Ok(config_data) => config_data,
-                Err(err) => {
-                    log::error!("Failed to serialize config data for {name}: {err}");
-                    continue;
+            if launch(old) != launch(new) {
+                changes.restarted.push(name.to_owned());
+            } else if old.config != new.config {
+                changes.updated.push(name.to_owned());
+            }
+        }
+        changes.removed.sort_unstable();
+        changes.restarted.sort_unstable();
+        changes.updated.sort_unstable();
+        changes
+    }
+}
+
+/// Converts the `config` table of the plugin `name` for sending it.
+fn config_data(name: &str, config: &BaseConfig) -> Option<rmpv::Value> {
+    rmpv::ext::to_value(config.config.clone())
+        .inspect_err(|err| log::error!("Failed to serialize config data for {name}: {err}"))
+        .ok()
+}
+
+fn init_raw(name: &str, data: rmpv::Value) -> Option<RawDataPack> {
+    init_datapack(data)
+        .serialize_to_raw()
+        .inspect_err(|err| log::error!("Failed to serialize init package for {name}: {err}"))
+        .ok()
+}
+
+/// How long to wait for the last packs of a plugin after it exits.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs a plugin and restarts it when it exits, until its [`RestartPolicy`]
+/// or its restart limit says otherwise.
+///
+/// Aborting the supervisor kills the plugin.
+struct Supervisor {
+    name:     String,
+    config:   BaseConfig,
+    init:     watch::Receiver<RawDataPack>,
+    routes:   Arc<Routes>,
+    metrics:  Arc<HostMetrics>,
+    services: UnboundedSender<HostRequest>,
+}
+
+impl Supervisor {
+    async fn run(self) {
+        let name = self.name.as_str();
+        let labels = [("plugin", name)];
+        let frames_in = registry().counter(
+            "sithra_host_frames_in_total",
+            "Frames received from a plugin.",
+            &labels,
+        );
+        let frames_out = registry().counter(
+            "sithra_host_frames_out_total",
+            "Frames sent to a plugin.",
+            &labels,
+        );
+        let restarts_total = registry().counter(
+            "sithra_host_plugin_restarts_total",
+            "Times a plugin was restarted.",
+            &labels,
+        );
+        let mut restarts = Restarts::new(self.config.restart.clone());
+        loop {
+            let depends_on = &self.config.depends_on;
+            if !depends_on.is_empty() {
+                log::info!("Plugin {name} waiting for {}", depends_on.join(", "));
+                let wait = self.routes.wait_ready(name, depends_on);
+                tokio::pin!(wait);
+                let timeout = Duration::from_secs(self.config.ready_timeout_secs);
+                while tokio::time::timeout(timeout, &mut wait).await.is_err() {
+                    let missing = self.routes.not_ready(depends_on).join(", ");
+                    log::warn!("Plugin {name} is still waiting for {missing}");
+                }
+            }
+            let started = Instant::now();
+            let success = match self.run_once(&frames_in, &frames_out).await {
+                Ok(status) if status.success() => {
+                    log::info!("Plugin {name} exited ({status})");
+                    true
+                }
+                Ok(status) => {
+                    log::error!("Plugin {name} exited ({status})");
+                    false
                 }
-            };
-            let init_package = init_datapack(config_data);
-            let raw = init_package.serialize_to_raw();
-            let raw = match raw {
-                Ok(raw) => raw,
                 Err(err) => {
-                    log::error!("Failed to serialize init package for {name}: {err}");
-                    continue;
+                    log::error!("Failed to start plugin {name}: {err}");
+                    false
                 }
             };
-            let join_handle1 = tokio::spawn(async move {
-                let mut write = write;
-                let mut broadcast_rx = broadcast_rx;
+            self.routes.set_stopped(name);
+            let Some(delay) = restarts.next(success, started.elapsed(), Instant::now()) else {
+                break;
+            };
+            log::info!("Restarting plugin {name} in {delay:?}");
+            tokio::time::sleep(delay).await;
+            restarts_total.inc();
+        }
+        if restarts.exhausted() {
+            log::error!("Plugin {name} restarted too often, giving up");
+        }
+        self.routes.remove(name);
+    }
+
+    /// Starts the plugin, and waits for it to exit.
+    async fn run_once(&self, frames_in: &Counter, frames_out: &Counter) -> io::Result<ExitStatus> {
+        let Self {
+            name,
+            config,
+            init,
+            routes,
+            metrics,
+            services,
+        } = self;
+        let (peer, mut child) = run(&config.path, name, config).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let acl = config.acl.clone();
+        routes
+            .insert(name, tx, config.paths.as_deref(), acl)
+            .map_err(io::Error::other)?;
 
-                let result = write.send(raw).await;
+        let (write, read) = split_peer(peer);
+        let init = init.borrow().clone();
+        let writer = spawn_writer(write, init, rx, frames_out.clone());
+        let reader = spawn_reader(
+            read,
+            name.clone(),
+            config.acl.clone(),
+            routes.clone(),
+            metrics.clone(),
+            services.clone(),
+            frames_in.clone(),
+        );
+        let _writer = AbortOnDrop(writer);
+        let mut reader = AbortOnDrop(reader);
+        let wait = child.wait();
+        tokio::pin!(wait);
+        let ready_timeout = Duration::from_secs(config.ready_timeout_secs);
+        let status = if let Ok(status) = tokio::time::timeout(ready_timeout, &mut wait).await {
+            status?
+        } else {
+            if routes.set_ready(name) {
+                log::warn!("Plugin {name} did not report ready in {ready_timeout:?}");
+            }
+            wait.await?
+        };
+        tokio::time::timeout(DRAIN_TIMEOUT, &mut reader.0).await.ok();
+        Ok(status)
+    }
+}
 
-                if let Err(err) = result {
-                    log::log!(log::Level::Error, "Failed to send init package {err}");
-                    return;
-                }
+/// Aborts a task when dropped, e.g. when its supervisor is aborted.
+struct AbortOnDrop(JoinHandle<()>);
 
-                while let Ok(data) = broadcast_rx.recv().await {
-                    if let Err(err) = write.send(data).await {
-                        log::log!(log::Level::Error, "Failed to send data {err}");
-                    }
-                }
-            });
-            let join_handle2 = tokio::spawn(async move {
-                let mut read = read;
-                let broadcast_tx = broadcast_tx;
-
-                while let Some(data) = read.next().await {
-                    match data {
-                        Ok(data) => {
-                            let Some(data) = map_log(data) else {
-                                continue;
-                            };
-                            let result = broadcast_tx.send(data);
-                            if result.is_err() {
-                                log::error!("Failed to broadcast data");
-                            }
-                        }
-                        Err(err) => {
-                            log::error!("Failed to read data: {err}");
-                        }
-                    }
-                }
-            });
-            self.join_map.insert(name.to_owned(), (join_handle1, join_handle2));
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Decides whether and when a plugin is restarted.
+struct Restarts {
+    config:    RestartConfig,
+    /// When the plugin was restarted within the window.
+    history:   VecDeque<Instant>,
+    /// The number of restarts since the plugin last ran for a whole window.
+    attempt:   u32,
+    exhausted: bool,
+}
+
+impl Restarts {
+    const fn new(config: RestartConfig) -> Self {
+        Self {
+            config,
+            history: VecDeque::new(),
+            attempt: 0,
+            exhausted: false,
         }
     }
 
-    pub fn abort(&mut self, name: &str) {
-        if let Some((join_handle1, join_handle2)) = self.join_map.remove(name) {
-            join_handle1.abort();
-            join_handle2.abort();
+    const fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Returns how long to wait before restarting a plugin that exited at
+    /// `now` after running for `ran_for`, or `None` to leave it stopped.
+    fn next(&mut self, success: bool, ran_for: Duration, now: Instant) -> Option<Duration> {
+        match self.config.policy {
+            RestartPolicy::Never => return None,
+            RestartPolicy::OnFailure if success => return None,
+            RestartPolicy::OnFailure | RestartPolicy::Always => {}
+        }
+        let window = Duration::from_secs(self.config.window_secs);
+        while self.history.front().is_some_and(|at| now.duration_since(*at) >= window) {
+            self.history.pop_front();
+        }
+        if self.history.len() >= self.config.max_restarts as usize {
+            self.exhausted = true;
+            return None;
         }
+        self.history.push_back(now);
+        if ran_for >= window {
+            self.attempt = 0;
+        }
+        let factor = 1u64.checked_shl(self.attempt).unwrap_or(u64::MAX);
+        let backoff = self.config.backoff_ms.saturating_mul(factor).min(self.config.backoff_max_ms);
+        self.attempt = self.attempt.saturating_add(1);
+        Some(Duration::from_millis(backoff))
     }
+}
 
-    pub fn abort_all(&mut self) {
-        for (_, (join_handle1, join_handle2)) in self.join_map.drain() {
-            join_handle1.abort();
-            join_handle2.abort();
+/// Orders the plugins so that each comes after its dependencies, and by name
+/// otherwise. Plugins that depend on an unknown plugin or on themselves are
+/// left out.
+fn start_order(config: &Config) -> Vec<&str> {
+    let mut names = config.iter().map(|(name, _)| name).collect::<Vec<_>>();
+    names.sort_unstable();
+    let mut order = Vec::with_capacity(names.len());
+    while let Some(index) = names.iter().position(|name| {
+        config.config[*name].depends_on.iter().all(|dep| order.contains(&dep.as_str()))
+    }) {
+        order.push(names.remove(index));
+    }
+    for name in names {
+        let unknown = config.config[name]
+            .depends_on
+            .iter()
+            .find(|dep| !config.config.contains_key(dep.as_str()));
+        match unknown {
+            Some(dep) => log::error!("Plugin {name} depends on unknown plugin {dep}"),
+            None => log::error!("Plugin {name} is part of a dependency cycle"),
         }
     }
+    order
 }
 
-fn run<P, I, S>(path: P, args: I) -> Result<Peer, io::Error>
-where
-    P: AsRef<OsStr>,
-    I: IntoIterator<Item = S>,
-    S: AsRef<OsStr>,
-{
-    let child = Command::new(path)
-        .args(args)
+/// Sends the init package, then the packs routed to the plugin.
+fn spawn_writer(
+    mut write: FramedWrite<Writer, DataPackCodec>,
+    raw: RawDataPack,
+    mut rx: UnboundedReceiver<DataPack>,
+    frames_out: Counter,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(err) = write.send(raw).await {
+            log::error!("Failed to send init package {err}");
+            return;
+        }
+
+        while let Some(data) = rx.recv().await {
+            if let Err(err) = write.send(data).await {
+                log::error!("Failed to send data {err}");
+            } else {
+                frames_out.inc();
+            }
+        }
+    })
+}
+
+/// Handles the packs meant for the host, and routes the others that `acl`
+/// lets the plugin send.
+fn spawn_reader(
+    mut read: FramedRead<Reader, DataPackCodec>,
+    plugin: String,
+    acl: AclConfig,
+    routes: Arc<Routes>,
+    metrics: Arc<HostMetrics>,
+    services: UnboundedSender<HostRequest>,
+    frames_in: Counter,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(data) = read.next().await {
+            match data {
+                Ok(data) => {
+                    frames_in.inc();
+                    let data = map_log(data)
+                        .and_then(map_span)
+                        .and_then(|data| map_metrics(&plugin, &metrics, &routes, data))
+                        .and_then(|data| map_register(&plugin, &routes, data))
+                        .and_then(|data| map_ready(&plugin, &routes, data));
+                    if let Some(data) = data {
+                        dispatch(&plugin, &acl, &routes, &services, data);
+                    }
+                }
+                Err(err) => {
+                    log::error!("Failed to read data: {err}");
+                }
+            }
+        }
+    })
+}
+
+/// Routes `data` from `plugin` to the other plugins or to the host services,
+/// or answers it with an error if `acl` denies it.
+fn dispatch(
+    plugin: &str,
+    acl: &AclConfig,
+    routes: &Routes,
+    services: &UnboundedSender<HostRequest>,
+    mut data: DataPack,
+) {
+    let _span = route_span(plugin, &mut data);
+    let key = data.correlation();
+    if let Err(err) = acl.check_send(&data) {
+        let path = data.path.as_deref().unwrap_or_default();
+        log::warn!(target: AUDIT_TARGET, "Denied {path} from {plugin}: {err}");
+        routes.send(
+            plugin,
+            DataPack::builder().correlate(key).build_with_error(&err),
+        );
+        return;
+    }
+    let Some(path) = data.path.clone().filter(|path| path.starts_with(services::PREFIX)) else {
+        routes.dispatch(plugin, data);
+        return;
+    };
+    let request = HostRequest {
+        plugin: plugin.to_owned(),
+        data,
+    };
+    if services.send(request).is_err() {
+        let error = RequestError::NoHandler(path);
+        routes.send(
+            plugin,
+            DataPack::builder().correlate(key).build_with_error(&error),
+        );
+    }
+}
+
+/// Opens the host's span for routing the request `data` if it carries a trace
+/// context, and makes it the parent of the spans handling the request.
+fn route_span(plugin: &str, data: &mut DataPack) -> Option<tracing::span::EnteredSpan> {
+    let path = data.path.as_deref()?;
+    let parent = data.trace.as_ref()?;
+    let context = parent.child();
+    let span = tracing::info_span!(
+        "route",
+        path,
+        from = plugin,
+        trace_id = %context.trace_id,
+        span_id = %context.span_id,
+        parent_span_id = %parent.span_id,
+    )
+    .entered();
+    data.trace = Some(context);
+    Some(span)
+}
+
+/// Starts the plugin `name` with its process settings.
+async fn run(program: &Path, name: &str, config: &BaseConfig) -> Result<(Peer, Child), io::Error> {
+    let mut command = Command::new(program);
+    if let Some(data_dir) = &config.data_dir {
+        std::fs::create_dir_all(data_dir)?;
+        command.env(DATA_DIR_ENV, data_dir);
+    }
+    command
+        .args(&config.args)
+        .envs(&config.env)
         .kill_on_drop(true)
         .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()?;
-    Ok(Peer::from_child(child).expect(
-        "If you see this message, it means that the child process failed convert to a peer. THIS \
-         IS A BUG, PLEASE REPORT IT",
-    ))
+        .stdout(Stdio::piped());
+    if let Some(cwd) = &config.cwd {
+        command.current_dir(cwd);
+    }
+    if config.stderr == Stderr::Inherit {
+        command.stderr(Stdio::inherit());
+    } else {
+        command.stderr(Stdio::piped());
+    }
+    let mut child = command.spawn()?;
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        unreachable!("the standard I/O of the child process is piped");
+    };
+    if let Some(stderr) = child.stderr.take() {
+        stderr::capture(name, stderr, &config.stderr).await?;
+    }
+    Ok((Peer::from_pipes(stdin, stdout), child))
 }
 
 fn split_peer(
@@ -191,3 +772,298 @@ fn map_log(data: DataPack) -> Option<DataPack> {
 
     None
 }
+
+fn map_span(data: DataPack) -> Option<DataPack> {
+    let is_span = data.path.as_ref().is_some_and(|v| v == "/span.create");
+    if !is_span {
+        return Some(data);
+    }
+
+    let Ok(span) = data.payload::<Span>() else {
+        return Some(data);
+    };
+
+    let Span {
+        name,
+        target,
+        level,
+        trace,
+        parent_span_id,
+        fields,
+        duration,
+        ..
+    } = span;
+
+    let fields = fields.iter().fold(String::new(), |mut acc, (key, value)| {
+        acc.push(' ');
+        acc.push_str(key);
+        acc.push('=');
+        acc.push_str(value);
+        acc
+    });
+    let parent_span_id = parent_span_id.as_deref().unwrap_or("-");
+
+    log::log!(
+        target: target.as_str(),
+        level,
+        "span {name} closed in {duration}us trace_id={} span_id={} \
+         parent_span_id={parent_span_id}{fields}",
+        trace.trace_id,
+        trace.span_id,
+    );
+
+    None
+}
+
+fn map_metrics(
+    plugin: &str,
+    metrics: &HostMetrics,
+    routes: &Routes,
+    data: DataPack,
+) -> Option<DataPack> {
+    match data.path.as_deref() {
+        Some(path) if path == MetricsReport::path() => {
+            let Ok(report) = data.payload::<MetricsReport>() else {
+                return Some(data);
+            };
+            metrics.report(plugin, report.families);
+            None
+        }
+        Some(path) if path == GetMetrics::path() => {
+            let response = DataPack::builder()
+                .correlate(data.correlation())
+                .build_with_payload(metrics.render());
+            routes.send(plugin, response);
+            None
+        }
+        _ => Some(data),
+    }
+}
+
+fn map_ready(plugin: &str, routes: &Routes, data: DataPack) -> Option<DataPack> {
+    let is_ready = data.path.as_ref().is_some_and(|v| v == Ready::path());
+    if !is_ready {
+        return Some(data);
+    }
+
+    if routes.set_ready(plugin) {
+        log::info!("Plugin {plugin} is ready");
+    }
+
+    None
+}
+
+fn map_register(plugin: &str, routes: &Routes, data: DataPack) -> Option<DataPack> {
+    let is_register = data.path.as_ref().is_some_and(|v| v == Register::path());
+    if !is_register {
+        return Some(data);
+    }
+
+    let Ok(Register { paths }) = data.payload::<Register>() else {
+        return Some(data);
+    };
+
+    if let Err(err) = routes.register(plugin, &paths) {
+        log::error!("Invalid paths registered by {plugin}: {err}");
+    }
+
+    None
+}
+
+#[cfg(test)]
+impl Loader {
+    /// A loader of the plugins in the TOML `plugins`, with the plugin `name`
+    /// connected and ready to receive `paths`.
+    ///
+    /// Returns the loader and the packs routed to `name`.
+    pub(crate) fn connected(
+        plugins: &str,
+        name: &str,
+        paths: Option<&[String]>,
+    ) -> (Arc<tokio::sync::Mutex<Self>>, UnboundedReceiver<DataPack>) {
+        let loader = Self::new(Config::plugins(plugins));
+        let (tx, rx) = mpsc::unbounded_channel();
+        loader.routes.insert(name, tx, paths, AclConfig::default()).unwrap();
+        loader.routes.set_ready(name);
+        (Arc::new(tokio::sync::Mutex::new(loader)), rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use sithra_kit::transport::{datapack::DataPack, trace::TraceContext};
+
+    use super::{Changes, Restarts, route_span, start_order};
+    use crate::conf::{Config, RestartConfig, RestartPolicy};
+
+    fn restarts(policy: RestartPolicy) -> Restarts {
+        Restarts::new(RestartConfig {
+            policy,
+            max_restarts: 3,
+            window_secs: 60,
+            backoff_ms: 100,
+            backoff_max_ms: 300,
+        })
+    }
+
+    #[test]
+    fn policy() {
+        let now = Instant::now();
+        let short = Duration::from_secs(1);
+        assert_eq!(restarts(RestartPolicy::Never).next(false, short, now), None);
+        assert_eq!(
+            restarts(RestartPolicy::OnFailure).next(true, short, now),
+            None
+        );
+        assert!(restarts(RestartPolicy::OnFailure).next(false, short, now).is_some());
+        assert!(restarts(RestartPolicy::Always).next(true, short, now).is_some());
+    }
+
+    #[test]
+    fn route() {
+        let parent = TraceContext::new_root();
+        let mut request = DataPack::builder().path(&"/get").trace(parent.clone()).build();
+        assert!(route_span("caller", &mut request).is_some());
+        let trace = request.trace.unwrap();
+        assert_eq!(trace.trace_id, parent.trace_id);
+        assert_ne!(trace.span_id, parent.span_id);
+
+        let mut response = DataPack::builder().trace(parent.clone()).build();
+        assert!(route_span("handler", &mut response).is_none());
+        assert_eq!(response.trace, Some(parent));
+        let mut untraced = DataPack::builder().path(&"/get").build();
+        assert!(route_span("caller", &mut untraced).is_none());
+    }
+
+    #[test]
+    fn backoff_and_limit() {
+        let mut restarts = restarts(RestartPolicy::Always);
+        let start = Instant::now();
+        let short = Duration::from_secs(1);
+        let delays = (0..4)
+            .map(|i| restarts.next(false, short, start + Duration::from_secs(i)))
+            .collect::<Vec<_>>();
+        let ms = Duration::from_millis;
+        assert_eq!(delays, [Some(ms(100)), Some(ms(200)), Some(ms(300)), None]);
+        assert!(restarts.exhausted());
+
+        // Once the window has passed, the plugin may restart again, and the
+        // backoff is reset after a long run.
+        let later = start + Duration::from_secs(70);
+        assert_eq!(
+            restarts.next(false, Duration::from_mins(2), later),
+            Some(ms(100))
+        );
+    }
+
+    #[test]
+    fn order() {
+        let config = Config::plugins(
+            r#"
+            [echo]
+            path = "echo"
+            depends-on = ["onebot"]
+            [onebot]
+            path = "onebot"
+            [cycle-a]
+            path = "a"
+            depends-on = ["cycle-b"]
+            [cycle-b]
+            path = "b"
+            depends-on = ["cycle-a"]
+            [orphan]
+            path = "orphan"
+            depends-on = ["missing"]
+            [admin]
+            path = "admin"
+            depends-on = ["echo", "onebot"]
+            "#,
+        );
+        assert_eq!(start_order(&config), ["onebot", "echo", "admin"]);
+    }
+
+    #[test]
+    fn changes() {
+        let old = Config::plugins(
+            r#"
+            [echo]
+            path = "echo"
+            config = { prefix = "echo" }
+            [onebot]
+            path = "onebot"
+            args = ["--debug"]
+            [stale]
+            path = "stale"
+            "#,
+        );
+        let new = Config::plugins(
+            r#"
+            [echo]
+            path = "echo"
+            config = { prefix = "say" }
+            [onebot]
+            path = "onebot"
+            [added]
+            path = "added"
+            "#,
+        );
+        assert_eq!(
+            Changes::new(&old, &new),
+            Changes {
+                removed:   vec!["stale".to_owned()],
+                restarted: vec!["onebot".to_owned()],
+                updated:   vec!["echo".to_owned()],
+            }
+        );
+        assert_eq!(Changes::new(&new, &new), Changes::default());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn supervisor() {
+        use std::sync::Arc;
+
+        use ahash::HashMap;
+        use tokio::sync::{mpsc, watch};
+
+        use super::{Supervisor, init_datapack};
+        use crate::conf::{AclConfig, BaseConfig, Stderr};
+
+        let log = std::env::temp_dir().join(format!("sithra-supervisor-{}", std::process::id()));
+        let script = format!("echo run >> {}; exit 3", log.display());
+        let (_, init) = watch::channel(init_datapack(rmpv::Value::Nil).serialize_to_raw().unwrap());
+        let supervisor = Supervisor {
+            name: "supervised".to_owned(),
+            config: BaseConfig {
+                path:               "/bin/sh".into(),
+                args:               vec!["-c".to_owned(), script],
+                paths:              None,
+                restart:            RestartConfig {
+                    policy:         RestartPolicy::OnFailure,
+                    max_restarts:   2,
+                    window_secs:    60,
+                    backoff_ms:     1,
+                    backoff_max_ms: 1,
+                },
+                depends_on:         Vec::new(),
+                ready_timeout_secs: 30,
+                env:                HashMap::default(),
+                cwd:                None,
+                data_dir:           None,
+                stderr:             Stderr::Inherit,
+                acl:                AclConfig::default(),
+                config:             None,
+            },
+            init,
+            routes: Arc::default(),
+            metrics: Arc::default(),
+            services: mpsc::unbounded_channel().0,
+        };
+        tokio::time::timeout(Duration::from_secs(10), supervisor.run()).await.unwrap();
+        let runs = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_file(&log).unwrap();
+        assert_eq!(runs.lines().count(), 3);
+    }
+}
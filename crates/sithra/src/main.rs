@@ -1,10 +1,90 @@
-use sithra::{conf, loader};
-use tokio::signal;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use clap::{Parser, Subcommand};
+use sithra::{
+    conf::{ConfigSource, ConfigWatcher},
+    control::{self, Request, Response},
+    loader, metrics, services,
+};
+use tokio::{signal, sync::Mutex};
+
+/// How often `config.toml` is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Runs the bot, or manages a running one.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// The config file, instead of `SITHRA_CONFIG` or `config.toml` next to
+    /// the executable.
+    #[arg(long, global = true)]
+    config:  Option<PathBuf>,
+    /// The profile layered over the config file, e.g. `prod` for
+    /// `config.prod.toml`, instead of `SITHRA_PROFILE`.
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// The control socket of the host, instead of the one in the config.
+    #[arg(long, global = true)]
+    socket:  Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the host and its plugins (the default).
+    Run,
+    /// Shows the status of the running host.
+    Status,
+    /// Lists the plugins of the running host.
+    Plugins {
+        #[command(subcommand)]
+        command: PluginsCommand,
+    },
+    /// Manages a plugin of the running host.
+    Plugin {
+        #[command(subcommand)]
+        command: PluginCommand,
+    },
+    /// Sends a request to the plugins of the running host and prints the
+    /// responses.
+    Send {
+        path:    String,
+        /// The payload, as JSON.
+        #[arg(default_value = "null")]
+        payload: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum PluginsCommand {
+    /// Lists the configured plugins and their state.
+    List,
+}
+
+#[derive(Subcommand)]
+enum PluginCommand {
+    /// Starts a stopped plugin.
+    Start { name: String },
+    /// Stops a plugin until it is started again.
+    Stop { name: String },
+    /// Restarts a plugin.
+    Restart { name: String },
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let source = ConfigSource::resolve(cli.config, cli.profile)?;
+    match cli.command {
+        None | Some(Command::Run) => run(&source, cli.socket).await,
+        Some(command) => manage(&source, cli.socket, command).await,
+    }
+}
+
+async fn run(source: &ConfigSource, socket: Option<PathBuf>) -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
-    let config = conf::load_config();
+    let config = source.load();
     let config = match config {
         Ok(config) => config,
         Err(err) => {
@@ -12,11 +92,126 @@ async fn main() -> anyhow::Result<()> {
             return Err(err.into());
         }
     };
+    let metrics_addr = config.host.metrics_addr;
+    let socket = match socket {
+        Some(socket) => socket,
+        None => config.host.control_socket_path(&source.dir()?),
+    };
     let mut loader = loader::Loader::new(config);
     loader.load();
+    if let Some(addr) = metrics_addr {
+        let metrics = loader.metrics();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr, metrics).await {
+                log::error!("Failed to serve metrics: {err}");
+            }
+        });
+    }
+    let requests = loader.host_requests().expect("host requests are only taken here");
+    let loader = Arc::new(Mutex::new(loader));
+    tokio::spawn(services::serve(requests, loader.clone()));
+    let control = tokio::spawn({
+        let loader = loader.clone();
+        let socket = socket.clone();
+        async move {
+            if let Err(err) = control::serve(&socket, loader).await {
+                log::error!("Failed to serve control socket: {err}");
+            }
+        }
+    });
+
+    let mut watcher = ConfigWatcher::new(source.paths(), RELOAD_INTERVAL);
+    loop {
+        tokio::select! {
+            result = signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            () = watcher.changed() => match source.load() {
+                Ok(config) => {
+                    log::info!("Reloading config");
+                    loader.lock().await.reload(config).await;
+                }
+                Err(err) => log::error!("Failed to reload config: {err}"),
+            },
+        }
+    }
 
-    signal::ctrl_c().await?;
+    control.abort();
+    // The socket is only ours if it was still being served.
+    if control.await.is_err_and(|err| err.is_cancelled()) {
+        std::fs::remove_file(&socket).ok();
+    }
+    loader.lock().await.abort_all();
+    Ok(())
+}
 
-    loader.abort_all();
+/// Sends `command` to the running host and prints the result.
+async fn manage(
+    source: &ConfigSource,
+    socket: Option<PathBuf>,
+    command: Command,
+) -> anyhow::Result<()> {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => source
+            .load()
+            .map(|config| config.host)
+            .unwrap_or_default()
+            .control_socket_path(&source.dir()?),
+    };
+    let request = match command {
+        Command::Run => unreachable!("the host is run by main"),
+        Command::Status => Request::Status,
+        Command::Plugins {
+            command: PluginsCommand::List,
+        } => Request::Plugins,
+        Command::Plugin { command } => match command {
+            PluginCommand::Start { name } => Request::Start { name },
+            PluginCommand::Stop { name } => Request::Stop { name },
+            PluginCommand::Restart { name } => Request::Restart { name },
+        },
+        Command::Send { path, payload } => Request::Send {
+            path,
+            payload: serde_json::from_str(&payload)?,
+        },
+    };
+    let response = control::request(&socket, &request).await.map_err(|err| {
+        anyhow::anyhow!("Failed to reach the host at {}: {err}", socket.display())
+    })?;
+    match response {
+        Response::Status(status) => {
+            let uptime = status.uptime_secs;
+            println!("sithra {} (pid {})", status.version, status.pid);
+            println!(
+                "Uptime: {}h {}m {}s",
+                uptime / 3600,
+                uptime / 60 % 60,
+                uptime % 60
+            );
+            println!("Plugins: {}/{} running", status.running, status.plugins);
+        }
+        Response::Plugins { plugins } => {
+            let width = plugins.iter().map(|plugin| plugin.name.len()).max().unwrap_or(0);
+            for plugin in plugins {
+                let state = format!("{:?}", plugin.state).to_lowercase();
+                println!(
+                    "{:width$}  {state:8}  {}",
+                    plugin.name,
+                    plugin.path.display()
+                );
+            }
+        }
+        Response::Done => {}
+        Response::Sent { responses } => {
+            if responses.is_empty() {
+                eprintln!("No response within {:?}", control::SEND_TIMEOUT);
+            }
+            for response in responses {
+                println!("{}", serde_json::to_string_pretty(&response)?);
+            }
+        }
+        Response::Error { message } => anyhow::bail!(message),
+    }
     Ok(())
 }
@@ -0,0 +1,96 @@
+//! Aggregates the metrics of the host and its plugins.
+//!
+//! Plugins report snapshots of their registry with `/metrics.report`. The
+//! latest snapshot of every plugin is kept, labelled with `plugin`, and
+//! rendered together with the host's own metrics in the Prometheus text
+//! format, either over HTTP or in response to `/host/metrics`.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use ahash::HashMap;
+use sithra_kit::server::metrics::{self, MetricFamily};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Default)]
+pub struct HostMetrics {
+    plugins: Mutex<HashMap<String, Vec<MetricFamily>>>,
+}
+
+impl HostMetrics {
+    /// Replaces the snapshot of plugin `name`.
+    pub fn report(&self, name: &str, mut families: Vec<MetricFamily>) {
+        for family in &mut families {
+            for sample in &mut family.samples {
+                sample.labels.push(("plugin".to_owned(), name.to_owned()));
+            }
+        }
+        self.plugins
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.to_owned(), families);
+    }
+
+    /// Forgets the snapshot of plugin `name`.
+    pub fn remove(&self, name: &str) {
+        self.plugins.lock().unwrap_or_else(PoisonError::into_inner).remove(name);
+    }
+
+    /// Renders the metrics of the host and all plugins.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let host = metrics::registry().snapshot();
+        let plugins = self.plugins.lock().unwrap_or_else(PoisonError::into_inner);
+        metrics::render(host.iter().chain(plugins.values().flatten()))
+    }
+}
+
+/// Serves the metrics on `addr` over HTTP, at `/metrics`.
+///
+/// # Errors
+/// Returns an error if `addr` cannot be bound.
+pub async fn serve(addr: SocketAddr, metrics: Arc<HostMetrics>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving metrics on http://{addr}/metrics");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &metrics).await {
+                log::debug!("Failed to serve metrics: {err}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &HostMetrics) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let request_line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let response = if method == Some(b"GET") && path == Some(b"/metrics") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+             {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,718 @@
+//! Routing of data packs between plugins.
+//!
+//! Requests go to the plugins that handle their path, and responses go back
+//! to the plugin that sent the request. A caller cancelling a streamed
+//! response reaches only the plugin streaming it. Plugins declare their paths
+//! with [`Register`](sithra_kit::types::host::Register) or the `paths` of their
+//! configuration; a plugin that declares nothing receives every request.
+//! Nothing is sent back to the plugin it came from, nor to a plugin its
+//! [`AclConfig`] does not let receive it. Only the plugins a request was
+//! routed to may answer it.
+//!
+//! Requests for a plugin that has not reported ready yet are queued until it
+//! does, up to [`MAX_QUEUED`] of them; the requests beyond are rejected. For
+//! requests whose sender awaits a reply, the host answers with an error itself
+//! when no plugin handles the path, or when no response arrives before the
+//! timeout; the responses to other requests are still routed back to their
+//! sender until then.
+//!
+//! The host also learns the bots from the events (`/event/...` requests) the
+//! plugins send with a `bot_id`.
+
+use std::{
+    mem,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use ahash::{HashMap, HashSet};
+use matchit::InsertError;
+use sithra_kit::{
+    transport::datapack::{DataPack, StreamFlag},
+    types::host::BotInfo,
+};
+use thiserror::Error;
+use tokio::sync::{Notify, mpsc::UnboundedSender};
+use ulid::Ulid;
+
+use crate::{acl::AUDIT_TARGET, conf::AclConfig};
+
+/// How long a request waits for its response unless configured otherwise.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many requests are held at most for a plugin that is not ready.
+pub const MAX_QUEUED: usize = 1024;
+
+/// The errors the host answers requests with.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RequestError {
+    #[error("No plugin handles {0}")]
+    NoHandler(String),
+    #[error("Request for {path} timed out after {timeout:?}")]
+    Timeout { path: String, timeout: Duration },
+    #[error("Request for {path} rejected: too many requests are held for {plugin}")]
+    QueueFull { path: String, plugin: String },
+}
+
+pub struct Routes {
+    inner:   Mutex<Inner>,
+    /// Notified when a plugin becomes ready.
+    ready:   Notify,
+    /// How long a request waits for its response, or for the next item of a
+    /// streamed response.
+    timeout: Duration,
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Self::new(DEFAULT_REQUEST_TIMEOUT)
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    plugins: HashMap<String, Plugin>,
+    /// The requests still waiting for a response.
+    pending: HashMap<Ulid, Pending>,
+    /// The plugin that sent the last event of each bot.
+    bots:    HashMap<String, String>,
+    /// The plugins waiting for their dependencies.
+    waiting: HashSet<String>,
+}
+
+struct Plugin {
+    tx:    UnboundedSender<DataPack>,
+    paths: Option<matchit::Router<()>>,
+    /// Whether `paths` come from the configuration, so the plugin cannot
+    /// change them.
+    fixed: bool,
+    /// Which requests the plugin may receive.
+    acl:   AclConfig,
+    ready: bool,
+    /// Requests held until the plugin is ready.
+    queue: Vec<DataPack>,
+}
+
+impl Plugin {
+    fn handles(&self, path: &str) -> bool {
+        self.paths.as_ref().is_none_or(|paths| paths.at(path).is_ok())
+    }
+
+    /// Sends the request `data`, or holds it until the plugin is ready.
+    /// Returns `false` if [`MAX_QUEUED`] requests are held already.
+    fn send_request(&mut self, data: DataPack) -> bool {
+        if self.ready {
+            self.tx.send(data).ok();
+        } else if self.queue.len() < MAX_QUEUED {
+            self.queue.push(data);
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+struct Pending {
+    /// The plugin that sent the request.
+    plugin:    String,
+    path:      String,
+    at:        Instant,
+    /// The plugins the request was routed to, the only ones that may
+    /// respond.
+    targets:   Vec<String>,
+    /// The plugin streaming the response, once its first item arrived.
+    responder: Option<String>,
+    /// Whether the plugin awaits the response, so it is told when none
+    /// arrives.
+    awaits:    bool,
+}
+
+fn matcher<T: AsRef<str>>(paths: &[T]) -> Result<matchit::Router<()>, InsertError> {
+    let mut matcher = matchit::Router::new();
+    for path in paths {
+        matcher.insert(path.as_ref(), ())?;
+    }
+    Ok(matcher)
+}
+
+impl Routes {
+    /// Creates routes where requests wait `timeout` for their response.
+    #[must_use]
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            inner: Mutex::default(),
+            ready: Notify::new(),
+            timeout,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds the plugin `name`, which receives its packs through `tx` once it
+    /// is ready. Requests held for a plugin of the same name are kept.
+    ///
+    /// If `paths` is given, the plugin only receives requests for them and
+    /// cannot register others. Of those, it only receives the ones `acl`
+    /// lets it.
+    ///
+    /// # Errors
+    /// Returns an error if one of `paths` is not a valid route pattern.
+    pub fn insert(
+        &self,
+        name: &str,
+        tx: UnboundedSender<DataPack>,
+        paths: Option<&[String]>,
+        acl: AclConfig,
+    ) -> Result<(), InsertError> {
+        let paths = paths.map(matcher).transpose()?;
+        let mut inner = self.lock();
+        let queue = inner.plugins.remove(name).map(|plugin| plugin.queue).unwrap_or_default();
+        let plugin = Plugin {
+            tx,
+            fixed: paths.is_some(),
+            paths,
+            acl,
+            ready: false,
+            queue,
+        };
+        inner.plugins.insert(name.to_owned(), plugin);
+        drop(inner);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) {
+        let mut inner = self.lock();
+        inner.plugins.remove(name);
+        inner.bots.retain(|_, plugin| plugin != name);
+    }
+
+    /// Returns the bots the plugins sent events for, by id.
+    #[must_use]
+    pub fn bots(&self) -> Vec<BotInfo> {
+        let inner = self.lock();
+        let mut bots = inner
+            .bots
+            .iter()
+            .map(|(id, plugin)| BotInfo {
+                id:     id.clone(),
+                plugin: plugin.clone(),
+            })
+            .collect::<Vec<_>>();
+        drop(inner);
+        bots.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        bots
+    }
+
+    /// Sets the paths the plugin `name` handles, unless they are fixed by its
+    /// configuration.
+    ///
+    /// # Errors
+    /// Returns an error if one of `paths` is not a valid route pattern.
+    pub fn register(&self, name: &str, paths: &[String]) -> Result<(), InsertError> {
+        let matcher = matcher(paths)?;
+        let mut inner = self.lock();
+        if let Some(plugin) = inner.plugins.get_mut(name)
+            && !plugin.fixed
+        {
+            plugin.paths = Some(matcher);
+        }
+        drop(inner);
+        Ok(())
+    }
+
+    /// Marks the plugin `name` as ready and sends it the requests held for
+    /// it. Returns `false` if it was already ready.
+    pub fn set_ready(&self, name: &str) -> bool {
+        let mut inner = self.lock();
+        let Some(plugin) = inner.plugins.get_mut(name) else {
+            return false;
+        };
+        if mem::replace(&mut plugin.ready, true) {
+            return false;
+        }
+        for data in plugin.queue.drain(..) {
+            plugin.tx.send(data).ok();
+        }
+        drop(inner);
+        self.ready.notify_waiters();
+        true
+    }
+
+    /// Holds the requests for the plugin `name` until it is ready again,
+    /// e.g. while it restarts.
+    pub fn set_stopped(&self, name: &str) {
+        if let Some(plugin) = self.lock().plugins.get_mut(name) {
+            plugin.ready = false;
+        }
+    }
+
+    #[must_use]
+    pub fn is_ready(&self, name: &str) -> bool {
+        self.lock().plugins.get(name).is_some_and(|plugin| plugin.ready)
+    }
+
+    /// Returns those of the plugins `names` that are not ready.
+    #[must_use]
+    pub fn not_ready(&self, names: &[String]) -> Vec<String> {
+        let inner = self.lock();
+        names
+            .iter()
+            .filter(|name| !inner.plugins.get(*name).is_some_and(|plugin| plugin.ready))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns whether the plugin `name` is in [`Routes::wait_ready`].
+    #[must_use]
+    pub fn is_waiting(&self, name: &str) -> bool {
+        self.lock().waiting.contains(name)
+    }
+
+    /// Waits until all the plugins `names` are ready, with the plugin
+    /// `waiting` marked as waiting for them meanwhile.
+    pub async fn wait_ready(&self, waiting: &str, names: &[String]) {
+        let _waiting = Waiting::new(self, waiting);
+        loop {
+            let notified = self.ready.notified();
+            let ready = {
+                let inner = self.lock();
+                names
+                    .iter()
+                    .all(|name| inner.plugins.get(name).is_some_and(|plugin| plugin.ready))
+            };
+            if ready {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Sends `data` to the plugin `name`, even if it is not ready or its ACL
+    /// would not let it receive `data`.
+    ///
+    /// This is for the packs of the host itself.
+    pub fn send(&self, name: &str, data: DataPack) {
+        let inner = self.lock();
+        if let Some(plugin) = inner.plugins.get(name) {
+            plugin.tx.send(data).ok();
+        }
+    }
+
+    /// Routes `data` from the plugin `from`.
+    pub fn dispatch(&self, from: &str, data: DataPack) {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        let key = data.correlation();
+        if let Some(path) = data.path.clone() {
+            if let Some(bot_id) = &data.bot_id
+                && path.starts_with("/event/")
+            {
+                inner.bots.insert(bot_id.clone(), from.to_owned());
+            }
+            let candidates = inner
+                .plugins
+                .iter_mut()
+                .filter(|(name, plugin)| *name != from && plugin.handles(&path));
+            let mut targets = Vec::new();
+            let mut full = None;
+            for (name, plugin) in candidates {
+                if !plugin.acl.may_receive(&data) {
+                    let reason = "not allowed to receive it";
+                    log::info!(target: AUDIT_TARGET, "Withheld {path} from {name}: {reason}");
+                    continue;
+                }
+                if !plugin.send_request(data.clone()) {
+                    log::warn!(
+                        "Rejected {path} from {from}: {MAX_QUEUED} requests are held for {name}"
+                    );
+                    full = Some(name.clone());
+                    continue;
+                }
+                targets.push(name.clone());
+            }
+            if targets.is_empty() {
+                let error = full.map_or_else(
+                    || {
+                        log::debug!("No plugin handles {path} from {from}");
+                        RequestError::NoHandler(path.clone())
+                    },
+                    |plugin| RequestError::QueueFull {
+                        path: path.clone(),
+                        plugin,
+                    },
+                );
+                if data.awaits_reply {
+                    inner.reply(from, key, &error);
+                }
+            } else {
+                let plugin = from.to_owned();
+                inner.pending.insert(
+                    key,
+                    Pending {
+                        plugin,
+                        path,
+                        at: now,
+                        targets,
+                        responder: None,
+                        awaits: data.awaits_reply,
+                    },
+                );
+            }
+            return;
+        }
+        if data.stream == Some(StreamFlag::Cancel) {
+            if inner.pending.get(&key).is_none_or(|pending| pending.plugin != from) {
+                log::debug!("Dropping cancel {key} from {from}: no pending request");
+                return;
+            }
+            let Some(pending) = inner.pending.remove(&key) else {
+                return;
+            };
+            // Before the first item, any of the targets may be streaming.
+            let targets = pending.responder.map_or(pending.targets, |name| vec![name]);
+            for plugin in targets.iter().filter_map(|name| inner.plugins.get(name)) {
+                plugin.tx.send(data.clone()).ok();
+            }
+            return;
+        }
+        let Some(pending) = inner.pending.get_mut(&key) else {
+            log::debug!("Dropping response {key} from {from}: no pending request");
+            return;
+        };
+        if !pending.targets.iter().any(|name| name == from) {
+            let path = &pending.path;
+            log::warn!(
+                target: AUDIT_TARGET,
+                "Denied response {key} for {path} from {from}: the request was not routed to it"
+            );
+            return;
+        }
+        let plugin = if data.stream == Some(StreamFlag::Item) {
+            pending.at = now;
+            pending.responder.get_or_insert_with(|| from.to_owned());
+            pending.plugin.clone()
+        } else {
+            let Some(pending) = inner.pending.remove(&key) else {
+                return;
+            };
+            pending.plugin
+        };
+        if let Some(plugin) = inner.plugins.get(&plugin) {
+            plugin.tx.send(data).ok();
+        }
+    }
+
+    /// Forgets the requests that have waited for the timeout at `now`, and
+    /// answers those whose sender awaits a reply with an error.
+    pub fn expire(&self, now: Instant) {
+        let timeout = self.timeout;
+        let mut inner = self.lock();
+        let mut expired = Vec::new();
+        inner.pending.retain(|key, pending| {
+            let waiting = now.saturating_duration_since(pending.at) < timeout;
+            if !waiting && pending.awaits {
+                expired.push((
+                    *key,
+                    mem::take(&mut pending.plugin),
+                    mem::take(&mut pending.path),
+                ));
+            }
+            waiting
+        });
+        for (key, plugin, path) in expired {
+            log::debug!("Request {key} for {path} from {plugin} timed out");
+            inner.reply(&plugin, key, &RequestError::Timeout { path, timeout });
+        }
+    }
+
+    /// Expires the requests that time out, until dropped.
+    pub async fn expire_pending(&self) {
+        let mut interval = tokio::time::interval(self.timeout.min(Duration::from_secs(1)));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.expire(Instant::now());
+        }
+    }
+}
+
+/// Marks a plugin as waiting for its dependencies while alive.
+struct Waiting<'a> {
+    routes: &'a Routes,
+    name:   &'a str,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(routes: &'a Routes, name: &'a str) -> Self {
+        routes.lock().waiting.insert(name.to_owned());
+        Self { routes, name }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.routes.lock().waiting.remove(self.name);
+    }
+}
+
+impl Inner {
+    /// Answers the request `key` of the plugin `to` with `error`.
+    fn reply(&self, to: &str, key: Ulid, error: &RequestError) {
+        if let Some(plugin) = self.plugins.get(to) {
+            let data = DataPack::builder().correlate(key).build_with_error(error);
+            plugin.tx.send(data).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sithra_kit::transport::datapack::{DataPack, StreamFlag};
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use super::{MAX_QUEUED, RequestError, Routes};
+    use crate::conf::AclConfig;
+
+    fn plugin(
+        routes: &Routes,
+        name: &str,
+        paths: Option<&[String]>,
+    ) -> UnboundedReceiver<DataPack> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        routes.insert(name, tx, paths, AclConfig::default()).unwrap();
+        routes.set_ready(name);
+        rx
+    }
+
+    fn paths(rx: &mut UnboundedReceiver<DataPack>) -> Vec<Option<String>> {
+        std::iter::from_fn(|| rx.try_recv().ok()).map(|data| data.path).collect()
+    }
+
+    #[test]
+    fn requests() {
+        let routes = Routes::default();
+        let mut adapter = plugin(&routes, "adapter", None);
+        let mut echo = plugin(&routes, "echo", None);
+        let mut fixed = plugin(&routes, "fixed", Some(&["/command/{name}".to_owned()]));
+        routes.register("echo", &["/message".to_owned()]).unwrap();
+        routes.register("fixed", &["/message".to_owned()]).unwrap();
+
+        let message = DataPack::builder().path(&"/message").build();
+        routes.dispatch("adapter", message);
+        let command = DataPack::builder().path(&"/command/help").build();
+        routes.dispatch("adapter", command);
+        let reply = DataPack::builder().path(&"/message.create").build();
+        routes.dispatch("echo", reply);
+
+        assert_eq!(paths(&mut adapter), [Some("/message.create".to_owned())]);
+        assert_eq!(paths(&mut echo), [Some("/message".to_owned())]);
+        assert_eq!(paths(&mut fixed), [Some("/command/help".to_owned())]);
+    }
+
+    #[test]
+    fn responses() {
+        let routes = Routes::default();
+        let mut caller = plugin(&routes, "caller", None);
+        let mut other = plugin(&routes, "other", None);
+        let mut handler = plugin(&routes, "handler", Some(&["/get".to_owned()]));
+
+        let request = DataPack::builder().path(&"/get").awaits_reply().build();
+        let key = request.correlation();
+        routes.dispatch("caller", request);
+        assert_eq!(paths(&mut other).len(), 1);
+        assert_eq!(paths(&mut handler).len(), 1);
+
+        let item = DataPack::builder().correlate(key).stream(StreamFlag::Item).build();
+        routes.dispatch("handler", item);
+        let end = DataPack::builder().correlate(key).stream(StreamFlag::End).build();
+        routes.dispatch("handler", end.clone());
+        routes.dispatch("handler", end);
+        assert_eq!(paths(&mut caller), [None, None]);
+        assert!(paths(&mut other).is_empty());
+
+        let request = DataPack::builder().path(&"/get").awaits_reply().build();
+        let key = request.correlation();
+        routes.dispatch("caller", request);
+        assert_eq!(paths(&mut other).len(), 1);
+        assert_eq!(paths(&mut handler).len(), 1);
+        let item = DataPack::builder().correlate(key).stream(StreamFlag::Item).build();
+        routes.dispatch("handler", item);
+        assert_eq!(paths(&mut caller), [None]);
+        let cancel = DataPack::builder().correlate(key).stream(StreamFlag::Cancel).build();
+        routes.dispatch("caller", cancel);
+        assert_eq!(paths(&mut handler), [None]);
+        assert!(paths(&mut other).is_empty());
+        routes.expire(std::time::Instant::now() + routes.timeout * 2);
+        assert!(paths(&mut caller).is_empty());
+    }
+
+    #[test]
+    fn acl() {
+        let routes = Routes::default();
+        let mut caller = plugin(&routes, "caller", None);
+        let mut handler = plugin(&routes, "handler", Some(&["/get".to_owned()]));
+        let (tx, mut limited) = mpsc::unbounded_channel();
+        let acl = AclConfig {
+            receive: Some(vec!["/message".to_owned()]),
+            ..AclConfig::default()
+        };
+        routes.insert("limited", tx, None, acl).unwrap();
+        routes.set_ready("limited");
+
+        routes.dispatch("caller", DataPack::builder().path(&"/message").build());
+        assert_eq!(paths(&mut limited), [Some("/message".to_owned())]);
+        let request = DataPack::builder().path(&"/get").awaits_reply().build();
+        let key = request.correlation();
+        routes.dispatch("caller", request);
+        assert!(paths(&mut limited).is_empty());
+        assert_eq!(paths(&mut handler).len(), 1);
+
+        // Only the plugins a request was routed to may answer it.
+        routes.dispatch("limited", DataPack::builder().correlate(key).build());
+        assert!(paths(&mut caller).is_empty());
+        routes.dispatch("handler", DataPack::builder().correlate(key).build());
+        assert_eq!(paths(&mut caller), [None]);
+
+        routes.dispatch(
+            "caller",
+            DataPack::builder().path(&"/other").awaits_reply().build(),
+        );
+        let error = caller.try_recv().unwrap().payload::<()>().unwrap_err();
+        assert_eq!(
+            error,
+            RequestError::NoHandler("/other".to_owned()).to_string()
+        );
+        routes.send(
+            "limited",
+            DataPack::builder().path(&"/config.updated").build(),
+        );
+        assert_eq!(paths(&mut limited), [Some("/config.updated".to_owned())]);
+    }
+
+    #[test]
+    fn queue_limit() {
+        let routes = Routes::default();
+        let mut caller = plugin(&routes, "caller", None);
+        let (tx, mut late) = mpsc::unbounded_channel();
+        routes.insert("late", tx, None, AclConfig::default()).unwrap();
+
+        let request = || DataPack::builder().path(&"/get").awaits_reply().build();
+        for _ in 0..MAX_QUEUED {
+            routes.dispatch("caller", request());
+        }
+        assert!(caller.try_recv().is_err());
+        routes.dispatch("caller", request());
+        let error = caller.try_recv().unwrap().payload::<()>().unwrap_err();
+        let (path, plugin) = ("/get".to_owned(), "late".to_owned());
+        assert_eq!(error, RequestError::QueueFull { path, plugin }.to_string());
+
+        routes.set_ready("late");
+        assert_eq!(paths(&mut late).len(), MAX_QUEUED);
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let routes = std::sync::Arc::new(Routes::default());
+        let mut adapter = plugin(&routes, "adapter", None);
+        let (tx, mut late) = mpsc::unbounded_channel();
+        routes.insert("late", tx, None, AclConfig::default()).unwrap();
+
+        let waiter = tokio::spawn({
+            let routes = routes.clone();
+            async move {
+                let names = ["adapter".to_owned(), "late".to_owned()];
+                routes.wait_ready("admin", &names).await;
+            }
+        });
+        tokio::task::yield_now().await;
+        routes.dispatch("adapter", DataPack::builder().path(&"/message").build());
+        assert!(paths(&mut late).is_empty());
+        assert!(!waiter.is_finished());
+        assert!(routes.is_waiting("admin"));
+        assert_eq!(
+            routes.not_ready(&["adapter".to_owned(), "late".to_owned()]),
+            ["late"]
+        );
+
+        assert!(routes.set_ready("late"));
+        assert!(!routes.set_ready("late"));
+        assert_eq!(paths(&mut late), [Some("/message".to_owned())]);
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!routes.is_waiting("admin"));
+        assert!(paths(&mut adapter).is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        let timeout = std::time::Duration::from_secs(10);
+        let routes = Routes::new(timeout);
+        let mut caller = plugin(&routes, "caller", None);
+        let mut handler = plugin(&routes, "handler", Some(&["/get".to_owned()]));
+
+        // Responses reach senders that do not mark their requests too.
+        let request = DataPack::builder().path(&"/get").build();
+        let key = request.correlation();
+        routes.dispatch("caller", request);
+        routes.dispatch(
+            "handler",
+            DataPack::builder().correlate(key).payload(1).build(),
+        );
+        let response = caller.try_recv().unwrap();
+        assert_eq!(
+            (response.correlation(), response.payload::<i32>()),
+            (key, Ok(1))
+        );
+        assert_eq!(paths(&mut handler).len(), 1);
+        let mut error = || caller.try_recv().ok().map(|data| data.payload::<()>().unwrap_err());
+
+        routes.dispatch("caller", DataPack::builder().path(&"/missing").build());
+        assert_eq!(error(), None);
+        let missing = DataPack::builder().path(&"/missing").awaits_reply();
+        routes.dispatch("caller", missing.build());
+        let message = RequestError::NoHandler("/missing".to_owned()).to_string();
+        assert_eq!(error(), Some(message));
+
+        let start = std::time::Instant::now();
+        routes.dispatch("caller", DataPack::builder().path(&"/get").build());
+        routes.dispatch(
+            "caller",
+            DataPack::builder().path(&"/get").awaits_reply().build(),
+        );
+        assert_eq!(paths(&mut handler).len(), 2);
+        routes.expire(start + timeout / 2);
+        assert_eq!(error(), None);
+        routes.expire(start + timeout * 2);
+        let path = "/get".to_owned();
+        assert_eq!(
+            error(),
+            Some(RequestError::Timeout { path, timeout }.to_string())
+        );
+        routes.expire(start + timeout * 3);
+        assert_eq!(error(), None);
+    }
+
+    #[test]
+    fn bots() {
+        let routes = Routes::default();
+        let _adapter = plugin(&routes, "adapter", None);
+        let _echo = plugin(&routes, "echo", None);
+        let event = DataPack::builder().path(&"/event/message.created").bot_id("qq");
+        routes.dispatch("adapter", event.build());
+        let command = DataPack::builder().path(&"/command/message.create").bot_id("qq");
+        routes.dispatch("echo", command.build());
+
+        let bots = routes.bots();
+        assert_eq!(bots.len(), 1);
+        assert_eq!(
+            (bots[0].id.as_str(), bots[0].plugin.as_str()),
+            ("qq", "adapter")
+        );
+        routes.remove("adapter");
+        assert!(routes.bots().is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+//! The services the host offers to plugins.
+//!
+//! The host answers the requests under `/host/` itself, with the types of
+//! [`sithra_kit::types::host`]: admin plugins can list the plugins and bots,
+//! and start, stop or restart plugins. Like any other request, these are
+//! subject to the [`AclConfig`](crate::conf::AclConfig) of the sender, which
+//! has to grant the services that change the plugins explicitly.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use sithra_kit::{
+    transport::datapack::DataPack,
+    types::host::{GetInfo, ListBots, ListPlugins, RestartPlugin, StartPlugin, StopPlugin},
+};
+use tokio::sync::{Mutex, mpsc::UnboundedReceiver};
+
+use crate::{loader::Loader, routing::RequestError};
+
+/// The paths of the host services start with this.
+pub const PREFIX: &str = "/host/";
+
+/// A request of the plugin `plugin` for a host service.
+pub struct HostRequest {
+    pub plugin: String,
+    pub data:   DataPack,
+}
+
+/// Answers the `requests` for host services, taken from
+/// [`Loader::host_requests`], until the loader is dropped.
+pub async fn serve(mut requests: UnboundedReceiver<HostRequest>, loader: Arc<Mutex<Loader>>) {
+    while let Some(HostRequest { plugin, data }) = requests.recv().await {
+        let key = data.correlation();
+        let mut loader = loader.lock().await;
+        let result = execute(&data, &mut loader).await;
+        if let Err(err) = &result {
+            log::warn!("Host service for {plugin} failed: {err}");
+        }
+        let response = DataPack::builder().correlate(key).result(result).build();
+        loader.routes().send(&plugin, response);
+    }
+}
+
+async fn execute(data: &DataPack, loader: &mut Loader) -> Result<rmpv::Value, String> {
+    let path = data.path.as_deref().unwrap_or_default();
+    match path {
+        _ if path == GetInfo::path() => to_value(loader.info()),
+        _ if path == ListPlugins::path() => to_value(loader.plugins()),
+        _ if path == ListBots::path() => to_value(loader.routes().bots()),
+        _ if path == StartPlugin::path() => {
+            let StartPlugin { name } = data.payload()?;
+            loader.start_plugin(&name).map_err(|err| err.to_string())?;
+            Ok(rmpv::Value::Nil)
+        }
+        _ if path == StopPlugin::path() => {
+            let StopPlugin { name } = data.payload()?;
+            loader.stop_plugin(&name).await.map_err(|err| err.to_string())?;
+            Ok(rmpv::Value::Nil)
+        }
+        _ if path == RestartPlugin::path() => {
+            let RestartPlugin { name } = data.payload()?;
+            loader.restart_plugin(&name).await.map_err(|err| err.to_string())?;
+            Ok(rmpv::Value::Nil)
+        }
+        _ => Err(RequestError::NoHandler(path.to_owned()).to_string()),
+    }
+}
+
+fn to_value(value: impl Serialize) -> Result<rmpv::Value, String> {
+    rmpv::ext::to_value(value).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use sithra_kit::{
+        transport::datapack::DataPack,
+        types::host::{HostInfo, PluginState, PluginStatus},
+    };
+    use tokio::sync::mpsc;
+
+    use crate::{loader::Loader, services::HostRequest};
+
+    #[tokio::test]
+    async fn requests() {
+        let (loader, mut rx) = Loader::connected(r#"echo = { path = "echo" }"#, "admin", Some(&[]));
+        let (services, requests) = mpsc::unbounded_channel();
+        tokio::spawn(super::serve(requests, loader.clone()));
+
+        let mut request = async |path: &str, payload: rmpv::Value| {
+            let data = DataPack::builder().path(&path).payload(payload).build();
+            let plugin = "admin".to_owned();
+            services.send(HostRequest { plugin, data }).ok().unwrap();
+            rx.recv().await.unwrap()
+        };
+
+        let info = request("/host/info", rmpv::Value::Nil).await;
+        let info = info.payload::<HostInfo>().unwrap();
+        assert_eq!((info.plugins, info.running), (1, 0));
+        let plugins = request("/host/plugins.list", rmpv::Value::Nil).await;
+        let plugins = plugins.payload::<Vec<PluginStatus>>().unwrap();
+        assert_eq!(plugins[0].state, PluginState::Stopped);
+        let restart = request(
+            "/host/plugin.restart",
+            sithra_kit::types::map! {"name": "missing"},
+        );
+        assert_eq!(
+            restart.await.payload::<()>(),
+            Err("Unknown plugin missing".to_owned())
+        );
+        let missing = request("/host/missing", rmpv::Value::Nil).await;
+        assert_eq!(
+            missing.payload::<()>(),
+            Err("No plugin handles /host/missing".to_owned())
+        );
+    }
+}
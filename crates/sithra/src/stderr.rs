@@ -0,0 +1,158 @@
+//! Capture of the standard error of plugins.
+
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    task::JoinHandle,
+};
+
+use crate::conf::{Stderr, StderrFile};
+
+/// Forwards the standard error of the plugin `name` where `mode` says, until
+/// the plugin closes it.
+///
+/// Returns `None` for [`Stderr::Inherit`], which needs no forwarding.
+///
+/// # Errors
+/// Returns an error if the file of [`Stderr::File`] cannot be opened.
+pub async fn capture<R>(name: &str, stderr: R, mode: &Stderr) -> io::Result<Option<JoinHandle<()>>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut lines = BufReader::new(stderr);
+    let name = name.to_owned();
+    let handle = match mode {
+        Stderr::Inherit => return Ok(None),
+        Stderr::Log => tokio::spawn(async move {
+            let mut line = Vec::new();
+            while lines.read_until(b'\n', &mut line).await.is_ok_and(|read| read > 0) {
+                let text = String::from_utf8_lossy(&line);
+                log::warn!(target: name.as_str(), "{}", text.trim_end());
+                line.clear();
+            }
+        }),
+        Stderr::File(config) => {
+            let mut file = RotatingFile::open(config).await?;
+            tokio::spawn(async move {
+                let mut line = Vec::new();
+                while lines.read_until(b'\n', &mut line).await.is_ok_and(|read| read > 0) {
+                    if let Err(err) = file.write(&line).await {
+                        log::error!("Failed to write stderr of {name}: {err}");
+                        return;
+                    }
+                    line.clear();
+                }
+            })
+        }
+    };
+    Ok(Some(handle))
+}
+
+/// A file that is rotated when it would grow past a size: `echo.log` is
+/// renamed to `echo.log.1`, `echo.log.1` to `echo.log.2`, and so on.
+struct RotatingFile {
+    path:      PathBuf,
+    max_bytes: u64,
+    keep:      u32,
+    file:      File,
+    len:       u64,
+}
+
+impl RotatingFile {
+    async fn open(config: &StderrFile) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let file = append(&config.path).await?;
+        let len = file.metadata().await?.len();
+        Ok(Self {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            keep: config.keep,
+            file,
+            len,
+        })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let size = data.len() as u64;
+        if self.len > 0 && self.len + size > self.max_bytes {
+            self.rotate().await?;
+        }
+        self.file.write_all(data).await?;
+        self.file.flush().await?;
+        self.len += size;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = rotated(&self.path, index);
+                if tokio::fs::try_exists(&from).await? {
+                    tokio::fs::rename(from, rotated(&self.path, index + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&self.path, rotated(&self.path, 1)).await?;
+        }
+        self.file = append(&self.path).await?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+async fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path).await
+}
+
+/// Returns the path of the `index`th rotated file.
+fn rotated(path: &Path, index: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::capture;
+    use crate::conf::{Stderr, StderrFile};
+
+    #[tokio::test]
+    async fn rotation() {
+        let dir = std::env::temp_dir().join(format!("sithra-stderr-{}", std::process::id()));
+        let path = dir.join("logs").join("echo.log");
+        let mode = Stderr::File(StderrFile {
+            path:      path.clone(),
+            max_bytes: 12,
+            keep:      2,
+        });
+        let lines = b"line 1\nline 2\nline 3\nline 4\n";
+
+        let handle = capture("echo", &lines[..], &mode).await.unwrap().unwrap();
+        handle.await.unwrap();
+        let read = |index: Option<u32>| {
+            let path = index.map_or_else(|| path.clone(), |index| super::rotated(&path, index));
+            std::fs::read_to_string(path).ok()
+        };
+        let files = [read(None), read(Some(1)), read(Some(2)), read(Some(3))];
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            [
+                Some("line 4\n".to_owned()),
+                Some("line 3\n".to_owned()),
+                Some("line 2\n".to_owned()),
+                None,
+            ]
+        );
+        assert!(capture("echo", &lines[..], &Stderr::Inherit).await.unwrap().is_none());
+    }
+}
@@ -34,6 +34,7 @@ impl Channel {
     ///
     /// # Example
     /// ```
+    /// # use sithra_transport::channel::Channel;
     /// let channel = Channel::Private("user123".to_string(), "Alice".to_string());
     /// ```
     #[allow(non_snake_case)]
@@ -56,6 +57,7 @@ impl Channel {
     ///
     /// # Example
     /// ```
+    /// # use sithra_transport::channel::Channel;
     /// let channel =
     ///     Channel::Group("group123".to_string(), "Developers".to_string());
     /// ```
@@ -79,6 +81,7 @@ impl Channel {
     ///
     /// # Example
     /// ```
+    /// # use sithra_transport::channel::Channel;
     /// let channel = Channel::Direct("user456".to_string(), "Bob".to_string());
     /// ```
     #[allow(non_snake_case)]
@@ -102,6 +105,7 @@ impl Channel {
     ///
     /// # Example
     /// ```
+    /// # use sithra_transport::channel::Channel;
     /// let channel = Channel::DirectFromGroup(
     ///     "group123".to_string(),
     ///     "user789".to_string(),
@@ -129,7 +133,7 @@ impl Channel {
 
 /// Represents the type of a communication channel.
 #[typeshare]
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ChannelType {
     /// A group channel, typically used for multi-user conversations.
@@ -5,7 +5,7 @@ use thiserror::Error;
 use tokio_util::codec::{Decoder, Encoder};
 use ulid::Ulid;
 
-use crate::{channel::Channel, util::get_chunk};
+use crate::{channel::Channel, trace::TraceContext, util::get_chunk};
 
 /// A raw data packet containing a length-prefixed byte buffer.
 ///
@@ -110,22 +110,47 @@ impl Decoder for RawDataPackCodec {
 /// and a `result` field that can be either a payload or an error.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DataPack {
-    pub bot_id:      Option<String>,
-    pub path:        Option<String>,
-    pub correlation: Ulid,
-    pub channel:     Option<Channel>,
+    pub bot_id:       Option<String>,
+    pub path:         Option<String>,
+    pub correlation:  Ulid,
+    pub channel:      Option<Channel>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace:        Option<TraceContext>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream:       Option<StreamFlag>,
+    /// Whether the sender of a request waits for its response. The host only
+    /// answers such requests with an error when they are not handled.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub awaits_reply: bool,
     #[serde(flatten)]
-    pub result:      DataResult,
+    pub result:       DataResult,
+}
+
+/// Marks a response pack as part of a streamed response.
+///
+/// Every item of a stream is sent as [`StreamFlag::Item`] as soon as it is
+/// produced, and an empty pack flagged [`StreamFlag::End`] follows the last
+/// one. A caller that is no longer interested
+/// sends a pack flagged [`StreamFlag::Cancel`] with the stream's correlation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFlag {
+    Item,
+    End,
+    Cancel,
 }
 
 impl Default for DataPack {
     fn default() -> Self {
         Self {
-            bot_id:      None,
-            path:        None,
-            correlation: Ulid::new(),
-            channel:     None,
-            result:      DataResult::Payload(rmpv::Value::Nil),
+            bot_id:       None,
+            path:         None,
+            correlation:  Ulid::new(),
+            channel:      None,
+            trace:        None,
+            stream:       None,
+            awaits_reply: false,
+            result:       DataResult::Payload(rmpv::Value::Nil),
         }
     }
 }
@@ -137,6 +162,7 @@ impl From<RequestDataPack> for DataPack {
             path,
             correlation,
             channel,
+            trace,
             payload,
         } = value;
         Self {
@@ -144,6 +170,9 @@ impl From<RequestDataPack> for DataPack {
             path: Some(path),
             correlation,
             channel,
+            trace,
+            stream: None,
+            awaits_reply: false,
             result: DataResult::Payload(payload),
         }
     }
@@ -159,6 +188,8 @@ pub struct RequestDataPack {
     pub path:    String,
     correlation: Ulid,
     pub channel: Option<Channel>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace:   Option<TraceContext>,
     pub payload: rmpv::Value,
 }
 
@@ -170,6 +201,7 @@ impl Default for RequestDataPack {
             path:        String::new(),
             correlation: Ulid::new(),
             channel:     None,
+            trace:       None,
             payload:     rmpv::Value::Nil,
         }
     }
@@ -202,6 +234,12 @@ impl RequestDataPack {
         self
     }
 
+    #[must_use]
+    pub fn trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
     #[must_use]
     pub fn payload_value(mut self, payload: impl Into<rmpv::Value>) -> Self {
         self.payload = payload.into();
@@ -267,11 +305,14 @@ where
 /// Provides a fluent interface for setting fields like `path`, `correlation`,
 /// `channel`, and `result` before building the final `DataPack`.
 pub struct DataPackBuilder {
-    pub bot_id:      Option<String>,
-    pub path:        Option<String>,
-    pub correlation: Option<Ulid>,
-    pub channel:     Option<Channel>,
-    pub result:      Option<DataResult>,
+    pub bot_id:       Option<String>,
+    pub path:         Option<String>,
+    pub correlation:  Option<Ulid>,
+    pub channel:      Option<Channel>,
+    pub trace:        Option<TraceContext>,
+    pub stream:       Option<StreamFlag>,
+    pub awaits_reply: bool,
+    pub result:       Option<DataResult>,
 }
 
 impl Default for DataPackBuilder {
@@ -285,11 +326,14 @@ impl DataPackBuilder {
     #[must_use]
     pub const fn new() -> Self {
         Self {
-            bot_id:      None,
-            path:        None,
-            correlation: None,
-            channel:     None,
-            result:      None,
+            bot_id:       None,
+            path:         None,
+            correlation:  None,
+            channel:      None,
+            trace:        None,
+            stream:       None,
+            awaits_reply: false,
+            result:       None,
         }
     }
 
@@ -320,6 +364,27 @@ impl DataPackBuilder {
         self
     }
 
+    /// Sets the `trace` field for the `DataPack`.
+    #[must_use]
+    pub fn trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Sets the `stream` field for the `DataPack`.
+    #[must_use]
+    pub const fn stream(mut self, flag: StreamFlag) -> Self {
+        self.stream = Some(flag);
+        self
+    }
+
+    /// Marks the request as awaiting its response.
+    #[must_use]
+    pub const fn awaits_reply(mut self) -> Self {
+        self.awaits_reply = true;
+        self
+    }
+
     /// Sets the `result` field for the `DataPack`.
     #[must_use]
     pub fn result(mut self, result: impl Into<DataResult>) -> Self {
@@ -339,6 +404,9 @@ impl DataPackBuilder {
             path,
             correlation,
             channel,
+            trace,
+            stream,
+            awaits_reply,
             result,
         } = self;
 
@@ -351,6 +419,9 @@ impl DataPackBuilder {
             path,
             correlation,
             channel,
+            trace,
+            stream,
+            awaits_reply,
             result,
         }
     }
@@ -483,6 +554,9 @@ impl DataPack {
             path,
             correlation,
             channel,
+            trace,
+            stream: _,
+            awaits_reply: _,
             result,
         } = self;
         let payload: Result<_, _> = result.into();
@@ -491,6 +565,7 @@ impl DataPack {
             path: path.unwrap_or_default(),
             correlation,
             channel,
+            trace,
             payload: payload.unwrap_or(rmpv::Value::Nil),
         }
     }
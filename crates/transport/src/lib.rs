@@ -4,6 +4,7 @@
 //! - [`channel`]: Channel management for message passing
 //! - [`datapack`]: Structured data packet serialization
 //! - [`peer`]: Peer connection management
+//! - [`trace`]: Trace context propagation
 //! - [`util`]: Shared utilities
 //!
 //! # Features
@@ -14,4 +15,5 @@
 pub mod channel;
 pub mod datapack;
 pub mod peer;
+pub mod trace;
 pub mod util;
@@ -1,21 +1,26 @@
-use either::Either;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
 use tokio::{
-    io::{AsyncRead, AsyncWrite, Stdin, Stdout, stdin, stdout},
-    pin,
+    io::{
+        AsyncRead, AsyncWrite, DuplexStream, ReadBuf, ReadHalf, Stdin, Stdout, WriteHalf, duplex,
+        split, stdin, stdout,
+    },
     process::{Child, ChildStdin, ChildStdout},
 };
 use triomphe::Arc;
 
-/// A peer represents a communication endpoint, either as a child process or the
-/// current process.
+/// A peer represents a communication endpoint: a child process, the current
+/// process, or one end of an in-memory pipe.
 ///
 /// It encapsulates the input and output streams (`incoming` and `outgoing`) and
-/// optionally manages a child process (`process`). The streams are wrapped in
-/// `Either` to handle both child process streams and standard I/O streams.
+/// optionally manages a child process (`process`).
 pub struct Peer {
     process:  Option<Child>,
-    incoming: Either<ChildStdout, Stdin>,
-    outgoing: Either<ChildStdin, Stdout>,
+    incoming: Incoming,
+    outgoing: Outgoing,
 }
 
 /// A reader for a peer's incoming data stream.
@@ -25,7 +30,7 @@ pub struct Peer {
 /// the reader is active.
 pub struct Reader {
     _process: Option<Arc<Child>>,
-    incoming: Either<ChildStdout, Stdin>,
+    incoming: Incoming,
 }
 
 /// A writer for a peer's outgoing data stream.
@@ -35,7 +40,65 @@ pub struct Reader {
 /// the writer is active.
 pub struct Writer {
     _process: Option<Arc<Child>>,
-    outgoing: Either<ChildStdin, Stdout>,
+    outgoing: Outgoing,
+}
+
+/// The stream a peer reads from.
+enum Incoming {
+    Child(ChildStdout),
+    Stdio(Stdin),
+    Memory(ReadHalf<DuplexStream>),
+}
+
+/// The stream a peer writes to.
+enum Outgoing {
+    Child(ChildStdin),
+    Stdio(Stdout),
+    Memory(WriteHalf<DuplexStream>),
+}
+
+impl AsyncRead for Incoming {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Child(stdout) => Pin::new(stdout).poll_read(cx, buf),
+            Self::Stdio(stdin) => Pin::new(stdin).poll_read(cx, buf),
+            Self::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Outgoing {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Child(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            Self::Stdio(stdout) => Pin::new(stdout).poll_write(cx, buf),
+            Self::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Child(stdin) => Pin::new(stdin).poll_flush(cx),
+            Self::Stdio(stdout) => Pin::new(stdout).poll_flush(cx),
+            Self::Memory(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Child(stdin) => Pin::new(stdin).poll_shutdown(cx),
+            Self::Stdio(stdout) => Pin::new(stdout).poll_shutdown(cx),
+            Self::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
 }
 
 impl Default for Peer {
@@ -56,11 +119,41 @@ impl Peer {
     pub fn new() -> Self {
         Self {
             process:  None,
-            incoming: Either::Right(stdin()),
-            outgoing: Either::Right(stdout()),
+            incoming: Incoming::Stdio(stdin()),
+            outgoing: Outgoing::Stdio(stdout()),
         }
     }
 
+    /// Creates a pair of peers connected to each other in memory.
+    ///
+    /// Whatever one peer writes, the other reads. `max_buf_size` bounds the
+    /// number of bytes buffered in each direction. This is mostly useful for
+    /// tests.
+    ///
+    /// # Example
+    /// ```
+    /// # use sithra_transport::peer::Peer;
+    /// let (plugin, host) = Peer::duplex(64 * 1024);
+    /// ```
+    #[must_use]
+    pub fn duplex(max_buf_size: usize) -> (Self, Self) {
+        let (a, b) = duplex(max_buf_size);
+        let (a_read, a_write) = split(a);
+        let (b_read, b_write) = split(b);
+        (
+            Self {
+                process:  None,
+                incoming: Incoming::Memory(a_read),
+                outgoing: Outgoing::Memory(a_write),
+            },
+            Self {
+                process:  None,
+                incoming: Incoming::Memory(b_read),
+                outgoing: Outgoing::Memory(b_write),
+            },
+        )
+    }
+
     /// Splits the `Peer` into separate `Reader` and `Writer` instances.
     ///
     /// This allows concurrent reading and writing operations. The `Reader` and
@@ -109,11 +202,23 @@ impl Peer {
 
         Ok(Self {
             process:  Some(child),
-            incoming: Either::Left(stdout),
-            outgoing: Either::Left(stdin),
+            incoming: Incoming::Child(stdout),
+            outgoing: Outgoing::Child(stdin),
         })
     }
 
+    /// Creates a new `Peer` instance from the standard I/O streams of a child
+    /// process, leaving the process itself to the caller, e.g. to wait for it
+    /// to exit.
+    #[must_use]
+    pub const fn from_pipes(stdin: ChildStdin, stdout: ChildStdout) -> Self {
+        Self {
+            process:  None,
+            incoming: Incoming::Child(stdout),
+            outgoing: Outgoing::Child(stdin),
+        }
+    }
+
     /// Gracefully shuts down the peer by terminating the associated child
     /// process (if any).
     ///
@@ -149,155 +254,75 @@ impl TryFrom<Child> for Peer {
 impl AsyncRead for Reader {
     /// Polls the underlying stream for data to read.
     ///
-    /// This delegates to either the child process's `stdout` or the current
-    /// process's `stdin`, depending on the configuration of the `Reader`.
+    /// This delegates to the child process's `stdout`, the current process's
+    /// `stdin` or the in-memory pipe, depending on the configuration of the
+    /// `Reader`.
     fn poll_read(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.get_mut().incoming {
-            Either::Left(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_read(cx, buf)
-            }
-            Either::Right(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_read(cx, buf)
-            }
-        }
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().incoming).poll_read(cx, buf)
     }
 }
 
 impl AsyncWrite for Writer {
     /// Polls the underlying stream for readiness to write data.
     ///
-    /// This delegates to either the child process's `stdin` or the current
-    /// process's `stdout`, depending on the configuration of the `Writer`.
+    /// This delegates to the child process's `stdin`, the current process's
+    /// `stdout` or the in-memory pipe, depending on the configuration of the
+    /// `Writer`.
     fn poll_write(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
         buf: &[u8],
-    ) -> std::task::Poll<std::io::Result<usize>> {
-        match self.get_mut().outgoing {
-            Either::Left(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_write(cx, buf)
-            }
-            Either::Right(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_write(cx, buf)
-            }
-        }
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().outgoing).poll_write(cx, buf)
     }
 
-    fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.get_mut().outgoing {
-            Either::Left(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_flush(cx)
-            }
-            Either::Right(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_flush(cx)
-            }
-        }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().outgoing).poll_flush(cx)
     }
 
-    fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.get_mut().outgoing {
-            Either::Left(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_shutdown(cx)
-            }
-            Either::Right(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_shutdown(cx)
-            }
-        }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().outgoing).poll_shutdown(cx)
     }
 }
 
 impl AsyncRead for Peer {
     /// Polls the underlying stream for data to read.
     ///
-    /// This delegates to either the child process's `stdout` or the current
-    /// process's `stdin`, depending on the configuration of the `Peer`.
+    /// This delegates to the child process's `stdout`, the current process's
+    /// `stdin` or the in-memory pipe, depending on the configuration of the
+    /// `Peer`.
     fn poll_read(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.get_mut().incoming {
-            Either::Left(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_read(cx, buf)
-            }
-            Either::Right(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_read(cx, buf)
-            }
-        }
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().incoming).poll_read(cx, buf)
     }
 }
 
 impl AsyncWrite for Peer {
     /// Polls the underlying stream for readiness to write data.
     ///
-    /// This delegates to either the child process's `stdin` or the current
-    /// process's `stdout`, depending on the configuration of the `Peer`.
+    /// This delegates to the child process's `stdin`, the current process's
+    /// `stdout` or the in-memory pipe, depending on the configuration of the
+    /// `Peer`.
     fn poll_write(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
         buf: &[u8],
-    ) -> std::task::Poll<std::io::Result<usize>> {
-        match self.get_mut().outgoing {
-            Either::Left(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_write(cx, buf)
-            }
-            Either::Right(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_write(cx, buf)
-            }
-        }
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().outgoing).poll_write(cx, buf)
     }
 
-    fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.get_mut().outgoing {
-            Either::Left(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_flush(cx)
-            }
-            Either::Right(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_flush(cx)
-            }
-        }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().outgoing).poll_flush(cx)
     }
 
-    fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.get_mut().outgoing {
-            Either::Left(ref mut stdin) => {
-                pin!(stdin);
-                stdin.poll_shutdown(cx)
-            }
-            Either::Right(ref mut stdout) => {
-                pin!(stdout);
-                stdout.poll_shutdown(cx)
-            }
-        }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().outgoing).poll_shutdown(cx)
     }
 }
@@ -0,0 +1,58 @@
+//! Trace context carried by data packets.
+//!
+//! A [`TraceContext`] ties a request to the span that sent it, so the spans
+//! opened for it by the adapter, the host and the plugins can be joined into
+//! one trace. The IDs use the [W3C Trace Context] format.
+//!
+//! [W3C Trace Context]: https://www.w3.org/TR/trace-context/
+
+use serde::{Deserialize, Serialize};
+use typeshare::typeshare;
+use ulid::Ulid;
+
+#[typeshare]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+/// The trace a data packet belongs to, and the span that sent it.
+///
+/// # Fields
+/// - `trace_id`: 32 lowercase hex digits identifying the whole trace.
+/// - `span_id`: 16 lowercase hex digits identifying the sending span. The
+///   receiver uses it as the parent of its own span.
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id:  String,
+}
+
+impl TraceContext {
+    /// Starts a new trace.
+    ///
+    /// # Example
+    /// ```
+    /// # use sithra_transport::trace::TraceContext;
+    /// let root = TraceContext::new_root();
+    /// assert_eq!(root.trace_id.len(), 32);
+    /// assert_eq!(root.span_id.len(), 16);
+    /// ```
+    #[must_use]
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: format!("{:032x}", Ulid::new().0),
+            span_id:  new_span_id(),
+        }
+    }
+
+    /// Creates the context of a new span in the same trace.
+    #[must_use]
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id:  new_span_id(),
+        }
+    }
+}
+
+/// Generates a random span ID.
+#[must_use]
+pub fn new_span_id() -> String {
+    format!("{:016x}", Ulid::new().random() as u64)
+}
@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Tells the host which requests a plugin handles.
+///
+/// The host only forwards requests whose path matches one of `paths` to the
+/// plugin, and responses only to the plugin that sent the request. A plugin
+/// that never registers receives every request. Registering again replaces
+/// the previous paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Register {
+    /// Path patterns, with the same syntax as routes.
+    pub paths: Vec<String>,
+}
+
+impl Register {
+    pub fn new<T: ToString>(paths: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            paths: paths.into_iter().map(|path| path.to_string()).collect(),
+        }
+    }
+}
+
+/// Tells the host that a plugin is ready to handle requests.
+///
+/// The host holds the requests for a plugin until it is ready, and starts the
+/// plugins that depend on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ready;
+
+/// Asks the host for its [`HostInfo`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetInfo;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub version:     String,
+    pub pid:         u32,
+    pub uptime_secs: u64,
+    /// How many plugins are configured.
+    pub plugins:     usize,
+    /// How many of them are not stopped.
+    pub running:     usize,
+}
+
+/// Asks the host for the [`PluginStatus`] of every configured plugin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListPlugins;
+
+/// Whether a plugin runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PluginState {
+    /// The plugin waits for its dependencies to be ready.
+    Waiting,
+    /// The plugin is starting or restarting.
+    Starting,
+    Ready,
+    /// The plugin was stopped, or gave up restarting.
+    Stopped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginStatus {
+    pub name:  String,
+    pub state: PluginState,
+    pub path:  PathBuf,
+}
+
+/// Asks the host to start a stopped plugin.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StartPlugin {
+    pub name: String,
+}
+
+/// Asks the host to stop a plugin until it is started again.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StopPlugin {
+    pub name: String,
+}
+
+/// Asks the host to restart a plugin, or start it if it is stopped.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestartPlugin {
+    pub name: String,
+}
+
+/// Asks the host for the [`BotInfo`] of every bot it has seen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListBots;
+
+/// A bot, known to the host from the events a plugin sent for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BotInfo {
+    pub id:     String,
+    /// The plugin that sent the last event of the bot, usually its adapter.
+    pub plugin: String,
+}
+
+pub mod command {
+    use sithra_server::typed;
+    use sithra_transport::datapack::RequestDataPack;
+
+    use super::{
+        GetInfo, ListBots, ListPlugins, Ready, Register, RestartPlugin, StartPlugin, StopPlugin,
+    };
+
+    typed!("/host/register" => impl Register);
+
+    impl From<Register> for RequestDataPack {
+        fn from(value: Register) -> Self {
+            Self::default().payload(value).path("/host/register")
+        }
+    }
+
+    typed!("/host/ready" => impl Ready);
+
+    impl From<Ready> for RequestDataPack {
+        fn from(value: Ready) -> Self {
+            Self::default().payload(value).path("/host/ready")
+        }
+    }
+
+    typed!("/host/info" => impl GetInfo);
+
+    impl From<GetInfo> for RequestDataPack {
+        fn from(value: GetInfo) -> Self {
+            Self::default().payload(value).path("/host/info")
+        }
+    }
+
+    typed!("/host/plugins.list" => impl ListPlugins);
+
+    impl From<ListPlugins> for RequestDataPack {
+        fn from(value: ListPlugins) -> Self {
+            Self::default().payload(value).path("/host/plugins.list")
+        }
+    }
+
+    typed!("/host/plugin.start" => impl StartPlugin);
+
+    impl From<StartPlugin> for RequestDataPack {
+        fn from(value: StartPlugin) -> Self {
+            Self::default().payload(value).path("/host/plugin.start")
+        }
+    }
+
+    typed!("/host/plugin.stop" => impl StopPlugin);
+
+    impl From<StopPlugin> for RequestDataPack {
+        fn from(value: StopPlugin) -> Self {
+            Self::default().payload(value).path("/host/plugin.stop")
+        }
+    }
+
+    typed!("/host/plugin.restart" => impl RestartPlugin);
+
+    impl From<RestartPlugin> for RequestDataPack {
+        fn from(value: RestartPlugin) -> Self {
+            Self::default().payload(value).path("/host/plugin.restart")
+        }
+    }
+
+    typed!("/host/bots.list" => impl ListBots);
+
+    impl From<ListBots> for RequestDataPack {
+        fn from(value: ListBots) -> Self {
+            Self::default().payload(value).path("/host/bots.list")
+        }
+    }
+}
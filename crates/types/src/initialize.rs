@@ -24,8 +24,28 @@ where
     }
 }
 
+/// Sent by the host when the `config` table of a plugin changes, so that the
+/// plugin can apply it without restarting.
+///
+/// Route it with `ConfigUpdated::on` and extract the payload as
+/// `Payload<ConfigUpdated<Config>>`.
+#[derive(Deserialize, Serialize)]
+pub struct ConfigUpdated<C = rmpv::Value> {
+    pub config: C,
+}
+
+impl<C> ConfigUpdated<C> {
+    pub const fn new(config: C) -> Self {
+        Self { config }
+    }
+}
+
 pub mod command {
-    use super::Initialize;
+    use sithra_server::typed;
+
+    use super::{ConfigUpdated, Initialize};
+
+    typed!("/config.updated" => impl ConfigUpdated);
 
     #[allow(dead_code)]
     impl<C> Initialize<C> {
@@ -7,8 +7,11 @@ pub mod __private {
     pub use sithra_transport;
 }
 
+pub mod host;
 pub mod initialize;
 pub mod log;
 pub mod message;
+pub mod metrics;
+pub mod trace;
 
 pub use smallvec;
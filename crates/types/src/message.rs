@@ -1,10 +1,16 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use matchit::InsertError;
 use serde::{Deserialize, Serialize};
 use sithra_server::{
     extract::context::{Clientful, Context},
     server::PostError,
+    subscription::Subscription,
 };
 use sithra_transport::{channel::Channel, datapack::RequestDataPack};
 use smallvec::SmallVec;
+use thiserror::Error;
 use typeshare::typeshare;
 
 pub const NIL: rmpv::Value = rmpv::Value::Nil;
@@ -96,11 +102,96 @@ impl<Seg: Into<Segment>> From<SmallVec<[Seg; 1]>> for SendMessage {
     }
 }
 
+/// Which follow-up messages belong to the same conversation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// Any message from the same channel, e.g. the same group.
+    #[default]
+    Channel,
+    /// Only messages from the same user in the same channel.
+    User,
+}
+
+impl Scope {
+    /// Returns `true` if a message from `other` continues a conversation
+    /// started in `origin`.
+    #[must_use]
+    pub fn matches(self, origin: &Channel, other: &Channel) -> bool {
+        if origin.ty != other.ty || origin.self_id != other.self_id {
+            return false;
+        }
+        match self {
+            Self::Channel => match (&origin.parent_id, &other.parent_id) {
+                (Some(origin), Some(other)) => origin == other,
+                (None, None) => origin.id == other.id,
+                _ => false,
+            },
+            Self::User => origin.id == other.id && origin.parent_id == other.parent_id,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[allow(clippy::large_enum_variant)]
+pub enum ConversationError {
+    #[error("Request has no channel")]
+    NoChannel,
+    #[error("Failed to subscribe: {0}")]
+    Subscribe(#[from] InsertError),
+    #[error("Post error: {0}")]
+    Post(#[from] PostError),
+    #[error("Timed out waiting for the next message")]
+    Timeout,
+    #[error("Connection closed")]
+    Closed,
+    #[error("Failed to deserialize message: {0}")]
+    Deserialize(#[from] rmpv::ext::Error),
+}
+
 pub trait ContextExt {
+    type Segment;
+
     fn reply(
         &self,
         msg: impl Into<SendMessage> + Send + Sync,
     ) -> impl Future<Output = Result<Message, PostError>> + Send + Sync;
+
+    /// Waits for the next message from the same channel.
+    ///
+    /// The matched message is consumed, so no other handler sees it.
+    fn next_message(
+        &self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Message<Self::Segment>, ConversationError>> + Send {
+        self.next_message_in(Scope::Channel, timeout)
+    }
+
+    /// Waits for the next message in `scope`.
+    ///
+    /// The matched message is consumed, so no other handler sees it.
+    fn next_message_in(
+        &self,
+        scope: Scope,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Message<Self::Segment>, ConversationError>> + Send;
+
+    /// Replies with `msg`, then waits for the next message from the same
+    /// channel.
+    fn prompt(
+        &self,
+        msg: impl Into<SendMessage> + Send + Sync,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Message<Self::Segment>, ConversationError>> + Send {
+        self.prompt_in(Scope::Channel, msg, timeout)
+    }
+
+    /// Replies with `msg`, then waits for the next message in `scope`.
+    fn prompt_in(
+        &self,
+        scope: Scope,
+        msg: impl Into<SendMessage> + Send + Sync,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Message<Self::Segment>, ConversationError>> + Send;
 }
 
 impl<S, Seg> ContextExt for Context<Message<Seg>, S>
@@ -108,6 +199,8 @@ where
     S: Clientful + Send + Sync,
     Seg: for<'de> Deserialize<'de> + Send + Sync,
 {
+    type Segment = Seg;
+
     async fn reply(&self, msg: impl Into<SendMessage> + Send + Sync) -> Result<Message, PostError> {
         let datapack = self
             .client()
@@ -121,6 +214,60 @@ where
         let msg = datapack.payload::<Message>()?;
         Ok(msg)
     }
+
+    async fn next_message_in(
+        &self,
+        scope: Scope,
+        timeout: Duration,
+    ) -> Result<Message<Seg>, ConversationError> {
+        let subscription = follow_up(self, scope)?;
+        wait_message(subscription, timeout).await
+    }
+
+    async fn prompt_in(
+        &self,
+        scope: Scope,
+        msg: impl Into<SendMessage> + Send + Sync,
+        timeout: Duration,
+    ) -> Result<Message<Seg>, ConversationError> {
+        // Subscribe before replying, so a quick answer cannot slip past us.
+        let subscription = follow_up(self, scope)?;
+        self.reply(msg).await?;
+        wait_message(subscription, timeout).await
+    }
+}
+
+/// Intercepts the next message in `scope` around the message of `ctx`.
+#[allow(clippy::result_large_err)]
+fn follow_up<S, Seg>(
+    ctx: &Context<Message<Seg>, S>,
+    scope: Scope,
+) -> Result<Subscription, ConversationError>
+where
+    S: Clientful,
+    Seg: for<'de> Deserialize<'de>,
+{
+    let origin = ctx.request.channel().ok_or(ConversationError::NoChannel)?;
+    let bot_id = ctx.request.bot_id();
+    let subscription = ctx.client().intercept_once(event::PATH, move |req| {
+        req.bot_id_ref() == bot_id.as_deref()
+            && req.data.channel.as_ref().is_some_and(|other| scope.matches(&origin, other))
+    })?;
+    Ok(subscription)
+}
+
+async fn wait_message<Seg>(
+    mut subscription: Subscription,
+    timeout: Duration,
+) -> Result<Message<Seg>, ConversationError>
+where
+    Seg: for<'de> Deserialize<'de>,
+{
+    let request = tokio::time::timeout(timeout, subscription.next())
+        .await
+        .map_err(|_| ConversationError::Timeout)?
+        .ok_or(ConversationError::Closed)?;
+    Ok(request.payload()?)
 }
 
 pub trait ClientfulExt {
@@ -254,6 +401,8 @@ pub mod common {
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
+    use std::time::Duration;
+
     use sithra_server::{
         extract::{
             context::{Clientful, Context as RawContext},
@@ -268,7 +417,9 @@ mod tests {
     use sithra_transport::channel::Channel;
 
     use super::Message;
-    use crate::message::{ClientfulExt, ContextExt, SendMessage, common::CommonSegment};
+    use crate::message::{
+        ClientfulExt, ContextExt, ConversationError, Scope, SendMessage, common::CommonSegment,
+    };
 
     #[derive(Clone)]
     struct AppState {
@@ -306,6 +457,18 @@ mod tests {
         Ok(())
     }
 
+    async fn on_prompt(ctx: Context<Message<CommonSegment>>) -> Result<(), ConversationError> {
+        let answer = ctx
+            .prompt_in(
+                Scope::User,
+                msg!(CommonSegment[text: &"What city?"]),
+                Duration::from_secs(30),
+            )
+            .await?;
+        let _city = answer.content.first();
+        Ok(())
+    }
+
     async fn on_message3(Payload(_msg): Payload<Message>) -> SendMessage {
         msg!(CommonSegment[
             text: &"Hello, world!",
@@ -314,10 +477,27 @@ mod tests {
         .into()
     }
 
+    #[test]
+    fn scope() {
+        let origin = Channel::DirectFromGroup("g".to_owned(), "u1".to_owned(), "A".to_owned());
+        let same_user = Channel::DirectFromGroup("g".to_owned(), "u1".to_owned(), "A".to_owned());
+        let other_user = Channel::DirectFromGroup("g".to_owned(), "u2".to_owned(), "B".to_owned());
+        let other_group = Channel::DirectFromGroup("h".to_owned(), "u1".to_owned(), "A".to_owned());
+        let private = Channel::Private("u1".to_owned(), "A".to_owned());
+
+        assert!(Scope::Channel.matches(&origin, &same_user));
+        assert!(Scope::Channel.matches(&origin, &other_user));
+        assert!(!Scope::Channel.matches(&origin, &other_group));
+        assert!(!Scope::Channel.matches(&origin, &private));
+        assert!(Scope::User.matches(&origin, &same_user));
+        assert!(!Scope::User.matches(&origin, &other_user));
+        assert!(Scope::User.matches(&private, &private.clone()));
+    }
+
     #[tokio::test]
     async fn _type() {
         let _router = router! { Router::new() =>
-            Message[on_message, on_message2, on_message3]
+            Message[on_message, on_message2, on_message3, on_prompt]
         };
     }
 }
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use sithra_server::metrics::MetricFamily;
+
+/// A snapshot of a plugin's metrics, sent to the host periodically.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsReport {
+    pub families: Vec<MetricFamily>,
+}
+
+impl MetricsReport {
+    #[must_use]
+    pub const fn new(families: Vec<MetricFamily>) -> Self {
+        Self { families }
+    }
+}
+
+/// Asks the host for the metrics of all plugins.
+///
+/// The host responds with the Prometheus text format as a string.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GetMetrics;
+
+pub mod command {
+    use sithra_server::typed;
+    use sithra_transport::datapack::RequestDataPack;
+
+    use super::{GetMetrics, MetricsReport};
+    use crate::into_response;
+
+    typed!("/metrics.report" => impl MetricsReport);
+    into_response!("/metrics.report", MetricsReport);
+
+    impl From<MetricsReport> for RequestDataPack {
+        fn from(value: MetricsReport) -> Self {
+            Self::default().payload(value).path("/metrics.report")
+        }
+    }
+
+    typed!("/host/metrics" => impl GetMetrics);
+
+    impl From<GetMetrics> for RequestDataPack {
+        fn from(value: GetMetrics) -> Self {
+            Self::default().payload(value).path("/host/metrics")
+        }
+    }
+}
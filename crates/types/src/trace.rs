@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+
+use log::Level;
+use serde::{Deserialize, Serialize};
+use sithra_transport::trace::TraceContext;
+
+/// A finished span, forwarded from a plugin to the host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Span {
+    pub name:           String,
+    pub target:         String,
+    pub level:          Level,
+    /// The trace the span belongs to, and its own span ID.
+    pub trace:          TraceContext,
+    pub parent_span_id: Option<String>,
+    pub fields:         BTreeMap<String, String>,
+    /// Start time, in microseconds since the Unix epoch.
+    pub start:          u64,
+    /// Duration, in microseconds.
+    pub duration:       u64,
+}
+
+pub mod command {
+    use sithra_server::typed;
+    use sithra_transport::datapack::RequestDataPack;
+
+    use super::Span;
+    use crate::into_response;
+
+    typed!("/span.create" => impl Span);
+    into_response!("/span.create", Span);
+
+    impl From<Span> for RequestDataPack {
+        fn from(value: Span) -> Self {
+            Self::default().payload(value).path("/span.create")
+        }
+    }
+}
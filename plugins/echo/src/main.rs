@@ -7,11 +7,17 @@ use sithra_kit::{
 #[tokio::main]
 async fn main() {
     let (plugin, ()) = Plugin::new().await.unwrap();
-    let plugin = plugin.map(|r| r.route_typed(Message::on(echo)));
-    log::info!("Echo plugin started");
-    tokio::select! {
-        _ = plugin.run().join_all() => {}
-        _ = tokio::signal::ctrl_c() => {}
+    let exit = plugin
+        .map(|r| r.route_typed(Message::on(echo)))
+        .on_ready(async || log::info!("Echo plugin started"))
+        .on_shutdown(|exit| {
+            log::info!("Echo plugin stopped: {exit}");
+            async {}
+        })
+        .run()
+        .await;
+    if exit.is_error() {
+        std::process::exit(1);
     }
 }
 